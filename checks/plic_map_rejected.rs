@@ -0,0 +1,18 @@
+//! Must not compile: `just check-maps` expects this map to be rejected by
+//! `soc::plic::check_map`, with the enable words one page too high.
+
+#[path = "../src/soc.rs"]
+mod soc;
+
+use soc::plic::*;
+
+const _: () = check_map(
+    BASE,
+    PRIORITY_BASE,
+    BASE + 0x3000,
+    ENABLE_STRIDE,
+    THRESHOLD_BASE,
+    CLAIM_OFFSET,
+    CONTEXT_STRIDE,
+    MAX_SOURCES,
+);
@@ -3,15 +3,25 @@
 //! This module defines architecture-facing CPU metadata and constants used by
 //! the rest of the kernel.
 
-use alloc::{alloc::alloc, boxed::Box};
-use core::{alloc::Layout, arch::asm};
+mod rng;
+
+use alloc::{alloc::alloc, boxed::Box, vec::Vec};
+use core::{
+    alloc::Layout,
+    arch::asm,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
 
 use ::fdt::Fdt;
-use spin::{Mutex, Once};
 
+pub use self::rng::Rng;
 use crate::{
-    STACK_SIZE, TRAP_STACK_SIZE, fdt,
-    task::{Scheduler, Task},
+    STACK_SIZE, TRAP_STACK_SIZE, fdt, interrupt,
+    interrupt::{deferred::DeferredQueue, stats::TrapCounters},
+    sync::{Mutex, OnceLock},
+    task::{SchedCounters, Scheduler, Task, TaskId},
+    trace::TraceRing,
+    watchdog::Heartbeat,
 };
 
 macro_rules! define_page_config {
@@ -31,12 +41,14 @@ pub mod cause
     {
         pub const SUPERVISOR_SOFTWARE_INTERRUPT: usize = 1;
         pub const SUPERVISOR_TIMER_INTERRUPT: usize = 5;
+        pub const SUPERVISOR_EXTERNAL_INTERRUPT: usize = 9;
     }
 
     pub mod exceptions
     {
         pub const INSTRUCTION_ACCESS_FAULT: usize = 1;
         pub const ILLEGAL_INSTRUCTION: usize = 2;
+        pub const BREAKPOINT: usize = 3;
         pub const LOAD_ACCESS_FAULT: usize = 5;
         pub const STORE_ACCESS_FAULT: usize = 7;
 
@@ -44,18 +56,120 @@ pub mod cause
         pub const SUPERVISOR_ECALL: usize = 9;
         pub const MACHINE_ECALL: usize = 11;
     }
+
+    /// Set in `scause` when the trap was an interrupt.
+    pub const INTERRUPT_FLAG: usize = 1 << (usize::BITS - 1);
+
+    /// Human-readable name of a raw `scause` value.
+    #[inline]
+    pub fn describe(scause: usize) -> &'static str
+    {
+        let code = scause & !INTERRUPT_FLAG;
+        if scause & INTERRUPT_FLAG != 0
+        {
+            describe_interrupt(code)
+        }
+        else
+        {
+            describe_exception(code)
+        }
+    }
+
+    /// Name of an interrupt code (with the interrupt bit already cleared).
+    pub fn describe_interrupt(code: usize) -> &'static str
+    {
+        match code
+        {
+            1 => "Supervisor software interrupt",
+            3 => "Machine software interrupt",
+            5 => "Supervisor timer interrupt",
+            7 => "Machine timer interrupt",
+            9 => "Supervisor external interrupt",
+            11 => "Machine external interrupt",
+            13 => "Counter overflow interrupt",
+            0..16 => "Reserved interrupt",
+            _ => "Platform-specific interrupt",
+        }
+    }
+
+    /// Name of an exception code.
+    pub fn describe_exception(code: usize) -> &'static str
+    {
+        match code
+        {
+            0 => "Instruction address misaligned",
+            1 => "Instruction access fault",
+            2 => "Illegal instruction",
+            3 => "Breakpoint",
+            4 => "Load address misaligned",
+            5 => "Load access fault",
+            6 => "Store/AMO address misaligned",
+            7 => "Store/AMO access fault",
+            8 => "Environment call from U-mode",
+            9 => "Environment call from S-mode",
+            11 => "Environment call from M-mode",
+            12 => "Instruction page fault",
+            13 => "Load page fault",
+            15 => "Store/AMO page fault",
+            18 => "Software check",
+            19 => "Hardware error",
+            24..32 | 48..64 => "Custom exception",
+            _ => "Reserved exception",
+        }
+    }
 }
 
-pub static CPU_VEC: Once<Box<[Cpu]>> = Once::new();
+/// Every hart's `Cpu`, indexed by logical hart ID. Set once by the boot hart
+/// in `Cpu::init_vec`; secondaries `wait` on it before touching their entry.
+pub static CPU_VEC: OnceLock<Box<[Cpu]>> = OnceLock::new();
+
+/// Written at the lowest word of every trap stack; anything else there means
+/// a handler ran off the end.
+const TRAP_STACK_CANARY: usize = 0x5AFE_57AC_5AFE_57AC_u64 as usize;
 
+/// Per-hart kernel state.
+///
+/// Each hart finds its own entry through `tp` (see [`Cpu::get`]); other harts
+/// reach it through [`Cpu::nth`] or `CPU_VEC`, which is why everything mutable
+/// in here is behind a lock or an atomic.
 #[repr(C)]
 pub struct Cpu
 {
     pub physical_id: usize,
     pub logical_id: usize,
     pub scheduler: Mutex<Scheduler>,
+    /// Top of the boot/main stack the hart enters `hart_setup` on.
     pub stack_top: usize,
+    /// Top of the trap stack `sscratch` points into.
     pub trap_stack_top: usize,
+    pub rng: Rng,
+    pub deferred: Mutex<DeferredQueue>,
+    pub trap_counters: TrapCounters,
+    /// Set while this hart is inside `print!`; see `uart::_print`.
+    pub in_console: AtomicBool,
+    /// Nesting depth of `task::preempt_disable` on the running task; `schedule`
+    /// swaps it with each task it switches.
+    pub preempt_count: AtomicUsize,
+    /// `Mutex` and `TicketMutex` guards the running task holds, outside trap
+    /// handlers; swapped with each task like `preempt_count`. See
+    /// `sync::held_locks`.
+    pub held_locks: AtomicUsize,
+    /// A switch was held back by `preempt_count`; `preempt_enable` runs it.
+    pub resched_pending: AtomicBool,
+    pub trace: TraceRing,
+    pub sched_counters: SchedCounters,
+    /// Cleared for good if the hart never came up or missed the boot barrier;
+    /// see `boot::start_harts` and `boot::wait_all_ready`.
+    pub online: AtomicBool,
+    /// Set once the hart has finished its trap and PLIC setup, before it can
+    /// be given any task.
+    pub ready: AtomicBool,
+    /// Raw [`TaskId`] of the running task, kept in step by `schedule`.
+    pub current_task: AtomicUsize,
+    /// Nesting depth of `trap_handler`; see [`in_interrupt`].
+    pub trap_depth: AtomicUsize,
+    /// Last timer tick seen by this hart, for [`crate::watchdog`].
+    pub heartbeat: Heartbeat,
 }
 
 impl Cpu
@@ -66,17 +180,35 @@ impl Cpu
         let count = fdt::harts::parse_hart_count(dev_tree, boot_hart_id).unwrap();
         let cpus = (0..count)
             .map(|i| {
+                let idle = Task::idle();
+
                 let [stack_ptr, trap_stack_ptr] = [STACK_SIZE, TRAP_STACK_SIZE]
                     // Ensure page alignment
                     .map(|s| Layout::from_size_align(s, PAGE_SIZE).unwrap())
                     .map(|l| unsafe { alloc(l) as usize });
 
+                unsafe { (trap_stack_ptr as *mut usize).write(TRAP_STACK_CANARY) }
+
                 Cpu {
                     physical_id: fdt::harts::to_physical(i),
                     logical_id: i,
-                    scheduler: Mutex::new(Scheduler::with_task(Task::main())),
+                    current_task: AtomicUsize::new(idle.id.get()),
+                    trap_depth: AtomicUsize::new(0),
+                    heartbeat: Heartbeat::new(),
+                    scheduler: Mutex::new(Scheduler::with_task(idle)),
                     stack_top: stack_ptr + STACK_SIZE,
-                    trap_stack_top: trap_stack_ptr + STACK_SIZE,
+                    trap_stack_top: trap_stack_ptr + TRAP_STACK_SIZE,
+                    rng: Rng::new(),
+                    deferred: Mutex::new(DeferredQueue::new()),
+                    trap_counters: TrapCounters::new(),
+                    in_console: AtomicBool::new(false),
+                    preempt_count: AtomicUsize::new(0),
+                    held_locks: AtomicUsize::new(0),
+                    resched_pending: AtomicBool::new(false),
+                    trace: TraceRing::default(),
+                    sched_counters: SchedCounters::new(),
+                    online: AtomicBool::new(true),
+                    ready: AtomicBool::new(false),
                 }
             })
             .collect();
@@ -84,13 +216,38 @@ impl Cpu
         CPU_VEC.call_once(|| cpus);
     }
 
+    /// Whether this hart is running the kernel; placement and IPIs skip it
+    /// otherwise.
+    #[inline]
+    pub fn is_online(&self) -> bool
+    {
+        self.online.load(Ordering::Relaxed)
+    }
+
+    /// Whether the hart has passed its setup and may be handed tasks.
+    #[inline]
+    pub fn is_ready(&self) -> bool
+    {
+        self.ready.load(Ordering::Acquire)
+    }
+
+    /// Install this `Cpu` as the current hart's local state and seed its RNG.
     #[inline]
     pub fn set(&self)
     {
         let ptr = self as *const Cpu as usize;
         unsafe { asm!("mv tp, {0}", in(reg) ptr) }
+
+        // Mix the hart ID in so harts started in the same tick still diverge.
+        let now = unsafe { csr_read!("time") };
+        self.rng
+            .seed(now ^ self.logical_id.wrapping_add(1).wrapping_mul(0x9E37_79B9));
     }
 
+    /// The current hart's `Cpu`, read from `tp`.
+    ///
+    /// `Cpu::set` installs it at boot and `_trap` restores it on every trap
+    /// entry, so it is valid in handlers whatever the interrupted code did.
     #[inline]
     pub fn get() -> &'static Cpu
     {
@@ -101,6 +258,74 @@ impl Cpu
         }
     }
 
+    /// Like [`Cpu::get`], but returns `None` if `tp` doesn't point at a `Cpu`
+    /// yet, e.g. when panicking during early boot.
+    pub fn try_get() -> Option<&'static Cpu>
+    {
+        let ptr = thread_pointer();
+        if ptr == 0
+        {
+            // `_start` clears `tp`, so this is every hart before `Cpu::set`.
+            return None;
+        }
+
+        CPU_VEC
+            .get()?
+            .iter()
+            .find(|cpu| *cpu as *const Cpu as usize == ptr)
+    }
+
+    /// The word at the bottom of this hart's trap stack, where the canary
+    /// should be.
+    #[inline]
+    pub fn trap_stack_bottom(&self) -> *mut usize
+    {
+        (self.trap_stack_top - TRAP_STACK_SIZE) as *mut usize
+    }
+
+    /// Whether this hart's trap stack canary is still in place.
+    #[inline]
+    pub fn trap_stack_intact(&self) -> bool
+    {
+        unsafe { self.trap_stack_bottom().read_volatile() == TRAP_STACK_CANARY }
+    }
+
+    /// Panic if this hart's trap stack canary has been overwritten.
+    #[inline]
+    pub fn check_trap_stack(&self)
+    {
+        assert!(
+            self.trap_stack_intact(),
+            "Hart {} overflowed its trap stack (canary {:#x})",
+            self.logical_id,
+            unsafe { self.trap_stack_bottom().read_volatile() }
+        );
+    }
+
+    /// Number of tasks waiting in this hart's run queue.
+    ///
+    /// Takes the scheduler lock only for the read, so the value may be stale
+    /// by the time it is used.
+    #[inline]
+    pub fn load(&self) -> usize
+    {
+        interrupt::with_disabled(|| self.scheduler.lock().queue_len())
+    }
+
+    /// Whether the running task may be switched away from involuntarily.
+    #[inline]
+    pub fn preemptible(&self) -> bool
+    {
+        self.preempt_count.load(Ordering::Relaxed) == 0
+    }
+
+    /// The current hart's pseudo-random number generator.
+    #[inline]
+    pub fn rng() -> &'static Rng
+    {
+        &Self::get().rng
+    }
+
     #[inline]
     pub fn nth(logical_id: usize) -> &'static Cpu
     {
@@ -108,3 +333,78 @@ impl Cpu
         &cpus[logical_id]
     }
 }
+
+/// Raw `tp`: this hart's `Cpu` once `Cpu::set` has run, zero before.
+///
+/// Unique per hart after boot, so it doubles as a cheap owner tag.
+#[inline]
+pub fn thread_pointer() -> usize
+{
+    let tp: usize;
+    unsafe { asm!("mv {0}, tp", out(reg) tp) }
+    tp
+}
+
+/// Logical ID of the current hart.
+///
+/// `tp` holds this hart's `Cpu`, so this is a register read and one load,
+/// with no lookup in the hart ID map.
+#[inline]
+pub fn logical_hart_id() -> usize
+{
+    Cpu::get().logical_id
+}
+
+/// Physical (`mhartid`) ID of the current hart.
+///
+/// S-mode can't read `mhartid`; this is the ID firmware passed to `_start`,
+/// as recorded in this hart's `Cpu`. Use it for anything addressed by
+/// hardware or SBI, and [`logical_hart_id`] for kernel tables.
+#[inline]
+pub fn physical_hart_id() -> usize
+{
+    Cpu::get().physical_id
+}
+
+/// `(logical hart ID, run-queue length)` for every hart.
+///
+/// Each scheduler is locked on its own, never two at once, so this can't
+/// deadlock against a hart spawning onto another; the result is a snapshot
+/// taken over a short window rather than an atomic one.
+pub fn hart_loads() -> Vec<(usize, usize)>
+{
+    online_cpus()
+        .map(|cpu| (cpu.logical_id, cpu.load()))
+        .collect()
+}
+
+/// Whether this hart is inside a trap handler, where nothing may block,
+/// yield or allocate.
+#[inline]
+pub fn in_interrupt() -> bool
+{
+    Cpu::get().trap_depth.load(Ordering::Relaxed) != 0
+}
+
+/// ID of the task running on this hart, without taking the scheduler lock.
+#[inline]
+pub fn current_task_id() -> TaskId
+{
+    TaskId::from_raw(Cpu::get().current_task.load(Ordering::Relaxed))
+}
+
+/// Every hart that came up, in logical ID order.
+#[inline]
+pub fn online_cpus() -> impl Iterator<Item = &'static Cpu>
+{
+    CPU_VEC.wait().iter().filter(|cpu| cpu.is_online())
+}
+
+/// Draw a pseudo-random value from the current hart's generator.
+///
+/// Not cryptographically secure.
+#[inline]
+pub fn random() -> usize
+{
+    Cpu::rng().next()
+}
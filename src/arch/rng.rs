@@ -0,0 +1,70 @@
+//! Per-hart xorshift pseudo-random number generator.
+//!
+//! This generator is fast and lock-free, but it is NOT cryptographically
+//! secure. Use it for jitter, hash seeds, and placement heuristics only.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+// Any non-zero value works; xorshift never leaves the all-zero state.
+const DEFAULT_SEED: usize = 0x9E37_79B9;
+
+pub struct Rng
+{
+    state: AtomicUsize,
+}
+
+impl Rng
+{
+    #[inline]
+    pub const fn new() -> Self
+    {
+        Self {
+            state: AtomicUsize::new(DEFAULT_SEED),
+        }
+    }
+
+    /// Reset the generator to a deterministic state derived from `seed`.
+    #[inline]
+    pub fn seed(&self, seed: usize)
+    {
+        let state = if seed == 0 { DEFAULT_SEED } else { seed };
+        self.state.store(state, Ordering::Relaxed);
+    }
+
+    /// Advance the generator and return the next value.
+    ///
+    /// The state is only ever touched by its owning hart, so relaxed ordering
+    /// is enough. A trap interleaving with this call may observe a repeated
+    /// value, which is acceptable for non-cryptographic use.
+    #[inline]
+    pub fn next(&self) -> usize
+    {
+        let mut x = self.state.load(Ordering::Relaxed);
+
+        #[cfg(target_pointer_width = "64")]
+        {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+        }
+
+        #[cfg(target_pointer_width = "32")]
+        {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+        }
+
+        self.state.store(x, Ordering::Relaxed);
+        x
+    }
+}
+
+impl Default for Rng
+{
+    #[inline]
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
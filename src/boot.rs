@@ -2,19 +2,23 @@
 
 use core::{
     arch::asm,
+    fmt,
+    hint::spin_loop,
     sync::atomic::{AtomicU8, Ordering},
 };
 
 use ::fdt::Fdt;
-use spin::Mutex;
 
 use crate::{
-    arch::{CPU_VEC, Cpu},
-    demo,
-    drivers::uart::{self, UART, Uart},
+    arch::{self, CPU_VEC, Cpu},
+    drivers::{
+        uart::{self, UART, Uart},
+        virtio,
+    },
     fdt, interrupt, logger,
     memory::heap,
     platform::{plic, sbi, timer},
+    sync::TicketMutex,
 };
 
 #[repr(u8)]
@@ -28,14 +32,206 @@ pub enum BootStage
 #[unsafe(link_section = ".data.boot")]
 pub static BOOT_STATUS: AtomicU8 = AtomicU8::new(BootStage::ColdBoot as _);
 
+/// How long the boot hart waits at the boot barrier for the others, in
+/// microseconds, before leaving the stragglers offline.
+pub const BARRIER_TIMEOUT_US: u64 = 1_000_000;
+
+/// What a hart entering `kmain` is expected to do.
+///
+/// Decided from `BOOT_STATUS` alone: `opaque` is an FDT pointer for the boot
+/// hart but a stack address for secondaries, so its value says nothing about
+/// which one we are.
+enum BootRole
+{
+    Primary
+    {
+        dev_tree_ptr: *const u8,
+    },
+    Secondary,
+}
+
+impl BootRole
+{
+    #[inline]
+    fn current(opaque: usize) -> Self
+    {
+        if BOOT_STATUS.load(Ordering::Acquire) == BootStage::ColdBoot as _
+        {
+            Self::Primary {
+                dev_tree_ptr: opaque as _,
+            }
+        }
+        else
+        {
+            Self::Secondary
+        }
+    }
+}
+
 unsafe extern "C" {
     fn _start();
 }
 
+/// Logs entry into a boot phase, and its duration once dropped.
+pub struct Phase
+{
+    name: &'static str,
+    start: u64,
+}
+
+impl Drop for Phase
+{
+    fn drop(&mut self)
+    {
+        let elapsed = timer::now().wrapping_sub(self.start);
+        match timer::ticks_to_us(elapsed)
+        {
+            Some(us) => log::trace!("Completed {} in {} us.", self.name, us),
+            None => log::trace!("Completed {} in {} ticks.", self.name, elapsed),
+        }
+    }
+}
+
+/// Start timing a boot phase; it ends when the returned guard is dropped.
+#[inline]
+pub fn phase(name: &'static str) -> Phase
+{
+    log::trace!("Entering {}..", name);
+    Phase {
+        name,
+        start: timer::now(),
+    }
+}
+
 fn set_uart(dev_tree: &Fdt)
 {
     let uart_info = fdt::uart::get_info(dev_tree).unwrap();
-    UART.call_once(|| Mutex::new(Uart::with_info(uart_info)));
+    UART.call_once(|| TicketMutex::new(Uart::with_info(uart_info)));
+}
+
+/// Log the firmware and CPU implementation we're running on.
+fn banner()
+{
+    let (major, minor) = sbi::spec_version();
+    let impl_id = sbi::impl_id();
+
+    log::info!(
+        "SBI v{}.{}, implementation {} (id {}, version {:#x})",
+        major,
+        minor,
+        sbi::impl_name(impl_id).unwrap_or("unknown"),
+        impl_id,
+        sbi::impl_version()
+    );
+
+    log::info!(
+        "CPU mvendorid {}, marchid {}, mimpid {}",
+        Id(sbi::mvendorid()),
+        Id(sbi::marchid()),
+        Id(sbi::mimpid())
+    );
+    log::info!("Kernel built for {}", Isa(BUILD_MISA));
+}
+
+/// A machine ID register as the banner shows it; zero means the hart doesn't
+/// implement that register.
+pub struct Id(pub usize);
+
+impl fmt::Display for Id
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self.0
+        {
+            0 => f.write_str("n/a"),
+            v => write!(f, "{v:#x}"),
+        }
+    }
+}
+
+/// A `misa` value shown as an ISA string such as `rv64imafdc`.
+///
+/// Extensions come in canonical order, then any others alphabetically; the
+/// S and U bits are privilege modes, not extensions, so they are left out.
+pub struct Isa(pub usize);
+
+impl fmt::Display for Isa
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        const CANONICAL: &[u8] = b"iemafdqlcbkjtpvh";
+
+        match self.0 >> (usize::BITS - 2)
+        {
+            1 => f.write_str("rv32")?,
+            2 => f.write_str("rv64")?,
+            3 => f.write_str("rv128")?,
+            _ => f.write_str("rv?")?,
+        }
+
+        let has = |letter: u8| self.0 & 1 << (letter - b'a') != 0;
+        let others = (b'a'..=b'z').filter(|l| !CANONICAL.contains(l) && !b"su".contains(l));
+        for letter in CANONICAL.iter().copied().chain(others).filter(|&l| has(l))
+        {
+            write!(f, "{}", letter as char)?;
+        }
+        Ok(())
+    }
+}
+
+/// `misa` for the ISA this kernel was compiled for; S-mode can't read the
+/// hart's own.
+const BUILD_MISA: usize = {
+    const fn bit(letter: u8, present: bool) -> usize
+    {
+        (present as usize) << (letter - b'a')
+    }
+
+    let xlen = if usize::BITS == 64 { 2 } else { 1 };
+    xlen << (usize::BITS - 2)
+        | bit(b'i', true)
+        | bit(b'm', cfg!(target_feature = "m"))
+        | bit(b'a', cfg!(target_feature = "a"))
+        | bit(b'f', cfg!(target_feature = "f"))
+        | bit(b'd', cfg!(target_feature = "d"))
+        | bit(b'c', cfg!(target_feature = "c"))
+};
+
+/// How many times `start_harts` asks the firmware to start each hart.
+pub const HART_START_ATTEMPTS: usize = 3;
+/// Pause between attempts, in microseconds.
+const HART_START_RETRY_US: u64 = 1000;
+
+/// Run `start` until it succeeds, at most [`HART_START_ATTEMPTS`] times.
+///
+/// A failed call may still have got the hart going (or an earlier one may
+/// have, with the firmware now reporting it as already available), so
+/// `status` is checked before each retry and a hart that is started or
+/// pending counts as a success.
+pub fn start_with_retry(
+    mut start: impl FnMut() -> bool,
+    status: impl Fn() -> Option<sbi::HartState>,
+) -> bool
+{
+    for attempt in 1..=HART_START_ATTEMPTS
+    {
+        if start() || status().is_some_and(sbi::HartState::is_starting)
+        {
+            return true;
+        }
+
+        if attempt < HART_START_ATTEMPTS
+        {
+            let hz = timer::timebase_hz().unwrap_or(timer::DEFAULT_TIMEBASE_HZ);
+            let deadline = timer::now() + hz * HART_START_RETRY_US / 1_000_000;
+            while timer::now() < deadline
+            {
+                spin_loop();
+            }
+        }
+    }
+
+    false
 }
 
 fn start_harts()
@@ -45,13 +241,25 @@ fn start_harts()
 
     cpu_zero.set();
 
+    let hart_start = phase("hart start");
     for cpu in rem_cpus
     {
-        if !sbi::hart_start(cpu.physical_id, _start as *const () as usize, cpu.stack_top)
+        let started = start_with_retry(
+            || sbi::hart_start(cpu.physical_id, _start as *const () as usize, cpu.stack_top),
+            || sbi::hart_get_status(cpu.physical_id),
+        );
+
+        if !started
         {
-            log::error!("Failed to start Hart {}", cpu.physical_id);
+            log::error!(
+                "Failed to start Hart {} after {} attempts, leaving it offline",
+                cpu.physical_id,
+                HART_START_ATTEMPTS
+            );
+            cpu.online.store(false, Ordering::Relaxed);
         }
     }
+    drop(hart_start);
 
     unsafe {
         asm!(
@@ -65,13 +273,26 @@ fn start_harts()
 
 fn cold_boot(hart_id: usize, dev_tree_ptr: *const u8) -> !
 {
-    let dev_tree = fdt::fdt(dev_tree_ptr).unwrap();
-
-    let hz = fdt::timer::timebase_hz(&dev_tree);
-    timer::init(hz);
+    // Nothing else is up yet, so a panic here would print nothing.
+    let dev_tree = match fdt::fdt(dev_tree_ptr)
+    {
+        Ok(dev_tree) => dev_tree,
+        Err(e) =>
+        {
+            early_println!("Hart {hart_id}: invalid FDT at {dev_tree_ptr:p}: {e:?}");
+            halt()
+        }
+    };
 
     set_uart(&dev_tree);
     logger::init();
+    banner();
+
+    // After the logger, so a missing timebase is reported.
+    timer::init(timer::timebase_or_default(fdt::timer::timebase_hz(
+        &dev_tree,
+    )));
+    timer::ipi::init(fdt::aclint::sswi_base(&dev_tree));
 
     log::trace!(
         "Hart {} kmain entry. Device Tree pointer: {:p}",
@@ -79,10 +300,22 @@ fn cold_boot(hart_id: usize, dev_tree_ptr: *const u8) -> !
         dev_tree_ptr
     );
 
-    heap::init(&dev_tree);
-    log::trace!("Heap initialized.");
+    #[cfg(feature = "verbose-boot")]
+    fdt::dump::dump(&dev_tree);
+
+    {
+        let _phase = phase("heap init");
+        heap::init(&dev_tree, dev_tree_ptr);
+    }
+
+    uart::init_ports(fdt::uart::all(&dev_tree));
+
+    virtio::init(&dev_tree);
 
-    Cpu::init_vec(&dev_tree, hart_id);
+    {
+        let _phase = phase("CPU vector init");
+        Cpu::init_vec(&dev_tree, hart_id);
+    }
     BOOT_STATUS.store(BootStage::BssInitialized as _, Ordering::Release);
 
     start_harts();
@@ -93,9 +326,17 @@ fn secondary_boot(hart_id: usize) -> !
 {
     let cpu = CPU_VEC.wait().get(fdt::harts::to_logical(hart_id)).unwrap();
     cpu.set();
+
+    log::trace!("Hart {hart_id} took the secondary boot path.");
     hart_setup();
 }
 
+/// Bring this hart's trap handling up and hand it to the scheduler.
+///
+/// Interrupts stay masked until `interrupt::enable`, so nothing before it may
+/// wait on an IPI or timer tick: the locks taken here (console, scheduler)
+/// are only ever held with interrupts masked and are released without help
+/// from this hart, and `idle` is the first place the hart sleeps.
 fn hart_setup() -> !
 {
     let cpu = Cpu::get();
@@ -106,10 +347,37 @@ fn hart_setup() -> !
         cpu.physical_id
     );
 
-    log::trace!("Hart {}: Initializing interrupts..", cpu.logical_id);
-    interrupt::init(cpu.trap_stack_top);
+    {
+        let _phase = phase("interrupt init");
+        interrupt::init(cpu);
+    }
+
+    // Before the boot barrier, so every hart's first tick already uses it.
+    if cpu.logical_id == 0
+    {
+        let path = timer::select_path();
+        log::trace!("Timer ticks programmed via {path:?}");
+    }
+
+    {
+        let _phase = phase("PLIC init");
+        plic::init(cpu.physical_id);
+    }
+
+    {
+        let _phase = phase("boot barrier");
+        wait_all_ready(cpu);
+    }
+
+    // Every PLIC context is set up now, so the route can't be cleared again.
+    if cpu.logical_id == 0
+    {
+        uart::route_irq();
 
-    plic::init(cpu.physical_id);
+        // The last boot-time region is placed by now.
+        #[cfg(feature = "verbose-boot")]
+        crate::memory::map::dump();
+    }
 
     log::trace!("Hart {}: Scheduling next timer interrupt..", cpu.logical_id);
     timer::schedule_next();
@@ -119,29 +387,130 @@ fn hart_setup() -> !
 
     if cpu.logical_id == 0
     {
-        demo::spawn_boot_tasks();
+        #[cfg(feature = "selftest")]
+        crate::selftest::spawn();
+        #[cfg(not(feature = "selftest"))]
+        crate::demo::spawn_boot_tasks();
     }
 
+    idle()
+}
+
+/// Block until every started hart has finished its trap and PLIC setup.
+///
+/// No hart takes a tick or an IPI before this returns, and the boot hart only
+/// spawns work afterwards, so a task is never queued on a hart that can't yet
+/// be interrupted to run it. The boot hart waits at most
+/// [`BARRIER_TIMEOUT_US`]; any hart still not ready is taken offline, and
+/// halts if it does turn up. It releases everyone else by moving
+/// `BOOT_STATUS` to `ReadyToWork`.
+fn wait_all_ready(cpu: &Cpu)
+{
+    cpu.ready.store(true, Ordering::Release);
+
+    if cpu.logical_id == 0
+    {
+        let hz = timer::timebase_hz().unwrap_or(timer::DEFAULT_TIMEBASE_HZ);
+        let deadline = timer::now() + hz * BARRIER_TIMEOUT_US / 1_000_000;
+        while arch::online_cpus().any(|cpu| !cpu.is_ready()) && timer::now() < deadline
+        {
+            spin_loop();
+        }
+
+        for late in arch::online_cpus().filter(|cpu| !cpu.is_ready())
+        {
+            log::error!(
+                "Hart {} missed the boot barrier, leaving it offline",
+                late.physical_id
+            );
+            late.online.store(false, Ordering::Relaxed);
+        }
+        BOOT_STATUS.store(BootStage::ReadyToWork as _, Ordering::Release);
+    }
+    else
+    {
+        while BOOT_STATUS.load(Ordering::Acquire) != BootStage::ReadyToWork as _
+        {
+            spin_loop();
+        }
+
+        // Counted out already: nothing will ever be placed here.
+        if !cpu.is_online()
+        {
+            log::error!(
+                "Hart {}: too late for the boot barrier, halting",
+                cpu.physical_id
+            );
+            halt();
+        }
+    }
+}
+
+/// Stop this hart for good.
+pub fn halt() -> !
+{
+    interrupt::disable();
+    loop
+    {
+        unsafe { asm!("wfi") }
+    }
+}
+
+/// Park the hart until the next interrupt, flushing console output first.
+///
+/// The run queue is checked with interrupts masked and the hart sleeps in the
+/// same masked window: `wfi` still wakes on a pending interrupt, so an IPI
+/// that lands after the check can't be missed. Unmasking afterwards takes the
+/// trap, which is what actually switches to the new work. The timer is
+/// re-armed before each sleep so a lost or filtered IPI costs at most a tick.
+pub fn idle() -> !
+{
+    debug_assert!(
+        interrupt::wake_sources_enabled(),
+        "Idling before interrupt::init; wfi would never wake"
+    );
+
     loop
     {
         uart::drain();
 
-        if !sbi::hart_suspend()
+        interrupt::disable();
+
+        let (has_waiting, idle_hook) = {
+            let scheduler = Cpu::get().scheduler.lock();
+            (scheduler.has_waiting(), scheduler.idle_hook())
+        };
+
+        if has_waiting
         {
-            unsafe { asm!("wfi") }
+            // Work arrived without a trap to switch to it; make one.
+            timer::ipi::raise_local();
         }
+        else
+        {
+            if let Some(hook) = idle_hook
+            {
+                hook();
+            }
+
+            timer::schedule_next();
+
+            if !sbi::hart_suspend()
+            {
+                unsafe { asm!("wfi") }
+            }
+        }
+
+        interrupt::enable();
     }
 }
 
 #[unsafe(no_mangle)]
 pub extern "C" fn kmain(hart_id: usize, opaque: usize) -> !
 {
-    if BOOT_STATUS.load(Ordering::Acquire) == BootStage::ColdBoot as _
-    {
-        cold_boot(hart_id, opaque as *const u8)
-    }
-    else
+    match BootRole::current(opaque)
     {
-        secondary_boot(hart_id)
+        BootRole::Primary { dev_tree_ptr } => cold_boot(hart_id, dev_tree_ptr),
+        BootRole::Secondary => secondary_boot(hart_id),
     }
 }
@@ -0,0 +1,176 @@
+//! Fixed-capacity containers that never allocate.
+//!
+//! These are safe to use from interrupt handlers and before the heap exists.
+
+use core::mem::MaybeUninit;
+
+/// FIFO ring buffer holding up to `N` values inline.
+///
+/// Rejected pushes are counted rather than silently lost, so callers can
+/// report overruns.
+pub struct RingBuffer<T, const N: usize>
+{
+    buf: [MaybeUninit<T>; N],
+    head: usize,
+    len: usize,
+    overruns: usize,
+}
+
+impl<T, const N: usize> RingBuffer<T, N>
+{
+    #[inline]
+    pub const fn new() -> Self
+    {
+        Self {
+            buf: [const { MaybeUninit::uninit() }; N],
+            head: 0,
+            len: 0,
+            overruns: 0,
+        }
+    }
+
+    #[inline]
+    pub const fn capacity(&self) -> usize
+    {
+        N
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize
+    {
+        self.len
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool
+    {
+        self.len == 0
+    }
+
+    #[inline]
+    pub fn is_full(&self) -> bool
+    {
+        self.len == N
+    }
+
+    /// Number of pushes rejected because the buffer was full.
+    #[inline]
+    pub fn overruns(&self) -> usize
+    {
+        self.overruns
+    }
+
+    /// Append `value`, handing it back if the buffer is full.
+    pub fn push(&mut self, value: T) -> Result<(), T>
+    {
+        if self.is_full()
+        {
+            self.overruns += 1;
+            return Err(value);
+        }
+
+        let tail = (self.head + self.len) % N;
+        self.buf[tail].write(value);
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Append `value`, evicting and returning the oldest one if full.
+    ///
+    /// Evictions count as overruns.
+    pub fn force_push(&mut self, value: T) -> Option<T>
+    {
+        let evicted = if self.is_full()
+        {
+            self.overruns += 1;
+            self.pop()
+        }
+        else
+        {
+            None
+        };
+
+        let tail = (self.head + self.len) % N;
+        self.buf[tail].write(value);
+        self.len += 1;
+        evicted
+    }
+
+    /// Remove the oldest value.
+    pub fn pop(&mut self) -> Option<T>
+    {
+        if self.is_empty()
+        {
+            return None;
+        }
+
+        // Slot `head` is initialized while `len > 0`, and advancing `head`
+        // below ensures it is never read again.
+        let value = unsafe { self.buf[self.head].assume_init_read() };
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        Some(value)
+    }
+
+    /// Borrow the oldest value without removing it.
+    #[inline]
+    pub fn peek(&self) -> Option<&T>
+    {
+        (!self.is_empty()).then(|| unsafe { self.buf[self.head].assume_init_ref() })
+    }
+
+    /// Drop every stored value. The overrun count is kept.
+    #[inline]
+    pub fn clear(&mut self)
+    {
+        while self.pop().is_some()
+        {}
+    }
+}
+
+impl<T: Copy, const N: usize> RingBuffer<T, N>
+{
+    /// Append as much of `values` as fits, in order, returning how many were
+    /// taken. A call that can't take everything counts as one overrun.
+    pub fn push_slice(&mut self, values: &[T]) -> usize
+    {
+        let count = values.len().min(N - self.len);
+        if count < values.len()
+        {
+            self.overruns += 1;
+        }
+
+        // At most two runs: up to the end of `buf`, then from its start.
+        let tail = (self.head + self.len) % N;
+        let first = count.min(N - tail);
+        for (slot, &value) in self.buf[tail..tail + first].iter_mut().zip(values)
+        {
+            slot.write(value);
+        }
+        for (slot, &value) in self.buf.iter_mut().zip(&values[first..count])
+        {
+            slot.write(value);
+        }
+
+        self.len += count;
+        count
+    }
+}
+
+impl<T, const N: usize> Default for RingBuffer<T, N>
+{
+    #[inline]
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> Drop for RingBuffer<T, N>
+{
+    #[inline]
+    fn drop(&mut self)
+    {
+        self.clear();
+    }
+}
@@ -0,0 +1,109 @@
+//! Redirectable destination for `print!` output.
+//!
+//! By default output goes to the UART. Installing a [`Sink`] diverts it, for
+//! instance into a [`Capture`] so code can inspect what was printed.
+
+use alloc::{string::String, vec::Vec};
+use core::fmt::{self, Write};
+
+use crate::{interrupt, sync::Mutex};
+
+/// Receives formatted console output.
+pub trait Sink: Sync
+{
+    fn write_str(&self, s: &str);
+}
+
+static SINK: Mutex<Option<&'static dyn Sink>> = Mutex::new(None);
+
+/// Send subsequent output to `sink`, returning the one it replaces.
+pub fn set_sink(sink: &'static dyn Sink) -> Option<&'static dyn Sink>
+{
+    interrupt::with_disabled(|| SINK.lock().replace(sink))
+}
+
+/// Make sure everything printed so far has reached the terminal.
+///
+/// Call before anything that stops the machine, which would otherwise drop
+/// output still buffered for the UART.
+#[inline]
+pub fn flush()
+{
+    crate::drivers::uart::flush();
+}
+
+/// Send subsequent output back to the UART.
+pub fn reset_sink() -> Option<&'static dyn Sink>
+{
+    interrupt::with_disabled(|| SINK.lock().take())
+}
+
+/// Write `args` to the installed sink, if any. Returns whether it did.
+///
+/// Must be called with interrupts disabled.
+pub(crate) fn write_to_sink(args: fmt::Arguments) -> bool
+{
+    struct Adapter(&'static dyn Sink);
+
+    impl Write for Adapter
+    {
+        #[inline]
+        fn write_str(&mut self, s: &str) -> fmt::Result
+        {
+            self.0.write_str(s);
+            Ok(())
+        }
+    }
+
+    // Copy the reference out so the sink runs without `SINK` held.
+    let Some(sink) = *SINK.lock()
+    else
+    {
+        return false;
+    };
+
+    let _ = Adapter(sink).write_fmt(args);
+    true
+}
+
+/// Sink that keeps everything written to it in memory.
+pub struct Capture
+{
+    buf: Mutex<Vec<u8>>,
+}
+
+impl Capture
+{
+    #[inline]
+    pub const fn new() -> Self
+    {
+        Self {
+            buf: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Take the captured output, leaving the capture empty.
+    pub fn take(&self) -> String
+    {
+        let bytes = interrupt::with_disabled(|| core::mem::take(&mut *self.buf.lock()));
+        String::from_utf8(bytes).unwrap_or_else(|e| String::from_utf8_lossy(e.as_bytes()).into())
+    }
+}
+
+impl Default for Capture
+{
+    #[inline]
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+impl Sink for Capture
+{
+    #[inline]
+    fn write_str(&self, s: &str)
+    {
+        interrupt::with_disabled(|| self.buf.lock().extend_from_slice(s.as_bytes()));
+    }
+}
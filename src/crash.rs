@@ -0,0 +1,194 @@
+//! Coordinated whole-system stop when a hart panics.
+//!
+//! The panicking hart IPIs every other hart, and each one reports what it was
+//! running before halting. This gives a snapshot of the whole machine rather
+//! than of the one hart that noticed the problem.
+
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+
+use crate::{
+    arch::{CPU_VEC, Cpu},
+    boot, console,
+    platform::{
+        sbi::{self, ResetReason, ResetType},
+        timer,
+    },
+    task::TrapContext,
+};
+
+const NO_HART: usize = usize::MAX;
+const BACKTRACE_DEPTH: usize = 8;
+const REPORT_TIMEOUT_US: u64 = 100_000;
+
+// Frames further than this from the interrupted `sp` are assumed bogus.
+const MAX_STACK_WALK: usize = 64 * 1024;
+
+static PANIC_HART: AtomicUsize = AtomicUsize::new(NO_HART);
+static REPORTED: AtomicUsize = AtomicUsize::new(0);
+static POLICY: AtomicU8 = AtomicU8::new(PanicPolicy::Hang as _);
+
+/// What the machine does once a panic has been reported.
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PanicPolicy
+{
+    /// Halt every hart, leaving state in place for a debugger.
+    Hang = 0,
+    /// Power off through SBI, reporting a system failure (CI).
+    Shutdown = 1,
+    /// Warm-reboot through SBI.
+    Reboot = 2,
+}
+
+/// Select what happens after a panic.
+#[inline]
+pub fn set_policy(policy: PanicPolicy)
+{
+    POLICY.store(policy as _, Ordering::Relaxed);
+}
+
+#[inline]
+pub fn policy() -> PanicPolicy
+{
+    match POLICY.load(Ordering::Relaxed)
+    {
+        0 => PanicPolicy::Hang,
+        1 => PanicPolicy::Shutdown,
+        _ => PanicPolicy::Reboot,
+    }
+}
+
+/// Carry out the panic policy. Halts if the firmware can't reset.
+pub fn finish() -> !
+{
+    let reset_type = match policy()
+    {
+        PanicPolicy::Hang => boot::halt(),
+        PanicPolicy::Shutdown => ResetType::Shutdown,
+        PanicPolicy::Reboot => ResetType::WarmReboot,
+    };
+
+    console::flush();
+    sbi::system_reset(reset_type, ResetReason::SystemFailure);
+    log::error!("SBI system reset failed, halting instead.");
+    boot::halt()
+}
+
+/// Claim the crash for this hart.
+///
+/// Returns `false` if a panic is already in progress, either a nested panic
+/// on this hart or one racing on another, in which case the caller should
+/// halt without reporting.
+pub fn begin() -> bool
+{
+    // Before `Cpu::set` we can't tell harts apart; such early panics can only
+    // come from the boot hart anyway.
+    let id = Cpu::try_get().map_or(0, |cpu| cpu.logical_id);
+
+    PANIC_HART
+        .compare_exchange(NO_HART, id, Ordering::AcqRel, Ordering::Acquire)
+        .is_ok()
+}
+
+/// Whether some hart is panicking and the others should stop.
+#[inline]
+pub fn is_stopping() -> bool
+{
+    PANIC_HART.load(Ordering::Acquire) != NO_HART
+}
+
+/// Ask every other hart to report and halt, waiting a bounded time for them.
+///
+/// Harts running with interrupts disabled can't answer; they are named once
+/// the timeout expires.
+pub fn stop_others()
+{
+    let Some(cpus) = CPU_VEC.get()
+    else
+    {
+        return;
+    };
+    let me = PANIC_HART.load(Ordering::Acquire);
+
+    let others = || {
+        cpus.iter()
+            .filter(move |cpu| cpu.logical_id != me && cpu.is_online())
+    };
+    others().for_each(|cpu| timer::ipi::send(cpu.physical_id));
+
+    let expected = others().count();
+    let start = timer::now();
+    while REPORTED.load(Ordering::Acquire) < expected
+    {
+        let elapsed = timer::ticks_to_us(timer::now().wrapping_sub(start));
+        if elapsed.is_none_or(|us| us >= REPORT_TIMEOUT_US)
+        {
+            log::error!(
+                "{} of {} harts did not report before the timeout.",
+                expected - REPORTED.load(Ordering::Acquire),
+                expected
+            );
+            return;
+        }
+
+        core::hint::spin_loop();
+    }
+}
+
+/// Print what this hart was running when it was stopped, then halt.
+///
+/// Called from the software interrupt handler with the interrupted context.
+pub fn report_and_halt(context: &TrapContext) -> !
+{
+    let cpu = Cpu::get();
+
+    // Never block here: the lock holder may be the hart that panicked.
+    let task = cpu
+        .scheduler
+        .try_lock()
+        .map_or("<locked>", |scheduler| scheduler.task().kind.name());
+
+    log::error!(
+        "Hart {} stopped: {} task, pc {:#x}, sp {:#x}",
+        cpu.logical_id,
+        task,
+        context.pc,
+        context.sp
+    );
+    backtrace(context);
+
+    REPORTED.fetch_add(1, Ordering::AcqRel);
+    boot::halt()
+}
+
+/// Walk the frame-pointer chain starting at the interrupted `s0`.
+///
+/// Only meaningful when built with frame pointers; the walk stops at the
+/// first frame that doesn't look like it belongs to the interrupted stack.
+fn backtrace(context: &TrapContext)
+{
+    const WORD: usize = size_of::<usize>();
+
+    let stack = context.sp..context.sp.saturating_add(MAX_STACK_WALK);
+    let mut fp = context.s0;
+
+    for depth in 0..BACKTRACE_DEPTH
+    {
+        if !stack.contains(&fp) || !fp.is_multiple_of(WORD) || fp < stack.start + 2 * WORD
+        {
+            break;
+        }
+
+        // The frame record sits just below `fp`: `ra`, then the caller's `fp`.
+        let ra = unsafe { ((fp - WORD) as *const usize).read() };
+        let next = unsafe { ((fp - 2 * WORD) as *const usize).read() };
+
+        log::error!("  #{depth}: {ra:#x}");
+
+        if next <= fp
+        {
+            break;
+        }
+        fp = next;
+    }
+}
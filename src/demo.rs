@@ -19,6 +19,12 @@ pub fn spawn_boot_tasks()
     Task::spawn(task_a);
     Task::spawn(task_b);
     Task::spawn(task_c);
+
+    #[cfg(feature = "initramfs")]
+    if let Err(e) = crate::elf::spawn(crate::elf::INITRAMFS)
+    {
+        log::error!("Failed to load initramfs: {:?}", e);
+    }
 }
 
 fn task_a()
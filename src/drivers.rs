@@ -3,5 +3,8 @@
 //! This file serves as the entrypoint for concrete device drivers used across
 //! the kernel.
 
+pub mod block;
+pub mod virtio;
+
 #[macro_use]
 pub mod uart;
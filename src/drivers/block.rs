@@ -0,0 +1,132 @@
+//! Block device abstraction and a heap-backed RAM disk.
+//!
+//! Devices transfer whole blocks of `BLOCK_SIZE` bytes, which is the unit a
+//! future filesystem layer is expected to work in.
+
+use alloc::{vec, vec::Vec};
+
+pub const BLOCK_SIZE: usize = 512;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BlockError
+{
+    /// The requested block index is past the end of the device.
+    OutOfRange,
+    /// A multi-block transfer whose buffer isn't a whole number of blocks.
+    Misaligned,
+    /// The device reported a failure while servicing the request.
+    Io,
+}
+
+pub trait BlockDevice
+{
+    /// Number of addressable blocks.
+    fn block_count(&self) -> usize;
+
+    fn read_block(&mut self, n: usize, buf: &mut [u8; BLOCK_SIZE]) -> Result<(), BlockError>;
+
+    fn write_block(&mut self, n: usize, buf: &[u8; BLOCK_SIZE]) -> Result<(), BlockError>;
+
+    /// Read consecutive blocks from `first` until `buf` is full.
+    ///
+    /// Nothing is read unless every block is in range.
+    fn read_blocks(&mut self, first: usize, buf: &mut [u8]) -> Result<(), BlockError>
+    {
+        check_span(self.block_count(), first, buf.len())?;
+        for (n, chunk) in (first..).zip(buf.as_chunks_mut().0)
+        {
+            self.read_block(n, chunk)?;
+        }
+        Ok(())
+    }
+
+    /// Write consecutive blocks from `first` with the contents of `buf`.
+    ///
+    /// Nothing is written unless every block is in range.
+    fn write_blocks(&mut self, first: usize, buf: &[u8]) -> Result<(), BlockError>
+    {
+        check_span(self.block_count(), first, buf.len())?;
+        for (n, chunk) in (first..).zip(buf.as_chunks().0)
+        {
+            self.write_block(n, chunk)?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether `len` bytes from block `first` are whole blocks on a device of
+/// `blocks` blocks.
+fn check_span(blocks: usize, first: usize, len: usize) -> Result<(), BlockError>
+{
+    if !len.is_multiple_of(BLOCK_SIZE)
+    {
+        return Err(BlockError::Misaligned);
+    }
+
+    match first.checked_add(len / BLOCK_SIZE)
+    {
+        Some(end) if end <= blocks => Ok(()),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
+
+/// Volatile block device backed by kernel heap memory.
+pub struct RamDisk
+{
+    data: Vec<u8>,
+}
+
+impl RamDisk
+{
+    /// Create a zero-filled disk with `blocks` blocks.
+    #[inline]
+    pub fn new(blocks: usize) -> Self
+    {
+        Self {
+            data: vec![0; blocks * BLOCK_SIZE],
+        }
+    }
+
+    /// Create a disk initialized from `image`, zero-padded to a whole block.
+    pub fn from_image(image: &[u8]) -> Self
+    {
+        let mut disk = Self::new(image.len().div_ceil(BLOCK_SIZE));
+        disk.data[..image.len()].copy_from_slice(image);
+        disk
+    }
+
+    #[inline]
+    fn block_range(&self, n: usize) -> Result<core::ops::Range<usize>, BlockError>
+    {
+        if n >= self.block_count()
+        {
+            return Err(BlockError::OutOfRange);
+        }
+
+        let start = n * BLOCK_SIZE;
+        Ok(start..start + BLOCK_SIZE)
+    }
+}
+
+impl BlockDevice for RamDisk
+{
+    #[inline]
+    fn block_count(&self) -> usize
+    {
+        self.data.len() / BLOCK_SIZE
+    }
+
+    fn read_block(&mut self, n: usize, buf: &mut [u8; BLOCK_SIZE]) -> Result<(), BlockError>
+    {
+        let range = self.block_range(n)?;
+        buf.copy_from_slice(&self.data[range]);
+        Ok(())
+    }
+
+    fn write_block(&mut self, n: usize, buf: &[u8; BLOCK_SIZE]) -> Result<(), BlockError>
+    {
+        let range = self.block_range(n)?;
+        self.data[range].copy_from_slice(buf);
+        Ok(())
+    }
+}
@@ -1,37 +1,75 @@
 //! 16550-compatible UART driver and console formatting helpers.
 //!
 //! This module implements the UART backend used for kernel console output.
-
+//!
+//! The console locks (the sink, the TX buffer and the UART itself) are always
+//! the innermost locks: printing is fine while holding any other lock, from
+//! any context, but nothing may be locked while one of them is held. A hart
+//! that re-enters `print!` through a fault or panic raised mid-write falls
+//! back to the SBI console instead of spinning on its own locks.
+//!
+//! Interrupt handlers may print as well. Every console lock is taken with
+//! interrupts off, so a handler never finds one held on its own hart except
+//! through that same re-entry, and another hart holds one for at most a
+//! single write. Nothing reads the UART yet; an RX handler that echoes input
+//! through `print!` inherits these guarantees, though not a bound on how long
+//! the write itself spins on a full FIFO.
+
+use alloc::boxed::Box;
 use core::{
-    arch::asm,
     fmt::{self, Write},
+    sync::atomic::Ordering,
 };
 
-use spin::{Mutex, Once};
-
 use crate::{
+    arch::Cpu,
+    console,
+    fdt::uart::UartInfo,
     interrupt,
     mmio::{AccessStrategy, IoWidth, Register},
+    platform::plic,
     soc::uart::*,
+    sync::{OnceLock, TicketMutex},
 };
 
+/// One 16550-compatible UART; see [`UART`] for the console and [`port`] for
+/// the rest.
 pub struct Uart
 {
     base: usize,
     shift: u8,
     width: IoWidth,
+    irq: Option<u32>,
 }
 
 impl Uart
 {
     #[inline]
-    pub const fn with_info((base, shift, width): (usize, u8, IoWidth)) -> Self
+    pub const fn with_info(info: UartInfo) -> Self
+    {
+        Self {
+            base: info.base,
+            shift: info.shift,
+            width: info.width,
+            irq: info.irq,
+        }
+    }
+
+    #[inline]
+    pub fn base(&self) -> usize
     {
-        Self { base, shift, width }
+        self.base
     }
 
+    /// PLIC source this UART raises, if the device tree gave one.
     #[inline]
-    const fn reg(&self, offset: usize) -> Register<u8>
+    pub fn irq(&self) -> Option<u32>
+    {
+        self.irq
+    }
+
+    #[inline]
+    fn reg(&self, offset: usize) -> Register<u8>
     {
         Register::new(
             (self.base + (offset << self.shift)) as _,
@@ -57,10 +95,16 @@ impl Uart
             core::hint::spin_loop();
         }
 
-        // All previous Writes must finish before this Output operation
-        unsafe { asm!("fence w, o") }
+        self.reg(THR_OFFSET).write_ordered(c)
+    }
 
-        self.reg(THR_OFFSET).write(c)
+    /// Wait until the last byte written has left the shift register.
+    pub fn wait_tx_empty(&self)
+    {
+        while (self.reg(lsr::ADDR_OFFSET).read() & lsr::TX_EMPTY) == 0
+        {
+            core::hint::spin_loop();
+        }
     }
 }
 
@@ -74,7 +118,39 @@ impl fmt::Write for Uart
     }
 }
 
-pub static UART: Once<Mutex<Uart>> = Once::new();
+// Every hart prints through this lock, so serve them in FIFO order.
+//
+// It is only ever taken with local interrupts disabled, so its holder can't be
+// preempted mid-write: a waiter is delayed by at most one critical section per
+// hart queued ahead of it, whatever the tasks involved. Keep it that way rather
+// than adding priority donation here.
+pub static UART: OnceLock<TicketMutex<Uart>> = OnceLock::new();
+
+// Every UART other than the console, in device tree order. Nothing prints
+// through these, so they are outside the console lock rules above.
+static PORTS: OnceLock<Box<[TicketMutex<Uart>]>> = OnceLock::new();
+
+/// Register the non-console UARTs in `infos`; needs the heap.
+pub fn init_ports(infos: impl Iterator<Item = UartInfo>)
+{
+    let console = UART
+        .get()
+        .map(|uart| interrupt::with_disabled(|| uart.lock().base()));
+
+    PORTS.call_once(|| {
+        infos
+            .filter(|info| Some(info.base) != console)
+            .map(|info| TicketMutex::new(Uart::with_info(info)))
+            .collect()
+    });
+}
+
+/// The `index`th non-console UART, if there is one.
+#[inline]
+pub fn port(index: usize) -> Option<&'static TicketMutex<Uart>>
+{
+    PORTS.get()?.get(index)
+}
 
 mod buffering
 {
@@ -83,70 +159,19 @@ mod buffering
         sync::atomic::{AtomicBool, Ordering},
     };
 
-    use spin::Mutex;
-
     use super::Uart;
+    use crate::{collections::RingBuffer, sync::Mutex};
 
     const TX_BUF_CAP: usize = 4096;
 
+    type TxRing = RingBuffer<u8, TX_BUF_CAP>;
+
     static TX_BUFFER: Mutex<TxRing> = Mutex::new(TxRing::new());
 
     // When enabled, writes bypass the ring buffer after first flushing any
     // buffered bytes. This is used by paths that must force immediate output.
     static DIRECT_MODE: AtomicBool = AtomicBool::new(false);
 
-    struct TxRing
-    {
-        buf: [u8; TX_BUF_CAP],
-        head: usize,
-        len: usize,
-    }
-
-    impl TxRing
-    {
-        #[inline]
-        const fn new() -> Self
-        {
-            Self {
-                buf: [0; TX_BUF_CAP],
-                head: 0,
-                len: 0,
-            }
-        }
-
-        #[inline]
-        fn is_full(&self) -> bool
-        {
-            self.len == TX_BUF_CAP
-        }
-
-        fn push(&mut self, byte: u8) -> bool
-        {
-            if self.is_full()
-            {
-                return false;
-            }
-
-            let tail = (self.head + self.len) % TX_BUF_CAP;
-            self.buf[tail] = byte;
-            self.len += 1;
-            true
-        }
-
-        fn pop(&mut self) -> Option<u8>
-        {
-            if self.len == 0
-            {
-                return None;
-            }
-
-            let byte = self.buf[self.head];
-            self.head = (self.head + 1) % TX_BUF_CAP;
-            self.len -= 1;
-            Some(byte)
-        }
-    }
-
     pub fn drain_into(uart: &Uart)
     {
         let mut tx = TX_BUFFER.lock();
@@ -156,6 +181,12 @@ mod buffering
         }
     }
 
+    #[inline]
+    pub fn pending() -> usize
+    {
+        TX_BUFFER.lock().len()
+    }
+
     #[inline]
     pub fn set_direct_mode(enabled: bool)
     {
@@ -188,44 +219,64 @@ mod buffering
 
     impl Write for BufferedWriter<'_>
     {
+        // Each piece `fmt` hands us (padding included) is queued in one go.
         fn write_str(&mut self, s: &str) -> fmt::Result
         {
-            for byte in s.bytes()
+            let mut bytes = s.as_bytes();
+
+            if !self.direct_fallback
             {
-                if self.direct_fallback
+                bytes = &bytes[self.tx.push_slice(bytes)..];
+                if bytes.is_empty()
                 {
-                    if let Some(uart) = self.uart.as_deref_mut()
-                    {
-                        uart.putc(byte);
-                        continue;
-                    }
-                    return Err(fmt::Error);
+                    return Ok(());
                 }
 
-                if !self.tx.push(byte)
+                let Some(uart) = self.uart.as_deref_mut()
+                else
+                {
+                    return Err(fmt::Error);
+                };
+
+                // Drain the backlog and switch to direct output for the rest
+                // of this message so bytes are not silently dropped.
+                while let Some(pending) = self.tx.pop()
                 {
-                    let Some(uart) = self.uart.as_deref_mut()
-                    else
-                    {
-                        return Err(fmt::Error);
-                    };
-
-                    // Drain the backlog and switch to direct output for the rest
-                    // of this message so bytes are not silently dropped.
-                    while let Some(pending) = self.tx.pop()
-                    {
-                        uart.putc(pending);
-                    }
-
-                    self.direct_fallback = true;
-                    uart.putc(byte);
+                    uart.putc(pending);
                 }
+                self.direct_fallback = true;
             }
+
+            let Some(uart) = self.uart.as_deref_mut()
+            else
+            {
+                return Err(fmt::Error);
+            };
+            bytes.iter().for_each(|&byte| uart.putc(byte));
             Ok(())
         }
     }
 }
 
+/// Logical hart that takes the console UART's interrupt; see [`route_irq`].
+pub const IRQ_HART: usize = 0;
+
+/// Route the console UART's interrupt to [`IRQ_HART`] alone, where it drains
+/// the transmit buffer. Every hart's PLIC context must be set up first, so
+/// that none of them clears the route afterwards.
+pub fn route_irq()
+{
+    let Some(irq) = UART
+        .get()
+        .and_then(|uart| interrupt::with_disabled(|| uart.lock().irq()))
+    else
+    {
+        return;
+    };
+
+    plic::route_exclusive(irq, IRQ_HART, |_| drain());
+}
+
 #[inline]
 pub fn drain()
 {
@@ -241,18 +292,96 @@ pub fn drain()
     });
 }
 
+/// Push every buffered byte out of the UART and wait until the last one is
+/// on the wire.
+///
+/// Does nothing if this hart is already inside `print!`, whose locks it may
+/// be holding further up the stack.
+pub fn flush()
+{
+    interrupt::with_disabled(|| {
+        let Some(_entry) = ConsoleEntry::enter()
+        else
+        {
+            return;
+        };
+        let Some(uart_mutex) = UART.get()
+        else
+        {
+            return;
+        };
+
+        let uart = uart_mutex.lock();
+        buffering::drain_into(&uart);
+        uart.wait_tx_empty();
+    });
+}
+
+/// Bytes buffered for the UART but not yet written to it.
+#[inline]
+pub fn tx_pending() -> usize
+{
+    interrupt::with_disabled(buffering::pending)
+}
+
 #[inline]
 pub fn set_direct_mode(enabled: bool)
 {
     buffering::set_direct_mode(enabled);
 }
 
+/// Marks the current hart as printing until dropped.
+struct ConsoleEntry(Option<&'static Cpu>);
+
+impl ConsoleEntry
+{
+    /// `None` if this hart is already printing, i.e. we got here from a trap
+    /// taken in the middle of an earlier `print!`.
+    #[inline]
+    fn enter() -> Option<Self>
+    {
+        // Before the CPU vector exists only the boot hart runs.
+        let cpu = Cpu::try_get();
+        if cpu.is_some_and(|cpu| cpu.in_console.swap(true, Ordering::Acquire))
+        {
+            return None;
+        }
+        Some(Self(cpu))
+    }
+}
+
+impl Drop for ConsoleEntry
+{
+    #[inline]
+    fn drop(&mut self)
+    {
+        if let Some(cpu) = self.0
+        {
+            cpu.in_console.store(false, Ordering::Release);
+        }
+    }
+}
+
 #[doc(hidden)]
 #[inline]
 pub fn _print(args: fmt::Arguments)
 {
     // Keep writes atomic with respect to local interrupt handlers.
     interrupt::with_disabled(|| {
+        let Some(_entry) = ConsoleEntry::enter()
+        else
+        {
+            // Our own console locks may be held further up this stack.
+            _early_print(args);
+            return;
+        };
+
+        // Direct mode means we're crashing; that must reach the hardware.
+        if !buffering::is_direct_mode() && console::write_to_sink(args)
+        {
+            return;
+        }
+
         if buffering::is_direct_mode()
         {
             if let Some(uart_mutex) = UART.get()
@@ -269,6 +398,17 @@ pub fn _print(args: fmt::Arguments)
     });
 }
 
+#[doc(hidden)]
+#[inline]
+pub fn _early_print(args: fmt::Arguments)
+{
+    use crate::platform::sbi::Console;
+
+    // `Console` only borrows `args` and emits bytes one by one; neither side
+    // touches the heap, so this is sound before `heap::init`.
+    let _ = Console.write_fmt(args);
+}
+
 #[macro_export]
 macro_rules! print {
     ($($arg:tt)*) => ($crate::drivers::uart::_print(format_args!($($arg)*)));
@@ -279,3 +419,18 @@ macro_rules! println {
     () => ($crate::print!("\n"));
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
+
+/// Like `print!`, but through the SBI console, without buffering or locking.
+///
+/// Safe to use before the heap, the UART, or even `.bss` are initialized, at
+/// the cost of output from different harts interleaving.
+#[macro_export]
+macro_rules! early_print {
+    ($($arg:tt)*) => ($crate::drivers::uart::_early_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! early_println {
+    () => ($crate::early_print!("\n"));
+    ($($arg:tt)*) => ($crate::early_print!("{}\n", format_args!($($arg)*)));
+}
@@ -0,0 +1,420 @@
+//! VirtIO block device driver over the MMIO transport.
+//!
+//! Requests are issued one at a time and completion is polled, which keeps
+//! the driver independent of PLIC routing. Both the legacy (version 1) and
+//! modern (version 2) register interfaces are supported, since QEMU defaults
+//! to legacy.
+
+use alloc::boxed::Box;
+use core::ptr::NonNull;
+
+use ::fdt::Fdt;
+
+use super::block::{BLOCK_SIZE, BlockDevice, BlockError};
+use crate::{
+    arch::PAGE_SIZE,
+    fdt, interrupt,
+    memory::heap,
+    mmio::{self, AccessStrategy, Register},
+    soc::virtio::*,
+    sync::{Mutex, OnceLock},
+};
+
+const QUEUE_SIZE: u16 = 8;
+
+const DESC_F_NEXT: u16 = 1 << 0;
+const DESC_F_WRITE: u16 = 1 << 1;
+
+const BLK_T_IN: u32 = 0;
+const BLK_T_OUT: u32 = 1;
+const BLK_S_OK: u8 = 0;
+const BLK_F_RO: u32 = 1 << 5;
+
+const F_VERSION_1: u32 = 1 << 0; // Bit 32, i.e. bit 0 of feature word 1
+
+// Split virtqueue layout: descriptors and the available ring share the first
+// page, the used ring starts on the next one (legacy requires page alignment).
+const AVAIL_OFFSET: usize = QUEUE_SIZE as usize * size_of::<Descriptor>();
+const USED_OFFSET: usize = PAGE_SIZE;
+const QUEUE_PAGES: usize = 2;
+
+const _: () = assert!(AVAIL_OFFSET + 6 + 2 * QUEUE_SIZE as usize <= USED_OFFSET);
+
+pub static BLOCK_DEVICE: OnceLock<Mutex<VirtioBlock>> = OnceLock::new();
+
+#[repr(C)]
+struct Descriptor
+{
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+struct RequestHeader
+{
+    kind: u32,
+    _reserved: u32,
+    sector: u64,
+}
+
+struct VirtQueue
+{
+    base: NonNull<u8>,
+    next_avail: u16,
+    last_used: u16,
+}
+
+impl VirtQueue
+{
+    fn new() -> Option<Self>
+    {
+        let base = NonNull::new(heap::pmm().lock().alloc_pages(QUEUE_PAGES))?;
+        unsafe { base.write_bytes(0, QUEUE_PAGES * PAGE_SIZE) }
+
+        Some(Self {
+            base,
+            next_avail: 0,
+            last_used: 0,
+        })
+    }
+
+    /// A queue whose rings have already moved `index` entries, for checking
+    /// index wraparound without issuing that many requests.
+    #[cfg(feature = "selftest")]
+    fn starting_at(index: u16) -> Option<Self>
+    {
+        let mut queue = Self::new()?;
+        queue.next_avail = index;
+        queue.last_used = index;
+        let used_idx = queue.addr(USED_OFFSET + 2) as *mut u16;
+        unsafe { used_idx.write_volatile(index) }
+        Some(queue)
+    }
+
+    #[inline]
+    fn addr(&self, offset: usize) -> usize
+    {
+        self.base.as_ptr() as usize + offset
+    }
+
+    #[inline]
+    fn set_descriptor(&mut self, i: u16, addr: usize, len: usize, flags: u16, next: u16)
+    {
+        let desc = Descriptor {
+            addr: addr as u64,
+            len: len as u32,
+            flags,
+            next,
+        };
+        let ptr = self.addr(i as usize * size_of::<Descriptor>()) as *mut Descriptor;
+        unsafe { ptr.write_volatile(desc) }
+    }
+
+    /// Publish the chain starting at `head` to the device.
+    fn submit(&mut self, head: u16)
+    {
+        let slot = self.next_avail % QUEUE_SIZE;
+        let ring = self.addr(AVAIL_OFFSET + 4 + 2 * slot as usize) as *mut u16;
+        unsafe { ring.write_volatile(head) }
+
+        self.next_avail = self.next_avail.wrapping_add(1);
+
+        // The ring entry must be visible before the index that publishes it.
+        mmio::write_barrier();
+        let idx = self.addr(AVAIL_OFFSET + 2) as *mut u16;
+        unsafe { idx.write_volatile(self.next_avail) }
+    }
+
+    /// Consume one completion if the device has produced any.
+    fn pop_used(&mut self) -> bool
+    {
+        let idx = self.addr(USED_OFFSET + 2) as *const u16;
+        if unsafe { idx.read_volatile() } == self.last_used
+        {
+            return false;
+        }
+
+        // Don't read buffers the device wrote until we've seen the index.
+        mmio::read_barrier();
+        self.last_used = self.last_used.wrapping_add(1);
+        true
+    }
+}
+
+impl Drop for VirtQueue
+{
+    fn drop(&mut self)
+    {
+        heap::pmm()
+            .lock()
+            .free_pages(self.base.as_ptr(), QUEUE_PAGES);
+    }
+}
+
+pub struct VirtioBlock
+{
+    base: usize,
+    queue: VirtQueue,
+    capacity: usize,
+    read_only: bool,
+}
+
+// The queue memory is owned exclusively by this driver; access is serialized
+// by `BLOCK_DEVICE`'s lock.
+unsafe impl Send for VirtioBlock {}
+
+impl VirtioBlock
+{
+    /// Initialize the transport at `base` if it hosts a block device.
+    pub fn probe(base: usize) -> Option<Self>
+    {
+        let reg = |offset| Self::reg_at(base, offset);
+
+        if reg(MAGIC_VALUE_OFFSET).read() != MAGIC
+            || reg(DEVICE_ID_OFFSET).read() != DEVICE_ID_BLOCK
+        {
+            return None;
+        }
+
+        let version = reg(VERSION_OFFSET).read();
+        if !matches!(version, 1 | 2)
+        {
+            return None;
+        }
+        let legacy = version == 1;
+
+        // Reset, then walk the status handshake.
+        reg(STATUS_OFFSET).write(0);
+        reg(STATUS_OFFSET).write(status::ACKNOWLEDGE);
+        reg(STATUS_OFFSET).write(status::ACKNOWLEDGE | status::DRIVER);
+
+        reg(DEVICE_FEATURES_SEL_OFFSET).write(0);
+        let read_only = reg(DEVICE_FEATURES_OFFSET).read() & BLK_F_RO != 0;
+
+        // We need no optional features; modern devices insist on VERSION_1.
+        reg(DRIVER_FEATURES_SEL_OFFSET).write(0);
+        reg(DRIVER_FEATURES_OFFSET).write(0);
+        reg(DRIVER_FEATURES_SEL_OFFSET).write(1);
+        reg(DRIVER_FEATURES_OFFSET).write(if legacy { 0 } else { F_VERSION_1 });
+
+        let mut status = status::ACKNOWLEDGE | status::DRIVER;
+        if !legacy
+        {
+            status |= status::FEATURES_OK;
+            reg(STATUS_OFFSET).write(status);
+            if reg(STATUS_OFFSET).read() & status::FEATURES_OK == 0
+            {
+                reg(STATUS_OFFSET).write(status::FAILED);
+                return None;
+            }
+        }
+
+        reg(QUEUE_SEL_OFFSET).write(0);
+        let max = reg(QUEUE_NUM_MAX_OFFSET).read();
+        if max < QUEUE_SIZE as u32
+        {
+            reg(STATUS_OFFSET).write(status::FAILED);
+            return None;
+        }
+
+        let Some(queue) = VirtQueue::new()
+        else
+        {
+            reg(STATUS_OFFSET).write(status::FAILED);
+            return None;
+        };
+        reg(QUEUE_NUM_OFFSET).write(QUEUE_SIZE as u32);
+
+        if legacy
+        {
+            reg(GUEST_PAGE_SIZE_OFFSET).write(PAGE_SIZE as u32);
+            reg(QUEUE_ALIGN_OFFSET).write(PAGE_SIZE as u32);
+            reg(QUEUE_PFN_OFFSET).write((queue.addr(0) / PAGE_SIZE) as u32);
+        }
+        else
+        {
+            let split = |addr: usize| (addr as u64 as u32, (addr as u64 >> 32) as u32);
+            for (low, high, addr) in [
+                (QUEUE_DESC_LOW_OFFSET, QUEUE_DESC_HIGH_OFFSET, queue.addr(0)),
+                (
+                    QUEUE_DRIVER_LOW_OFFSET,
+                    QUEUE_DRIVER_HIGH_OFFSET,
+                    queue.addr(AVAIL_OFFSET),
+                ),
+                (
+                    QUEUE_DEVICE_LOW_OFFSET,
+                    QUEUE_DEVICE_HIGH_OFFSET,
+                    queue.addr(USED_OFFSET),
+                ),
+            ]
+            {
+                let (lo, hi) = split(addr);
+                reg(low).write(lo);
+                reg(high).write(hi);
+            }
+            reg(QUEUE_READY_OFFSET).write(1);
+        }
+
+        reg(STATUS_OFFSET).write(status | status::DRIVER_OK);
+
+        // Config space starts with the capacity in 512-byte sectors (u64).
+        let mut words = [0; 2];
+        reg(CONFIG_OFFSET).read_strided(&mut words, 4);
+        let capacity = words[0] as u64 | (words[1] as u64) << 32;
+
+        Some(Self {
+            base,
+            queue,
+            capacity: capacity as usize * 512 / BLOCK_SIZE,
+            read_only,
+        })
+    }
+
+    #[inline]
+    fn reg_at(base: usize, offset: usize) -> Register<u32>
+    {
+        Register::new((base + offset) as _, AccessStrategy::Direct)
+    }
+
+    #[inline]
+    fn reg(&self, offset: usize) -> Register<u32>
+    {
+        Self::reg_at(self.base, offset)
+    }
+
+    /// Issue a single request and spin until the device completes it.
+    fn transfer(&mut self, kind: u32, n: usize, data: usize) -> Result<(), BlockError>
+    {
+        if n >= self.capacity
+        {
+            return Err(BlockError::OutOfRange);
+        }
+
+        let header = Box::new(RequestHeader {
+            kind,
+            _reserved: 0,
+            sector: (n * BLOCK_SIZE / 512) as u64,
+        });
+        let mut status = Box::new(0xffu8);
+
+        let data_flags = if kind == BLK_T_IN { DESC_F_WRITE } else { 0 };
+        let header_addr = &*header as *const RequestHeader as usize;
+        let status_addr = &mut *status as *mut u8 as usize;
+
+        self.queue
+            .set_descriptor(0, header_addr, size_of::<RequestHeader>(), DESC_F_NEXT, 1);
+        self.queue
+            .set_descriptor(1, data, BLOCK_SIZE, data_flags | DESC_F_NEXT, 2);
+        self.queue
+            .set_descriptor(2, status_addr, 1, DESC_F_WRITE, 0);
+        self.queue.submit(0);
+
+        // Ring memory writes must land before the device is told to look.
+        self.reg(QUEUE_NOTIFY_OFFSET).write_ordered(0);
+
+        while !self.queue.pop_used()
+        {
+            core::hint::spin_loop();
+        }
+
+        let pending = self.reg(INTERRUPT_STATUS_OFFSET).read();
+        self.reg(INTERRUPT_ACK_OFFSET).write(pending);
+
+        match unsafe { (&*status as *const u8).read_volatile() }
+        {
+            BLK_S_OK => Ok(()),
+            _ => Err(BlockError::Io),
+        }
+    }
+}
+
+impl BlockDevice for VirtioBlock
+{
+    #[inline]
+    fn block_count(&self) -> usize
+    {
+        self.capacity
+    }
+
+    fn read_block(&mut self, n: usize, buf: &mut [u8; BLOCK_SIZE]) -> Result<(), BlockError>
+    {
+        self.transfer(BLK_T_IN, n, buf.as_mut_ptr() as usize)
+    }
+
+    fn write_block(&mut self, n: usize, buf: &[u8; BLOCK_SIZE]) -> Result<(), BlockError>
+    {
+        if self.read_only
+        {
+            return Err(BlockError::Io);
+        }
+        self.transfer(BLK_T_OUT, n, buf.as_ptr() as usize)
+    }
+}
+
+/// Bring up the first VirtIO block device described by the device tree.
+pub fn init(dev_tree: &Fdt)
+{
+    let Some(dev) = fdt::virtio::mmio_bases(dev_tree).find_map(VirtioBlock::probe)
+    else
+    {
+        log::trace!("No VirtIO block device found.");
+        return;
+    };
+
+    log::trace!(
+        "VirtIO block device at {:#x}: {} blocks{}.",
+        dev.base,
+        dev.capacity,
+        if dev.read_only { " (read-only)" } else { "" }
+    );
+
+    BLOCK_DEVICE.call_once(|| Mutex::new(dev));
+}
+
+/// Run `f` against the block device, if one was found.
+pub fn with_device<T>(f: impl FnOnce(&mut VirtioBlock) -> T) -> Option<T>
+{
+    let dev = BLOCK_DEVICE.get()?;
+    Some(interrupt::with_disabled(|| f(&mut dev.lock())))
+}
+
+/// Push requests through a queue across the `u16` wrap of its ring indices,
+/// playing the device side by hand. For the selftest.
+#[cfg(feature = "selftest")]
+pub fn check_ring_wrap() -> Result<(), &'static str>
+{
+    let start = u16::MAX - 1;
+    let mut queue = VirtQueue::starting_at(start).ok_or("no memory for a queue")?;
+    let avail_idx = queue.addr(AVAIL_OFFSET + 2) as *const u16;
+    let used_idx = queue.addr(USED_OFFSET + 2) as *mut u16;
+
+    for head in 0..QUEUE_SIZE / 2
+    {
+        let index = start.wrapping_add(head);
+        queue.submit(head);
+
+        let slot = queue.addr(AVAIL_OFFSET + 4 + 2 * (index % QUEUE_SIZE) as usize) as *const u16;
+        if unsafe { slot.read_volatile() } != head
+        {
+            return Err("request published in the wrong ring slot");
+        }
+        if unsafe { avail_idx.read_volatile() } != index.wrapping_add(1)
+        {
+            return Err("available index did not wrap");
+        }
+
+        if queue.pop_used()
+        {
+            return Err("completion seen before the device produced one");
+        }
+        unsafe { used_idx.write_volatile(index.wrapping_add(1)) }
+        if !queue.pop_used() || queue.pop_used()
+        {
+            return Err("completion not consumed exactly once across the wrap");
+        }
+    }
+
+    Ok(())
+}
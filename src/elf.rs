@@ -0,0 +1,318 @@
+//! Minimal ELF loader for programs embedded in the kernel image.
+//!
+//! There is no paging yet, so images are copied to freshly allocated physical
+//! pages and run at kernel privilege. They must be position-independent
+//! (`ET_DYN`) and free of dynamic relocations, since the load address is
+//! wherever the PMM finds room; fixed-address `ET_EXEC` images are refused.
+
+use core::{arch::asm, ptr};
+
+use crate::{
+    arch::PAGE_SIZE,
+    memory::{
+        heap,
+        map::{self, RegionKind},
+    },
+    task::Task,
+};
+
+/// Single ELF image embedded at build time, selected by `RISKY_INITRAMFS`.
+#[cfg(feature = "initramfs")]
+pub static INITRAMFS: &[u8] = include_bytes!(env!("RISKY_INITRAMFS"));
+
+const MAGIC: [u8; 4] = *b"\x7fELF";
+const DATA_LSB: u8 = 1;
+const MACHINE_RISCV: u16 = 243;
+const TYPE_DYN: u16 = 3;
+const PT_LOAD: u32 = 1;
+
+/// Header and program-header field offsets for the native ELF class.
+#[cfg(target_pointer_width = "64")]
+pub mod layout
+{
+    pub const CLASS: u8 = 2;
+    pub const WORD: usize = 8;
+
+    pub const EHDR_SIZE: usize = 64;
+    pub const E_ENTRY: usize = 24;
+    pub const E_PHOFF: usize = 32;
+    pub const E_PHENTSIZE: usize = 54;
+    pub const E_PHNUM: usize = 56;
+
+    pub const PHDR_SIZE: usize = 56;
+    pub const P_FLAGS: usize = 4;
+    pub const P_OFFSET: usize = 8;
+    pub const P_VADDR: usize = 16;
+    pub const P_FILESZ: usize = 32;
+    pub const P_MEMSZ: usize = 40;
+}
+
+#[cfg(target_pointer_width = "32")]
+pub mod layout
+{
+    pub const CLASS: u8 = 1;
+    pub const WORD: usize = 4;
+
+    pub const EHDR_SIZE: usize = 52;
+    pub const E_ENTRY: usize = 24;
+    pub const E_PHOFF: usize = 28;
+    pub const E_PHENTSIZE: usize = 42;
+    pub const E_PHNUM: usize = 44;
+
+    pub const PHDR_SIZE: usize = 32;
+    pub const P_FLAGS: usize = 24;
+    pub const P_OFFSET: usize = 4;
+    pub const P_VADDR: usize = 8;
+    pub const P_FILESZ: usize = 16;
+    pub const P_MEMSZ: usize = 20;
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ElfError
+{
+    Truncated,
+    BadMagic,
+    WrongClass,
+    WrongEndian,
+    WrongMachine,
+    UnsupportedType,
+    NoLoadableSegments,
+    BadSegment,
+    EntryOutOfImage,
+    OutOfMemory,
+}
+
+/// A `PT_LOAD` program header.
+#[derive(Clone, Copy, Debug)]
+pub struct Segment
+{
+    pub vaddr: usize,
+    pub offset: usize,
+    pub file_size: usize,
+    pub mem_size: usize,
+    pub flags: u32,
+}
+
+/// A program copied into memory and ready to run.
+#[derive(Clone, Copy, Debug)]
+pub struct LoadedImage
+{
+    pub base: usize,
+    pub pages: usize,
+    pub entry: usize,
+}
+
+pub struct Elf<'a>
+{
+    data: &'a [u8],
+    entry: usize,
+    phoff: usize,
+    phnum: usize,
+}
+
+impl<'a> Elf<'a>
+{
+    /// Validate the ELF header for this target and locate its program headers.
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError>
+    {
+        use layout::*;
+
+        if data.len() < EHDR_SIZE
+        {
+            return Err(ElfError::Truncated);
+        }
+        if data[0..4] != MAGIC
+        {
+            return Err(ElfError::BadMagic);
+        }
+        if data[4] != CLASS
+        {
+            return Err(ElfError::WrongClass);
+        }
+        if data[5] != DATA_LSB
+        {
+            return Err(ElfError::WrongEndian);
+        }
+        if read_u16(data, 18)? != MACHINE_RISCV
+        {
+            return Err(ElfError::WrongMachine);
+        }
+        // Nothing relocates the image, so only position-independent ones run
+        // correctly at whatever base they get.
+        if read_u16(data, 16)? != TYPE_DYN
+        {
+            return Err(ElfError::UnsupportedType);
+        }
+        if read_u16(data, E_PHENTSIZE)? as usize != PHDR_SIZE
+        {
+            return Err(ElfError::BadSegment);
+        }
+
+        let elf = Self {
+            data,
+            entry: read_word(data, E_ENTRY)?,
+            phoff: read_word(data, E_PHOFF)?,
+            phnum: read_u16(data, E_PHNUM)? as usize,
+        };
+
+        let table_end = elf
+            .phnum
+            .checked_mul(PHDR_SIZE)
+            .and_then(|len| len.checked_add(elf.phoff))
+            .ok_or(ElfError::Truncated)?;
+        if table_end > data.len()
+        {
+            return Err(ElfError::Truncated);
+        }
+
+        Ok(elf)
+    }
+
+    #[inline]
+    pub fn entry(&self) -> usize
+    {
+        self.entry
+    }
+
+    /// Iterate the loadable segments, skipping malformed headers.
+    pub fn segments(&self) -> impl Iterator<Item = Segment> + '_
+    {
+        use layout::*;
+
+        (0..self.phnum).filter_map(|i| {
+            let ph = self.phoff + i * PHDR_SIZE;
+            if read_u32(self.data, ph).ok()? != PT_LOAD
+            {
+                return None;
+            }
+
+            Some(Segment {
+                vaddr: read_word(self.data, ph + P_VADDR).ok()?,
+                offset: read_word(self.data, ph + P_OFFSET).ok()?,
+                file_size: read_word(self.data, ph + P_FILESZ).ok()?,
+                mem_size: read_word(self.data, ph + P_MEMSZ).ok()?,
+                flags: read_u32(self.data, ph + P_FLAGS).ok()?,
+            })
+        })
+    }
+
+    /// Lowest and highest address the loadable segments cover, checking
+    /// that each lies within the file and that no two overlap in memory.
+    pub fn extent(&self) -> Result<(usize, usize), ElfError>
+    {
+        let mem_range = |seg: &Segment| seg.vaddr..seg.vaddr.saturating_add(seg.mem_size);
+
+        self.segments()
+            .enumerate()
+            .try_fold((usize::MAX, 0), |(low, high), (i, seg)| {
+                let file_end = seg.offset.checked_add(seg.file_size);
+                let mem_end = seg.vaddr.checked_add(seg.mem_size);
+                let overlaps = self.segments().take(i).any(|other| {
+                    let (a, b) = (mem_range(&seg), mem_range(&other));
+                    a.start < b.end && b.start < a.end
+                });
+
+                match (file_end, mem_end)
+                {
+                    (Some(file_end), Some(mem_end))
+                        if seg.file_size <= seg.mem_size
+                            && file_end <= self.data.len()
+                            && !overlaps =>
+                    {
+                        Ok((low.min(seg.vaddr), high.max(mem_end)))
+                    }
+                    _ => Err(ElfError::BadSegment),
+                }
+            })
+    }
+
+    /// Copy every loadable segment into newly allocated pages, preserving
+    /// their relative layout, and zero the `.bss`-style tails.
+    pub fn load(&self) -> Result<LoadedImage, ElfError>
+    {
+        let (low, high) = self.extent()?;
+
+        if low >= high
+        {
+            return Err(ElfError::NoLoadableSegments);
+        }
+        if !(low..high).contains(&self.entry)
+        {
+            return Err(ElfError::EntryOutOfImage);
+        }
+
+        let low = low / PAGE_SIZE * PAGE_SIZE;
+        let pages = (high - low).div_ceil(PAGE_SIZE);
+
+        let base = heap::pmm().lock().alloc_pages(pages);
+        if base.is_null()
+        {
+            return Err(ElfError::OutOfMemory);
+        }
+
+        unsafe { ptr::write_bytes(base, 0, pages * PAGE_SIZE) }
+
+        for seg in self.segments()
+        {
+            let src = &self.data[seg.offset..seg.offset + seg.file_size];
+            unsafe { ptr::copy_nonoverlapping(src.as_ptr(), base.add(seg.vaddr - low), src.len()) }
+        }
+
+        Ok(LoadedImage {
+            base: base as usize,
+            pages,
+            entry: base as usize + (self.entry - low),
+        })
+    }
+}
+
+/// Load `data` and spawn a task that jumps to its entry point.
+///
+/// The image's pages are never reclaimed; it is expected to live as long as
+/// the kernel.
+pub fn spawn(data: &[u8]) -> Result<LoadedImage, ElfError>
+{
+    let image = Elf::parse(data)?.load()?;
+    map::record(
+        RegionKind::Program,
+        image.base,
+        image.base + image.pages * PAGE_SIZE,
+    );
+
+    Task::spawn(move || {
+        // The task may land on any hart; make the freshly written code visible
+        // to this hart's instruction fetch before jumping into it.
+        unsafe { asm!("fence.i") }
+
+        let entry = unsafe { core::mem::transmute::<usize, extern "C" fn()>(image.entry) };
+        entry();
+    });
+
+    Ok(image)
+}
+
+#[inline]
+fn bytes<const N: usize>(data: &[u8], offset: usize) -> Result<[u8; N], ElfError>
+{
+    data.get(offset..offset + N)
+        .and_then(|b| b.try_into().ok())
+        .ok_or(ElfError::Truncated)
+}
+
+#[inline]
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, ElfError>
+{
+    bytes(data, offset).map(u16::from_le_bytes)
+}
+
+#[inline]
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, ElfError>
+{
+    bytes(data, offset).map(u32::from_le_bytes)
+}
+
+#[inline]
+fn read_word(data: &[u8], offset: usize) -> Result<usize, ElfError>
+{
+    bytes::<{ layout::WORD }>(data, offset).map(usize::from_le_bytes)
+}
@@ -4,9 +4,40 @@
 
 use fdt::{Fdt, FdtError};
 
-#[inline]
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_ALIGN: usize = 8;
+const FDT_HEADER_SIZE: usize = 40;
+
+/// Parse the device tree at `fdt_ptr`, checking the pointer, magic and
+/// header before anything else is read through it.
+///
+/// A header whose blocks run past `totalsize` is rejected here, since the
+/// parser would only find out by indexing out of bounds later.
 pub fn fdt<'a>(fdt_ptr: *const u8) -> Result<Fdt<'a>, FdtError>
 {
+    if fdt_ptr.is_null() || !(fdt_ptr as usize).is_multiple_of(FDT_ALIGN)
+    {
+        return Err(FdtError::BadPtr);
+    }
+
+    let field = |index: usize| {
+        let word = unsafe { (fdt_ptr as *const u32).add(index).read_volatile() };
+        u32::from_be(word) as usize
+    };
+    if field(0) as u32 != FDT_MAGIC
+    {
+        return Err(FdtError::BadMagic);
+    }
+
+    let total = field(1);
+    let fits =
+        |offset: usize, size: usize| offset.checked_add(size).is_some_and(|end| end <= total);
+    // Structure block, then strings block.
+    if total < FDT_HEADER_SIZE || !fits(field(2), field(9)) || !fits(field(3), field(8))
+    {
+        return Err(FdtError::BufferTooSmall);
+    }
+
     unsafe { Fdt::from_ptr(fdt_ptr) }
 }
 
@@ -14,27 +45,39 @@ pub mod harts
 {
     use alloc::{boxed::Box, vec::Vec};
 
-    use spin::Once;
-
     use super::*;
+    use crate::sync::OnceLock;
 
-    static HART_ID_MAP: Once<Box<[usize]>> = Once::new();
+    static HART_ID_MAP: OnceLock<Box<[usize]>> = OnceLock::new();
 
+    /// Map a physical hart ID to its logical ID, if the hart is registered.
     #[inline]
-    pub fn to_logical(physical_hart_id: usize) -> usize
+    pub fn try_to_logical(physical_hart_id: usize) -> Option<usize>
     {
         HART_ID_MAP
             .wait()
             .iter()
             .copied()
             .position(|p| p == physical_hart_id)
-            .expect("Booting on an unregistered Hart!")
+    }
+
+    /// Map a logical hart ID to its physical ID, if it is in range.
+    #[inline]
+    pub fn try_to_physical(logical_hart_id: usize) -> Option<usize>
+    {
+        HART_ID_MAP.wait().get(logical_hart_id).copied()
+    }
+
+    #[inline]
+    pub fn to_logical(physical_hart_id: usize) -> usize
+    {
+        try_to_logical(physical_hart_id).expect("Booting on an unregistered Hart!")
     }
 
     #[inline]
     pub fn to_physical(logical_hart_id: usize) -> usize
     {
-        HART_ID_MAP.wait()[logical_hart_id]
+        try_to_physical(logical_hart_id).expect("Logical Hart ID out of range!")
     }
 
     pub fn parse_hart_count(dev_tree: &Fdt, boot_hart_id: usize) -> Result<usize, FdtError>
@@ -55,7 +98,8 @@ pub mod harts
         }
 
         let count = physical_ids.len();
-        HART_ID_MAP.call_once(|| physical_ids.into_boxed_slice());
+        // Only the boot hart parses the tree, and only once.
+        let _ = HART_ID_MAP.set(physical_ids.into_boxed_slice());
 
         Ok(count)
     }
@@ -68,26 +112,37 @@ pub mod uart
     use super::*;
     use crate::mmio::IoWidth;
 
-    fn find_node<'a>(dev_tree: &'a Fdt<'a>) -> Option<FdtNode<'a, 'a>>
+    const PRIORITY_LIST: [&str; 3] = [
+        "snps,dw-apb-uart", // Modern Desktop/SoC UART
+        "ns16550a",         // Classic Generic UART
+        "ns16550",          // Legacy Generic UART
+    ];
+
+    /// Where a 16550-compatible UART sits and how to reach its registers.
+    #[derive(Clone, Copy)]
+    pub struct UartInfo
     {
-        let priority_list = [
-            "snps,dw-apb-uart", // Modern Desktop/SoC UART
-            "ns16550a",         // Classic Generic UART
-            "ns16550",          // Legacy Generic UART
-        ];
+        pub base: usize,
+        pub shift: u8,
+        pub width: IoWidth,
+        /// First entry of `interrupts`, if the node has one.
+        pub irq: Option<u32>,
+    }
 
+    fn find_node<'a>(dev_tree: &'a Fdt<'a>) -> Option<FdtNode<'a, 'a>>
+    {
         dev_tree
             .chosen()
             .stdout()
-            // If no stdout, check `priority_list` for compatible devices
+            // If no stdout, check `PRIORITY_LIST` for compatible devices
             .or_else(|| {
-                priority_list
+                PRIORITY_LIST
                     .iter()
                     .find_map(|&n| dev_tree.find_compatible(&[n]))
             })
     }
 
-    fn parse_node(node: FdtNode) -> Option<(usize, u8, IoWidth)>
+    fn parse_node(node: FdtNode) -> Option<UartInfo>
     {
         let base = node.reg()?.next()?.starting_address as usize;
 
@@ -102,14 +157,65 @@ pub mod uart
             .map(|w| if w == 4 { IoWidth::U32 } else { IoWidth::U8 })
             .unwrap_or(IoWidth::U8);
 
-        Some((base, shift, io_width))
+        let irq = node
+            .property("interrupts")
+            .and_then(|p| Some(u32::from_be_bytes(p.value.get(0..4)?.try_into().ok()?)));
+
+        Some(UartInfo {
+            base,
+            shift,
+            width: io_width,
+            irq,
+        })
     }
 
+    /// The console UART: `/chosen/stdout-path`, else the first known one.
     #[inline]
-    pub fn get_info(dev_tree: &Fdt) -> Option<(usize, u8, IoWidth)>
+    pub fn get_info(dev_tree: &Fdt) -> Option<UartInfo>
     {
         find_node(dev_tree).and_then(parse_node)
     }
+
+    /// Every UART the driver can run, console included, in tree order.
+    pub fn all<'a>(dev_tree: &'a Fdt<'a>) -> impl Iterator<Item = UartInfo> + 'a
+    {
+        dev_tree
+            .all_nodes()
+            .filter(|n| {
+                n.compatible()
+                    .is_some_and(|c| c.all().any(|s| PRIORITY_LIST.contains(&s)))
+            })
+            .filter_map(parse_node)
+    }
+}
+
+pub mod aclint
+{
+    use super::*;
+
+    /// Base of the ACLINT SSWI device, which lets S-mode raise IPIs itself.
+    pub fn sswi_base(dev_tree: &Fdt) -> Option<usize>
+    {
+        let node = dev_tree.find_compatible(&["riscv,aclint-sswi"])?;
+        Some(node.reg()?.next()?.starting_address as usize)
+    }
+}
+
+pub mod virtio
+{
+    use super::*;
+
+    /// Base addresses of every `virtio,mmio` transport, populated or not.
+    pub fn mmio_bases<'a>(dev_tree: &'a Fdt<'a>) -> impl Iterator<Item = usize> + 'a
+    {
+        dev_tree
+            .all_nodes()
+            .filter(|n| {
+                n.compatible()
+                    .is_some_and(|c| c.all().any(|s| s == "virtio,mmio"))
+            })
+            .filter_map(|n| Some(n.reg()?.next()?.starting_address as usize))
+    }
 }
 
 pub mod mem
@@ -138,6 +244,9 @@ pub mod mem
         }))
     }
 
+    /// Memory nothing may allocate from: `/memreserve/` entries, every
+    /// static `/reserved-memory` region (`no-map` or not), and any other
+    /// `no-map` node. A region can be listed more than once.
     pub fn reserved_regions<'a>(dev_tree: &'a Fdt<'a>)
     -> impl Iterator<Item = (usize, usize)> + 'a
     {
@@ -146,12 +255,21 @@ pub mod mem
             (size != 0).then_some((r.address() as usize, size))
         });
 
+        // Regions given only a `size` are placed by whoever uses them, so
+        // there's nothing to keep out of the heap for those.
+        let reserved_memory = reg_regions(
+            dev_tree
+                .find_node("/reserved-memory")
+                .into_iter()
+                .flat_map(|n| n.children()),
+        );
+
         let no_map = reg_regions(
             dev_tree
                 .all_nodes()
                 .filter(|n| n.property("no-map").is_some()),
         );
-        fdt_reserved.chain(no_map)
+        fdt_reserved.chain(reserved_memory).chain(no_map)
     }
 }
 
@@ -159,10 +277,126 @@ pub mod timer
 {
     use fdt::Fdt;
 
+    /// Frequency of the `time` CSR, from `/cpus` or, failing that, the
+    /// first CPU node that carries it.
     pub fn timebase_hz(dev_tree: &Fdt) -> Option<u64>
     {
-        let cpus_node = dev_tree.find_node("/cpus").unwrap(); // This node must exist
-        let freq = cpus_node.property("timebase-frequency")?.as_usize()?;
+        const PROPERTY: &str = "timebase-frequency";
+
+        let from_cpus = dev_tree
+            .find_node("/cpus")
+            .and_then(|n| n.property(PROPERTY)?.as_usize());
+        let freq = from_cpus.or_else(|| {
+            dev_tree
+                .cpus()
+                .find_map(|cpu| cpu.property(PROPERTY)?.as_usize())
+        })?;
+
         Some(freq as u64)
     }
 }
+
+pub mod dump
+{
+    use fdt::node::{FdtNode, NodeProperty};
+
+    use super::*;
+
+    const INDENT: usize = 4;
+    // Longer values are cut short, with a trailing `...`.
+    const MAX_CELLS: usize = 16;
+    const MAX_BYTES: usize = 32;
+
+    /// Print the whole tree to the console in `dtc`-like source form.
+    pub fn dump(dev_tree: &Fdt)
+    {
+        match dev_tree.find_node("/")
+        {
+            Some(root) => dump_node(root, 0),
+            None => println!("/* no root node */"),
+        }
+    }
+
+    fn dump_node(node: FdtNode, depth: usize)
+    {
+        let indent = depth * INDENT;
+        let name = if depth == 0 { "/" } else { node.name };
+        println!("{:indent$}{} {{", "", name);
+
+        for prop in node.properties()
+        {
+            print!("{:width$}", "", width = indent + INDENT);
+            dump_property(&prop);
+        }
+
+        for child in node.children()
+        {
+            dump_node(child, depth + 1);
+        }
+
+        println!("{:indent$}}};", "");
+    }
+
+    fn dump_property(prop: &NodeProperty)
+    {
+        let value = prop.value;
+        if value.is_empty()
+        {
+            println!("{};", prop.name);
+        }
+        else if is_string_list(value)
+        {
+            print!("{} = ", prop.name);
+            // The list ends in a NUL, so the last split is always empty.
+            let strings = value[..value.len() - 1].split(|&b| b == 0);
+            for (i, s) in strings.enumerate()
+            {
+                let sep = if i == 0 { "" } else { ", " };
+                print!("{sep}\"{}\"", core::str::from_utf8(s).unwrap_or("?"));
+            }
+            println!(";");
+        }
+        else if value.len().is_multiple_of(4)
+        {
+            print!("{} = <", prop.name);
+            let (cells, _) = value.as_chunks::<4>();
+            for (i, &cell) in cells.iter().take(MAX_CELLS).enumerate()
+            {
+                let sep = if i == 0 { "" } else { " " };
+                print!("{sep}{:#x}", u32::from_be_bytes(cell));
+            }
+            println!("{}>;", ellipsis(cells.len() > MAX_CELLS));
+        }
+        else
+        {
+            print!("{} = [", prop.name);
+            for (i, byte) in value.iter().take(MAX_BYTES).enumerate()
+            {
+                let sep = if i == 0 { "" } else { " " };
+                print!("{sep}{byte:02x}");
+            }
+            println!("{}];", ellipsis(value.len() > MAX_BYTES));
+        }
+    }
+
+    /// Whether `value` looks like one or more printable NUL-terminated strings.
+    fn is_string_list(value: &[u8]) -> bool
+    {
+        let Some((&0, body)) = value.split_last()
+        else
+        {
+            return false;
+        };
+
+        let printable = |&b: &u8| b == 0 || b == b' ' || b.is_ascii_graphic();
+        !body.is_empty()
+            && body.split(|&b| b == 0).all(|s| !s.is_empty())
+            && body.iter().all(printable)
+    }
+
+    #[inline]
+    fn ellipsis(truncated: bool) -> &'static str
+    {
+        if truncated { " ..." } else { "" }
+    }
+}
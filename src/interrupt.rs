@@ -2,18 +2,31 @@
 //!
 //! This module owns trap setup and high-level interrupt/exception handling.
 
-use core::arch::{asm, global_asm};
+pub mod deferred;
+pub mod fault;
+pub mod stats;
 
+use core::{
+    arch::{asm, global_asm},
+    sync::atomic::Ordering,
+};
+
+pub use self::stats::stats;
 use crate::{
     arch::{
         Cpu,
         cause::{self, exceptions, interrupts},
     },
-    platform::timer,
-    task::TrapContext,
+    crash,
+    platform::{plic, timer},
+    sync,
+    task::{self, TaskKind, TaskState, TrapContext},
+    trace, watchdog,
 };
 
 pub const SIE_FLAG: usize = 1 << 1; // Supervisor Interrupt Enable for `sstatus`
+pub const SPIE_FLAG: usize = 1 << 5; // `SIE` before the trap, restored by `sret`
+pub const SPP_FLAG: usize = 1 << 8; // Privilege before the trap, set for S-mode
 
 pub struct LocalIrqGuard
 {
@@ -38,14 +51,31 @@ global_asm!(include_str!("interrupt/rv64.S"));
 global_asm!(include_str!("interrupt/rv32.S"));
 
 /// Trap frame layout shared with `interrupt/rv*.S`.
+///
+/// Every trap saves every register, timer ticks included. There is no
+/// separate `switch_context`: `Scheduler::schedule` switches tasks by copying
+/// this frame out to the old task and the new task's context over it, so a
+/// lighter entry that skipped the callee-saved registers would lose them for
+/// whichever task gets switched out.
 #[repr(C)]
 struct TrapFrame
 {
     context: TrapContext,
     scause: usize,
-    _reserved: usize,
+    stval: usize,
 }
 
+// `interrupt/rv*.S` hard-codes these word offsets.
+const _: () = {
+    use core::mem::{offset_of, size_of};
+
+    const WORD: usize = size_of::<usize>();
+    assert!(offset_of!(TrapContext, pc) == 29 * WORD);
+    assert!(offset_of!(TrapContext, sstatus) == 30 * WORD);
+    assert!(offset_of!(TrapFrame, scause) == 32 * WORD);
+    assert!(offset_of!(TrapFrame, stval) == 33 * WORD);
+};
+
 // Low-level trap entry point referenced by `stvec`.
 unsafe extern "C" {
     fn _trap();
@@ -55,14 +85,18 @@ unsafe extern "C" {
 #[unsafe(no_mangle)]
 extern "C" fn trap_handler(frame: &mut TrapFrame)
 {
-    // Check top bit. If it's 1, we have an interrupt. Otherwise, it's an exception.
-    const CAUSE_INTERRUPT_FLAG: usize = 1 << (core::mem::size_of::<usize>() * 8 - 1);
-
     let scause = frame.scause;
 
-    let is_interrupt = scause & CAUSE_INTERRUPT_FLAG != 0;
+    // Check top bit. If it's 1, we have an interrupt. Otherwise, it's an exception.
+    let is_interrupt = scause & cause::INTERRUPT_FLAG != 0;
     // Mask out the interrupt bit to get the Exception Code
-    let code = scause & !CAUSE_INTERRUPT_FLAG;
+    let code = scause & !cause::INTERRUPT_FLAG;
+
+    let cpu = Cpu::get();
+    cpu.check_trap_stack();
+    cpu.trap_depth.fetch_add(1, Ordering::Relaxed);
+    cpu.trap_counters.record(is_interrupt, code);
+    trace::record(trace::EventKind::Trap, [scause, frame.context.pc]);
 
     if is_interrupt
     {
@@ -72,6 +106,7 @@ extern "C" fn trap_handler(frame: &mut TrapFrame)
         {
             SUPERVISOR_SOFTWARE_INTERRUPT => handle_software_interrupt(frame),
             SUPERVISOR_TIMER_INTERRUPT => handle_timer_interrupt(frame),
+            SUPERVISOR_EXTERNAL_INTERRUPT => plic::dispatch(cpu),
             _ =>
             {}
         }
@@ -80,28 +115,89 @@ extern "C" fn trap_handler(frame: &mut TrapFrame)
     {
         handle_exception(code, frame)
     }
+
+    cpu.trap_depth.fetch_sub(1, Ordering::Relaxed);
+    cpu.check_trap_stack();
 }
 
 fn handle_software_interrupt(frame: &mut TrapFrame)
 {
     timer::ipi::clear();
 
-    let mut scheduler = Cpu::get().scheduler.lock();
-    scheduler.schedule(&mut frame.context)
+    if crash::is_stopping()
+    {
+        crash::report_and_halt(&frame.context);
+    }
+
+    preempt(frame, false)
 }
 
 fn handle_timer_interrupt(frame: &mut TrapFrame)
 {
     timer::schedule_next();
+    let cpu = Cpu::get();
+    watchdog::on_tick(cpu, frame.context.pc);
+    task::balance::on_tick(cpu);
+    sync::futex_tick(cpu);
+    preempt(frame, true)
+}
+
+/// Switch tasks for a tick or IPI, unless the running task disabled
+/// preemption; then only note that a switch is owed.
+fn preempt(frame: &mut TrapFrame, tick: bool)
+{
+    let cpu = Cpu::get();
+    let mut scheduler = cpu.scheduler.lock();
+
+    if tick
+    {
+        let idle = scheduler.task().kind == TaskKind::Idle;
+        cpu.sched_counters.record_tick(idle);
+        scheduler.age_waiting();
+
+        if scheduler
+            .task_mut()
+            .charge_tick(cpu.preemptible() && sync::held_locks() == 0)
+            .is_some()
+        {
+            cpu.sched_counters.record_budget_overrun();
+        }
+    }
+
+    // A task that is exiting or parking must leave regardless; `schedule`
+    // sets its section aside until it resumes.
+    if !cpu.preemptible() && scheduler.task().state == TaskState::Running
+    {
+        cpu.resched_pending.store(true, Ordering::Relaxed);
+        return;
+    }
 
-    let mut scheduler = Cpu::get().scheduler.lock();
-    scheduler.schedule(&mut frame.context)
+    if scheduler.schedule(&mut frame.context) && tick
+    {
+        cpu.sched_counters.record_preemption();
+    }
 }
 
 fn handle_exception(code: usize, frame: &mut TrapFrame)
 {
     use exceptions::*;
 
+    if !matches!(code, USER_ECALL | SUPERVISOR_ECALL | MACHINE_ECALL)
+    {
+        match fault::dispatch(code, frame.stval, frame.context.pc)
+        {
+            // Leave `pc` untouched so the faulting instruction runs again.
+            fault::FaultAction::Retry => return,
+            fault::FaultAction::Skip =>
+            {
+                frame.context.pc += fault::instruction_len(frame.context.pc);
+                return;
+            }
+            fault::FaultAction::Fatal =>
+            {}
+        }
+    }
+
     match code
     {
         USER_ECALL | SUPERVISOR_ECALL | MACHINE_ECALL =>
@@ -111,9 +207,9 @@ fn handle_exception(code: usize, frame: &mut TrapFrame)
             // We move the EPC forward by 4 so that IF this task is ever
             // rescheduled (not applicable for Dead tasks, but vital for Syscalls),
             // it resumes AFTER the ecall instruction.
-            frame.context.pc += 4;
+            frame.context.pc += 4; // `ecall` has no compressed form
 
-            scheduler.schedule(&mut frame.context)
+            scheduler.schedule(&mut frame.context);
         }
         INSTRUCTION_ACCESS_FAULT => panic!(
             "Instruction Access Fault at {:#x}! (Likely task returned or bad RA)",
@@ -123,8 +219,11 @@ fn handle_exception(code: usize, frame: &mut TrapFrame)
         LOAD_ACCESS_FAULT => panic!("Load Access Fault at {:#x}!", frame.context.pc),
         STORE_ACCESS_FAULT => panic!("Store Access Fault at {:#x}!", frame.context.pc),
         _ => panic!(
-            "Unhandled exception: code {}, epc {:#x}",
-            code, frame.context.pc
+            "Unhandled exception: {} (code {}), epc {:#x}, stval {:#x}",
+            cause::describe_exception(code),
+            code,
+            frame.context.pc,
+            frame.stval
         ),
     }
 }
@@ -143,6 +242,16 @@ pub fn is_enabled() -> bool
     unsafe { csr_read!("sstatus") & SIE_FLAG != 0 }
 }
 
+/// Whether the timer and software interrupts that wake an idle hart are
+/// enabled in `sie`, regardless of the global `sstatus.SIE` mask.
+#[inline]
+pub fn wake_sources_enabled() -> bool
+{
+    let wake = 1 << interrupts::SUPERVISOR_TIMER_INTERRUPT
+        | 1 << interrupts::SUPERVISOR_SOFTWARE_INTERRUPT;
+    unsafe { csr_read!("sie") & wake == wake }
+}
+
 /// Disable local supervisor interrupts on the current hart.
 #[inline]
 pub fn disable()
@@ -168,8 +277,14 @@ pub fn with_disabled<T>(f: impl FnOnce() -> T) -> T
 }
 
 /// Configure trap vector and enabled interrupt sources for this hart.
-pub fn init(trap_stack_ptr: usize)
+///
+/// The top 16 bytes of `cpu`'s trap stack hold a pointer back to `cpu`, which
+/// `_trap` loads into `tp`; frames are pushed below it.
+pub fn init(cpu: &'static Cpu)
 {
+    let trap_stack_ptr = cpu.trap_stack_top - 16;
+    unsafe { (trap_stack_ptr as *mut usize).write(cpu as *const Cpu as usize) }
+
     // stvec setup: direct mode.
     unsafe {
         asm!(
@@ -182,12 +297,14 @@ pub fn init(trap_stack_ptr: usize)
     // `sscratch` holds the trap stack pointer for the assembly prologue.
     unsafe { csr_write!("sscratch", trap_stack_ptr) }
 
-    // Enable supervisor timer and software interrupts.
+    // Enable supervisor timer, software and external interrupts. The PLIC
+    // only forwards sources routed to this hart, see `plic::route`.
     unsafe {
         csr_set!(
             "sie",
             1 << cause::interrupts::SUPERVISOR_TIMER_INTERRUPT
                 | 1 << cause::interrupts::SUPERVISOR_SOFTWARE_INTERRUPT
+                | 1 << cause::interrupts::SUPERVISOR_EXTERNAL_INTERRUPT
         )
     }
 }
@@ -0,0 +1,65 @@
+//! Per-hart deferred work, run later with interrupts enabled.
+//!
+//! Interrupt handlers should only acknowledge the device and queue whatever
+//! else needs doing. Entries are plain function pointers with one argument so
+//! that deferring never allocates: the heap lock does not mask interrupts, so
+//! allocating from a handler could deadlock against the code it interrupted.
+//!
+//! Queuing work wakes the hart's kernel worker, which drains the queue ahead
+//! of other waiting tasks.
+
+use super::with_disabled;
+use crate::{arch::Cpu, collections::RingBuffer};
+
+/// Deferred callback and the argument it is invoked with.
+pub type Work = fn(usize);
+
+/// Maximum number of pending entries per hart.
+pub const CAPACITY: usize = 32;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DeferError
+{
+    /// The queue is full; the work was not queued.
+    Full,
+}
+
+/// Fixed-capacity FIFO of pending work.
+pub type DeferredQueue = RingBuffer<(Work, usize), CAPACITY>;
+
+/// Queue `work(arg)` to run later on the current hart.
+///
+/// Safe to call from interrupt handlers.
+pub fn defer(work: Work, arg: usize) -> Result<(), DeferError>
+{
+    with_disabled(|| {
+        let cpu = Cpu::get();
+        cpu.deferred
+            .lock()
+            .push((work, arg))
+            .map_err(|_| DeferError::Full)?;
+
+        cpu.scheduler.lock().signal_worker();
+        Ok(())
+    })
+}
+
+/// Whether the current hart has deferred work waiting.
+#[inline]
+pub fn has_pending() -> bool
+{
+    with_disabled(|| !Cpu::get().deferred.lock().is_empty())
+}
+
+/// Run all of the current hart's deferred work, including anything queued
+/// while draining.
+///
+/// Each entry is taken with interrupts disabled and then run with the
+/// caller's interrupt state, without the queue locked.
+pub fn run_pending()
+{
+    while let Some((work, arg)) = with_disabled(|| Cpu::get().deferred.lock().pop())
+    {
+        work(arg);
+    }
+}
@@ -0,0 +1,75 @@
+//! Hooks for recovering from synchronous faults.
+//!
+//! By default every fault is fatal. Subsystems such as demand paging can
+//! register a handler for an exception code and resolve the fault instead.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// What the trap handler should do after a fault handler ran.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FaultAction
+{
+    /// The cause was fixed; re-execute the faulting instruction.
+    Retry,
+    /// The instruction was handled (e.g. emulated); resume after it.
+    Skip,
+    /// The fault cannot be resolved; fall through to the kernel panic.
+    Fatal,
+}
+
+/// Fault handler, called with the faulting address (`stval`) and `sepc`.
+pub type FaultHandler = fn(addr: usize, epc: usize) -> FaultAction;
+
+/// Number of standard exception codes a handler can be registered for.
+pub const MAX_CODE: usize = 16;
+
+// Handlers are stored as raw function pointers so the trap path can read them
+// without taking a lock. Zero means "no handler".
+static HANDLERS: [AtomicUsize; MAX_CODE] = [const { AtomicUsize::new(0) }; _];
+
+/// Register `handler` for exception `code`, returning the one it replaced.
+///
+/// # Panics
+///
+/// Panics if `code` is not below `MAX_CODE`.
+pub fn register_handler(code: usize, handler: FaultHandler) -> Option<FaultHandler>
+{
+    assert!(code < MAX_CODE, "Exception code {code} out of range");
+    decode(HANDLERS[code].swap(handler as usize, Ordering::AcqRel))
+}
+
+/// Remove the handler for exception `code`, restoring the fatal default.
+pub fn unregister_handler(code: usize) -> Option<FaultHandler>
+{
+    HANDLERS
+        .get(code)
+        .and_then(|slot| decode(slot.swap(0, Ordering::AcqRel)))
+}
+
+/// Run the handler registered for `code`, if any.
+pub(super) fn dispatch(code: usize, addr: usize, epc: usize) -> FaultAction
+{
+    HANDLERS
+        .get(code)
+        .and_then(|slot| decode(slot.load(Ordering::Acquire)))
+        .map_or(FaultAction::Fatal, |handler| handler(addr, epc))
+}
+
+/// Length in bytes of the instruction at `epc`: 2 if compressed, else 4.
+///
+/// Only the low two bits of the first 16-bit parcel are inspected, which is
+/// all the base ISA needs. `epc` must point at mapped, executable memory, as
+/// it does for any instruction that just trapped.
+#[inline]
+pub fn instruction_len(epc: usize) -> usize
+{
+    // Parcels are 2-byte aligned, so this is an aligned load even with C.
+    let parcel = unsafe { (epc as *const u16).read_volatile() };
+    if parcel & 0b11 == 0b11 { 4 } else { 2 }
+}
+
+#[inline]
+fn decode(raw: usize) -> Option<FaultHandler>
+{
+    (raw != 0).then(|| unsafe { core::mem::transmute::<usize, FaultHandler>(raw) })
+}
@@ -0,0 +1,118 @@
+//! Per-hart trap counters, aggregated on demand.
+//!
+//! Each hart only increments its own counters, so updates are uncontended
+//! and `Relaxed` is enough; totals are a best-effort snapshot.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::arch::{CPU_VEC, cause};
+
+/// Number of interrupt and exception codes tracked; larger codes share the
+/// last slot.
+pub const MAX_CODE: usize = 16;
+
+/// Trap counters owned by a single hart.
+pub struct TrapCounters
+{
+    interrupts: [AtomicUsize; MAX_CODE],
+    exceptions: [AtomicUsize; MAX_CODE],
+}
+
+impl TrapCounters
+{
+    #[inline]
+    pub const fn new() -> Self
+    {
+        Self {
+            interrupts: [const { AtomicUsize::new(0) }; _],
+            exceptions: [const { AtomicUsize::new(0) }; _],
+        }
+    }
+
+    #[inline]
+    pub(super) fn record(&self, is_interrupt: bool, code: usize)
+    {
+        let table = if is_interrupt
+        {
+            &self.interrupts
+        }
+        else
+        {
+            &self.exceptions
+        };
+        table[code.min(MAX_CODE - 1)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Snapshot of this hart's counters.
+    pub fn snapshot(&self) -> TrapStats
+    {
+        let load =
+            |table: &[AtomicUsize; MAX_CODE]| table.each_ref().map(|c| c.load(Ordering::Relaxed));
+
+        TrapStats {
+            interrupts: load(&self.interrupts),
+            exceptions: load(&self.exceptions),
+        }
+    }
+}
+
+impl Default for TrapCounters
+{
+    #[inline]
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+/// Trap counts indexed by cause code.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct TrapStats
+{
+    pub interrupts: [usize; MAX_CODE],
+    pub exceptions: [usize; MAX_CODE],
+}
+
+impl TrapStats
+{
+    /// `(cause name, count)` for every cause that fired at least once.
+    pub fn nonzero(&self) -> impl Iterator<Item = (&'static str, usize)> + '_
+    {
+        let interrupts = self
+            .interrupts
+            .iter()
+            .enumerate()
+            .map(|(code, &n)| (cause::describe_interrupt(code), n));
+        let exceptions = self
+            .exceptions
+            .iter()
+            .enumerate()
+            .map(|(code, &n)| (cause::describe_exception(code), n));
+
+        interrupts.chain(exceptions).filter(|&(_, n)| n != 0)
+    }
+
+    fn merge(mut self, other: Self) -> Self
+    {
+        for (a, b) in self.interrupts.iter_mut().zip(other.interrupts)
+        {
+            *a += b;
+        }
+        for (a, b) in self.exceptions.iter_mut().zip(other.exceptions)
+        {
+            *a += b;
+        }
+        self
+    }
+}
+
+/// Trap counts summed over every hart.
+pub fn stats() -> TrapStats
+{
+    CPU_VEC
+        .get()
+        .into_iter()
+        .flat_map(|cpus| cpus.iter())
+        .map(|cpu| cpu.trap_counters.snapshot())
+        .fold(TrapStats::default(), TrapStats::merge)
+}
@@ -13,17 +13,26 @@ pub mod drivers;
 
 pub mod arch;
 pub mod boot;
+pub mod collections;
+pub mod console;
+pub mod crash;
 pub mod demo;
+pub mod elf;
 pub mod fdt;
 pub mod interrupt;
 pub mod logger;
 pub mod memory;
 pub mod mmio;
 pub mod platform;
+#[cfg(feature = "selftest")]
+pub mod selftest;
 pub mod soc;
+pub mod sync;
 pub mod task;
+pub mod trace;
+pub mod watchdog;
 
 pub use boot::{BOOT_STATUS, BootStage};
 
 pub const STACK_SIZE: usize = 1024 * 32; // 32KB
-const TRAP_STACK_SIZE: usize = 1024 * 8; // 8KB
+pub const TRAP_STACK_SIZE: usize = 1024 * 8; // 8KB, must be a multiple of 16
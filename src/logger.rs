@@ -2,10 +2,15 @@
 //!
 //! This module configures the kernel logging backend and formatting.
 
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
 use owo_colors::OwoColorize;
 
 struct Logger;
 
+static COLOR: AtomicBool = AtomicBool::new(true);
+static WARNINGS: AtomicUsize = AtomicUsize::new(0);
+
 impl log::Log for Logger
 {
     #[inline]
@@ -17,7 +22,21 @@ impl log::Log for Logger
     #[inline]
     fn log(&self, record: &log::Record)
     {
-        let level_str = match record.level()
+        let level = record.level();
+        let module = record.module_path().unwrap_or("unknown");
+
+        if level == log::Level::Warn
+        {
+            WARNINGS.fetch_add(1, Ordering::Relaxed);
+        }
+
+        if !COLOR.load(Ordering::Relaxed)
+        {
+            println!("[{}] ({}) {}", level, module, record.args());
+            return;
+        }
+
+        let level_str = match level
         {
             log::Level::Error => "ERROR".red().into_styled(),
             log::Level::Warn => "WARN".yellow().into_styled(),
@@ -26,12 +45,7 @@ impl log::Log for Logger
             log::Level::Trace => "TRACE".purple().into_styled(),
         };
 
-        println!(
-            "[{}] ({}) {}",
-            level_str,
-            record.module_path().unwrap_or("unknown"),
-            record.args()
-        );
+        println!("[{}] ({}) {}", level_str, module, record.args());
     }
 
     #[inline]
@@ -46,3 +60,24 @@ pub fn init()
     log::set_logger(&LOGGER).expect("Failed to initialize logger");
     log::set_max_level(log::LevelFilter::max());
 }
+
+/// Enable or disable ANSI colors in log output.
+#[inline]
+pub fn set_color(enabled: bool)
+{
+    COLOR.store(enabled, Ordering::Relaxed);
+}
+
+/// Whether log output uses ANSI colors.
+#[inline]
+pub fn color() -> bool
+{
+    COLOR.load(Ordering::Relaxed)
+}
+
+/// Warnings logged since boot.
+#[inline]
+pub fn warnings() -> usize
+{
+    WARNINGS.load(Ordering::Relaxed)
+}
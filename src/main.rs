@@ -6,13 +6,16 @@
 #![no_std]
 #![no_main]
 
-use core::{
-    arch::{asm, naked_asm},
-    panic::PanicInfo,
+use core::{arch::naked_asm, panic::PanicInfo};
+
+use risky::{
+    BOOT_STATUS, STACK_SIZE,
+    arch::{self, PageAligned},
+    boot, crash,
+    drivers::uart,
+    early_println, sync, task, trace,
 };
 
-use risky::{BOOT_STATUS, STACK_SIZE, arch::PageAligned, drivers::uart};
-
 #[unsafe(link_section = ".bss.stack")]
 static mut BOOT_STACK: PageAligned<STACK_SIZE> = PageAligned([0; _]);
 
@@ -30,6 +33,7 @@ pub extern "C" fn _start()
         // a0 = physical hartid, a1 = FDT pointer (if hart 0) OR heap_stack_top (if hart > 0)
         "mv s0, a0",
         "mv s1, a1",
+        "mv tp, zero",          // No `Cpu` until `Cpu::set`, whatever firmware left here
 
         // Branching: Master vs Secondary
         "la t0, {boot_status}",
@@ -61,8 +65,7 @@ pub extern "C" fn _start()
         "5:",
         // a1 contains the `opaque` value (`cpu.stack_top`) from sbi::hart_start
         "mv sp, a1",
-        "mv a0, s0",            // Restore hartid
-        "li a1, 0",             // Clear a1 so kmain knows this isn't an FDT
+        "mv a0, s0",            // Restore hartid; kmain ignores a1 on this path
 
         // -- Final Common Setup
         "3:",
@@ -86,14 +89,29 @@ pub extern "C" fn _start()
 #[panic_handler]
 fn panic(info: &PanicInfo) -> !
 {
+    // Never returns if only the current task has to go.
+    task::contain_panic(info);
+
+    if !crash::begin()
+    {
+        // Already panicking; the logging path may be what broke, so bypass it.
+        early_println!("Nested panic: {}", info);
+        boot::halt()
+    }
+
+    sync::poison_held_locks();
     uart::set_direct_mode(true);
 
     log::error!("\n--- KERNEL PANIC ---");
     log::error!("{}", info);
     log::error!("--------------------");
 
-    loop
+    if let Some(cpu) = arch::Cpu::try_get()
+        && trace::is_enabled()
     {
-        unsafe { asm!("wfi") }
+        trace::dump(cpu);
     }
+
+    crash::stop_others();
+    crash::finish()
 }
@@ -1,4 +1,5 @@
 //! Memory-management modules.
 
 pub mod heap;
+pub mod map;
 pub mod pmm;
@@ -1,30 +1,56 @@
 //! Kernel global heap allocator bootstrap.
+//!
+//! The heap, and the page allocator it grows from, manage the RAM between the
+//! `_heap_start` and `_heap_end` linker symbols (by default, everything after
+//! the kernel image) minus every reserved region the device tree lists.
 
 use core::{
     alloc::{GlobalAlloc, Layout},
+    fmt,
     mem::size_of,
+    ops::Range,
     ptr::{self, NonNull},
 };
 
 use ::fdt::Fdt;
-use spin::{Mutex, Once};
 use talc::{OomHandler, Span, Talc};
 
 use crate::{
     arch::PAGE_SIZE,
     fdt::mem::{ram_regions, reserved_regions},
-    memory::pmm::BitmapAlloc,
+    memory::{
+        map::{self, RegionKind},
+        pmm::BitmapAlloc,
+    },
+    sync::{Mutex, OnceLock},
 };
 
 const INITIAL_HEAP_SIZE: usize = 512 * 1024;
 const GROWTH_CHUNK_SIZE: usize = 256 * 1024;
 const BITS_PER_WORD: usize = usize::BITS as usize;
 
+/// Debug builds fill new allocations with this, so uninitialized reads stand
+/// out.
+pub const ALLOC_POISON: u8 = 0xAA;
+/// Debug builds fill blocks with this as they are freed, so use-after-free
+/// reads stand out.
+pub const FREE_POISON: u8 = 0xDE;
+const POISON: bool = cfg!(debug_assertions);
+
+#[inline]
+unsafe fn poison(ptr: *mut u8, len: usize, pattern: u8)
+{
+    if POISON && !ptr.is_null()
+    {
+        unsafe { ptr::write_bytes(ptr, pattern, len) }
+    }
+}
+
 pub struct GrowOnOom;
 
 #[global_allocator]
 static ALLOCATOR: AllocWrapper<GrowOnOom> = AllocWrapper(Mutex::new(Talc::new(GrowOnOom)));
-static PMM: Once<Mutex<BitmapAlloc>> = Once::new();
+static PMM: OnceLock<Mutex<BitmapAlloc>> = OnceLock::new();
 
 pub struct AllocWrapper<O: OomHandler>(Mutex<Talc<O>>);
 
@@ -33,7 +59,9 @@ unsafe impl<O: OomHandler> GlobalAlloc for AllocWrapper<O>
     #[inline]
     unsafe fn alloc(&self, layout: Layout) -> *mut u8
     {
-        unsafe { self.0.lock().malloc(layout) }.map_or_default(NonNull::<u8>::as_ptr)
+        let ptr = unsafe { self.0.lock().malloc(layout) }.map_or_default(NonNull::<u8>::as_ptr);
+        unsafe { poison(ptr, layout.size(), ALLOC_POISON) };
+        ptr
     }
 
     #[inline]
@@ -41,7 +69,70 @@ unsafe impl<O: OomHandler> GlobalAlloc for AllocWrapper<O>
     {
         if let Some(ptr) = NonNull::new(ptr)
         {
-            unsafe { self.0.lock().free(ptr, layout) }
+            unsafe {
+                poison(ptr.as_ptr(), layout.size(), FREE_POISON);
+                self.0.lock().free(ptr, layout)
+            }
+        }
+    }
+
+    // `talc` recycles freed chunks without clearing them, so zeroing can't be
+    // skipped here; this only avoids re-entering `alloc` through the trait.
+    #[inline]
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8
+    {
+        let ptr = unsafe { self.0.lock().malloc(layout) }.map_or_default(NonNull::<u8>::as_ptr);
+        if !ptr.is_null()
+        {
+            unsafe { ptr::write_bytes(ptr, 0, layout.size()) }
+        }
+        ptr
+    }
+
+    // Let `talc` resize in place when the neighbouring chunk allows it, instead
+    // of the default allocate-copy-free. A block that has to move is copied
+    // and freed here rather than by `talc::grow`, so it gets poisoned like any
+    // other freed block.
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8
+    {
+        let Some(old) = NonNull::new(ptr)
+        else
+        {
+            return ptr::null_mut();
+        };
+
+        let old_size = layout.size();
+        let mut talc = self.0.lock();
+        if new_size > old_size
+        {
+            if unsafe { talc.grow_in_place(old, layout, new_size) }.is_ok()
+            {
+                unsafe { poison(ptr.add(old_size), new_size - old_size, ALLOC_POISON) };
+                return ptr;
+            }
+
+            let new_layout = unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
+            let Ok(new) = (unsafe { talc.malloc(new_layout) })
+            else
+            {
+                return ptr::null_mut();
+            };
+            let new = new.as_ptr();
+            unsafe {
+                ptr::copy_nonoverlapping(ptr, new, old_size);
+                poison(new.add(old_size), new_size - old_size, ALLOC_POISON);
+                poison(ptr, old_size, FREE_POISON);
+                talc.free(old, layout);
+            }
+            new
+        }
+        else
+        {
+            unsafe {
+                poison(ptr.add(new_size), old_size - new_size, FREE_POISON);
+                talc.shrink(old, layout, new_size)
+            };
+            ptr
         }
     }
 }
@@ -62,7 +153,11 @@ impl OomHandler for GrowOnOom
 
         match unsafe { talc.claim(span) }
         {
-            Ok(_) => Ok(()),
+            Ok(_) =>
+            {
+                map::record(RegionKind::Heap, start as usize, start as usize + bytes);
+                Ok(())
+            }
             Err(_) =>
             {
                 let _ = pmm.lock().free_pages(start, pages);
@@ -72,15 +167,99 @@ impl OomHandler for GrowOnOom
     }
 }
 
+/// Why a span can't be handed to the allocator.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SpanError
+{
+    OutsideRam,
+    OverlapsKernel,
+    OverlapsFdt,
+}
+
+impl fmt::Display for SpanError
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        f.write_str(match self
+        {
+            Self::OutsideRam => "runs past the end of RAM",
+            Self::OverlapsKernel => "overlaps the kernel image",
+            Self::OverlapsFdt => "overlaps the device tree",
+        })
+    }
+}
+
+/// Check that `span` is RAM that nothing else lives in, before the allocator
+/// is told it may hand it out.
+pub fn check_span(
+    span: &Range<usize>,
+    ram: &Range<usize>,
+    kernel: &Range<usize>,
+    fdt: &Range<usize>,
+) -> Result<(), SpanError>
+{
+    let overlaps = |other: &Range<usize>| span.start < other.end && other.start < span.end;
+
+    if span.start < ram.start || span.end > ram.end
+    {
+        Err(SpanError::OutsideRam)
+    }
+    else if overlaps(kernel)
+    {
+        Err(SpanError::OverlapsKernel)
+    }
+    else if overlaps(fdt)
+    {
+        Err(SpanError::OverlapsFdt)
+    }
+    else
+    {
+        Ok(())
+    }
+}
+
+/// Lowest page-aligned address from `start` where `len` bytes fit below `end`
+/// without overlapping any of the `(base, size)` regions `reserved` yields.
+pub fn place<I>(start: usize, end: usize, len: usize, reserved: impl Fn() -> I) -> Option<usize>
+where
+    I: Iterator<Item = (usize, usize)>,
+{
+    let mut at = start.checked_next_multiple_of(PAGE_SIZE)?;
+    loop
+    {
+        let span_end = at.checked_add(len).filter(|&span_end| span_end <= end)?;
+        let Some((base, size)) =
+            reserved().find(|&(base, size)| base < span_end && at < base.saturating_add(size))
+        else
+        {
+            return Some(at);
+        };
+        at = base
+            .checked_add(size)
+            .and_then(|next| next.checked_next_multiple_of(PAGE_SIZE))?;
+    }
+}
+
+/// Free every page of RAM in `dev_tree`, then take back its reserved regions.
+pub fn mark_from_fdt(pmm: &mut BitmapAlloc, dev_tree: &Fdt)
+{
+    ram_regions(dev_tree).for_each(|(start, size)| pmm.mark_free(start, size));
+    reserved_regions(dev_tree).for_each(|(start, size)| pmm.mark_used(start, size));
+}
+
 pub fn pmm() -> &'static Mutex<BitmapAlloc>
 {
     PMM.wait()
 }
 
-pub fn init(dev_tree: &Fdt)
+pub fn init(dev_tree: &Fdt, dev_tree_ptr: *const u8)
 {
     unsafe extern "C" {
+        static _kernel_start: u8;
+        static _bss_start: u8;
         static _end: u8;
+        static _heap_start: u8;
+        static _heap_end: u8;
     }
 
     let Some((ram_start, ram_end)) = ({
@@ -98,20 +277,36 @@ pub fn init(dev_tree: &Fdt)
     };
 
     let kernel_end = ptr::addr_of!(_end) as usize;
+    let kernel = ptr::addr_of!(_kernel_start) as usize..kernel_end;
 
     let (managed_start_page, managed_end_page) = {
-        let (free_mem_start, free_mem_end) = (ram_start.max(kernel_end), ram_end);
-        (free_mem_start.div_ceil(PAGE_SIZE), free_mem_end / PAGE_SIZE)
+        let (heap_start, heap_end) = (
+            ptr::addr_of!(_heap_start) as usize,
+            ptr::addr_of!(_heap_end) as usize,
+        );
+        let free_mem_end = if heap_end > heap_start
+        {
+            heap_end.min(ram_end)
+        }
+        else
+        {
+            ram_end
+        };
+        (
+            ram_start.max(heap_start).div_ceil(PAGE_SIZE),
+            free_mem_end / PAGE_SIZE,
+        )
     };
 
     if managed_start_page >= managed_end_page
     {
-        panic!("Not enough RAM remains after kernel image.")
+        panic!("Not enough RAM within the heap bounds.")
     }
 
     let managed_start = managed_start_page
         .checked_mul(PAGE_SIZE)
         .expect("Managed start overflow.");
+    let managed_end = managed_end_page * PAGE_SIZE;
     let total_pages = managed_end_page - managed_start_page;
 
     let bitmap_pages = {
@@ -128,28 +323,23 @@ pub fn init(dev_tree: &Fdt)
         panic!("Not enough RAM for PMM.");
     }
 
-    let bitmap_base = managed_start;
     let bitmap_bytes_aligned = bitmap_pages
         .checked_mul(PAGE_SIZE)
         .expect("Bitmap page bytes overflow.");
-    let pmm_base = managed_start
-        .checked_add(bitmap_bytes_aligned)
-        .expect("PMM base overflow.");
-    let pmm_pages = total_pages - bitmap_pages;
+    // Written before anything else is marked, so it must dodge reserved
+    // memory by itself.
+    let bitmap_base = place(managed_start, managed_end, bitmap_bytes_aligned, || {
+        reserved_regions(dev_tree)
+    })
+    .expect("No room for the PMM bitmap outside reserved memory.");
 
     let bitmap_ptr =
         NonNull::new(bitmap_base as *mut usize).expect("PMM bitmap base cannot be a null pointer.");
 
-    let mut pmm = unsafe { BitmapAlloc::new(bitmap_ptr, pmm_pages, pmm_base) };
+    let mut pmm = unsafe { BitmapAlloc::new(bitmap_ptr, total_pages, managed_start) };
 
-    ram_regions(dev_tree).for_each(|(start, size)| pmm.mark_free(start, size));
-    reserved_regions(dev_tree).for_each(|(start, size)| pmm.mark_used(start, size));
-
-    let kernel_reserved_start = ram_start.max(pmm_base);
-    if kernel_end > kernel_reserved_start
-    {
-        pmm.mark_used(kernel_reserved_start, kernel_end - kernel_reserved_start);
-    }
+    mark_from_fdt(&mut pmm, dev_tree);
+    pmm.mark_used(kernel.start, kernel.len());
     pmm.mark_used(bitmap_base, bitmap_bytes_aligned);
 
     let heap_pages = INITIAL_HEAP_SIZE.div_ceil(PAGE_SIZE);
@@ -161,6 +351,18 @@ pub fn init(dev_tree: &Fdt)
     let heap_bytes = heap_pages
         .checked_mul(PAGE_SIZE)
         .expect("Heap byte size overflow.");
+
+    let heap_span = heap_start.as_ptr() as usize..heap_start.as_ptr() as usize + heap_bytes;
+    let fdt = dev_tree_ptr as usize..dev_tree_ptr as usize + dev_tree.total_size();
+    if let Err(e) = check_span(&heap_span, &(ram_start..ram_end), &kernel, &fdt)
+    {
+        panic!(
+            "Initial heap {:#x}..{:#x} {e} (RAM {ram_start:#x}..{ram_end:#x}, kernel \
+             {:#x}..{:#x}, FDT {:#x}..{:#x}).",
+            heap_span.start, heap_span.end, kernel.start, kernel.end, fdt.start, fdt.end
+        );
+    }
+
     let heap_range = Span::from_base_size(heap_start.as_ptr(), heap_bytes);
 
     unsafe {
@@ -170,4 +372,14 @@ pub fn init(dev_tree: &Fdt)
             .claim(heap_range)
             .expect("Failed to claim heap");
     }
+
+    let bss_start = ptr::addr_of!(_bss_start) as usize;
+    map::record(RegionKind::Image, kernel.start, bss_start);
+    map::record(RegionKind::Bss, bss_start, kernel.end);
+    map::record(
+        RegionKind::PageBitmap,
+        bitmap_base,
+        bitmap_base + bitmap_bytes_aligned,
+    );
+    map::record(RegionKind::Heap, heap_span.start, heap_span.end);
 }
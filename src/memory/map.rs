@@ -0,0 +1,246 @@
+//! Physical memory map: where the kernel image, the page bitmap, the heap and
+//! loaded programs ended up.
+//!
+//! `heap::init` records each region as it places it, heap growth records
+//! every chunk it claims, and `elf::spawn` records the programs it loads, so
+//! [`dump`] can print the layout (as boot does with the `verbose-boot`
+//! feature) and [`is_code`] can tell where a task may be executing. Per-hart
+//! stacks come from the heap and are listed within it, from `CPU_VEC`.
+
+use alloc::vec::Vec;
+use core::{fmt, ops::Range, ptr};
+
+use crate::{STACK_SIZE, TRAP_STACK_SIZE, arch::CPU_VEC, interrupt, sync::Mutex};
+
+/// Regions the table holds; heap chunks that touch merge, so growth rarely
+/// needs a new entry.
+const MAX_REGIONS: usize = 32;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RegionKind
+{
+    /// Text, read-only data and data, from `_kernel_start`.
+    Image,
+    /// Zeroed at boot, up to `_end`; holds the boot stack.
+    Bss,
+    /// The page allocator's bitmap.
+    PageBitmap,
+    /// Memory claimed by the heap allocator.
+    Heap,
+    /// A program loaded by `elf::spawn`, run in place.
+    Program,
+}
+
+impl RegionKind
+{
+    #[inline]
+    pub fn name(&self) -> &'static str
+    {
+        match self
+        {
+            Self::Image => "kernel image",
+            Self::Bss => "kernel bss",
+            Self::PageBitmap => "page bitmap",
+            Self::Heap => "heap",
+            Self::Program => "program",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Region
+{
+    pub kind: RegionKind,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Region
+{
+    #[inline]
+    pub fn len(&self) -> usize
+    {
+        self.end - self.start
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool
+    {
+        self.start == self.end
+    }
+
+    #[inline]
+    pub fn contains(&self, range: &Range<usize>) -> bool
+    {
+        self.start <= range.start && range.end <= self.end
+    }
+}
+
+impl fmt::Display for Region
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        write!(
+            f,
+            "{:#010x}..{:#010x} {:>8} KiB  {}",
+            self.start,
+            self.end,
+            self.len() / 1024,
+            self.kind.name()
+        )
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Table
+{
+    regions: [Region; MAX_REGIONS],
+    len: usize,
+    /// Regions that didn't fit.
+    dropped: usize,
+}
+
+const EMPTY: Region = Region {
+    kind: RegionKind::Heap,
+    start: 0,
+    end: 0,
+};
+
+// Taken by the heap's OOM handler with the allocator locked, so it must
+// never allocate itself.
+static TABLE: Mutex<Table> = Mutex::new(Table {
+    regions: [EMPTY; MAX_REGIONS],
+    len: 0,
+    dropped: 0,
+});
+
+impl Table
+{
+    /// Insert `region` in address order, merging it into a neighbour of the
+    /// same kind that it touches.
+    fn insert(&mut self, region: Region)
+    {
+        let index = self.regions[..self.len].partition_point(|r| r.start < region.start);
+
+        let touches_prev = index.checked_sub(1).is_some_and(|i| {
+            self.regions[i].kind == region.kind && self.regions[i].end == region.start
+        });
+        let touches_next = self.regions[..self.len]
+            .get(index)
+            .is_some_and(|next| next.kind == region.kind && next.start == region.end);
+
+        match (touches_prev, touches_next)
+        {
+            // Fills the gap between two: they become one.
+            (true, true) =>
+            {
+                self.regions[index - 1].end = self.regions[index].end;
+                self.regions.copy_within(index + 1..self.len, index);
+                self.len -= 1;
+                return;
+            }
+            (true, false) =>
+            {
+                self.regions[index - 1].end = region.end;
+                return;
+            }
+            (false, true) =>
+            {
+                self.regions[index].start = region.start;
+                return;
+            }
+            (false, false) =>
+            {}
+        }
+
+        if self.len == MAX_REGIONS
+        {
+            self.dropped += 1;
+            return;
+        }
+        self.regions.copy_within(index..self.len, index + 1);
+        self.regions[index] = region;
+        self.len += 1;
+    }
+}
+
+/// Note that `start..end` now holds `kind`. Empty ranges are ignored.
+pub fn record(kind: RegionKind, start: usize, end: usize)
+{
+    if start >= end
+    {
+        return;
+    }
+
+    let region = Region { kind, start, end };
+    interrupt::with_disabled(|| TABLE.lock().insert(region));
+}
+
+/// Bounds of the kernel's text, from the linker script.
+#[inline]
+pub fn kernel_text() -> Range<usize>
+{
+    unsafe extern "C" {
+        static _kernel_start: u8;
+        static _etext: u8;
+    }
+
+    ptr::addr_of!(_kernel_start) as usize..ptr::addr_of!(_etext) as usize
+}
+
+/// Whether a task could be executing at `addr`: kernel text or a loaded
+/// program. Never allocates, so trap handlers may ask.
+pub fn is_code(addr: usize) -> bool
+{
+    kernel_text().contains(&addr)
+        || interrupt::with_disabled(|| {
+            let table = TABLE.lock();
+            table.regions[..table.len].iter().any(|region| {
+                region.kind == RegionKind::Program && region.contains(&(addr..addr + 1))
+            })
+        })
+}
+
+/// Every recorded region, in address order.
+pub fn regions() -> Vec<Region>
+{
+    let table = interrupt::with_disabled(|| *TABLE.lock());
+    table.regions[..table.len].to_vec()
+}
+
+/// `(logical hart ID, main stack, trap stack)` for every hart, once the CPU
+/// vector exists.
+pub fn hart_stacks() -> impl Iterator<Item = (usize, Range<usize>, Range<usize>)>
+{
+    CPU_VEC.get().into_iter().flatten().map(|cpu| {
+        (
+            cpu.logical_id,
+            cpu.stack_top - STACK_SIZE..cpu.stack_top,
+            cpu.trap_stack_top - TRAP_STACK_SIZE..cpu.trap_stack_top,
+        )
+    })
+}
+
+/// Print the memory map, per-hart stacks included.
+pub fn dump()
+{
+    let table = interrupt::with_disabled(|| *TABLE.lock());
+
+    println!("Memory map:");
+    for region in &table.regions[..table.len]
+    {
+        println!("  {region}");
+    }
+    if table.dropped != 0
+    {
+        println!("  ({} regions not recorded)", table.dropped);
+    }
+
+    for (hart, stack, trap_stack) in hart_stacks()
+    {
+        println!(
+            "  hart {hart}: stack {:#010x}..{:#010x}, trap stack {:#010x}..{:#010x}",
+            stack.start, stack.end, trap_stack.start, trap_stack.end
+        );
+    }
+}
@@ -108,6 +108,11 @@ impl BitmapAlloc
         ptr::null_mut()
     }
 
+    /// Return `count` pages starting at `start`.
+    ///
+    /// Returns `false` if the range is misaligned or not managed here. Debug
+    /// builds panic instead, and also on freeing a page that isn't allocated,
+    /// since any of these means the caller's bookkeeping is already wrong.
     pub fn free_pages(&mut self, start: *mut u8, count: usize) -> bool
     {
         if count == 0
@@ -115,32 +120,35 @@ impl BitmapAlloc
             return true;
         }
 
-        let addr = start as usize;
-        if addr == 0 || addr < self.base_addr
-        {
-            return false;
-        }
-
-        let offset = addr - self.base_addr;
-        if offset % PAGE_SIZE != 0
-        {
-            return false;
-        }
-
-        let start_idx = offset / PAGE_SIZE;
-        let Some(end_idx) = start_idx.checked_add(count)
+        let Some(start_idx) = self.page_index(start as usize)
         else
         {
+            debug_assert!(
+                false,
+                "Freeing {start:p}, which is misaligned or outside the managed region"
+            );
             return false;
         };
 
-        if end_idx > self.total_pages
+        let Some(end_idx) = start_idx
+            .checked_add(count)
+            .filter(|&end| end <= self.total_pages)
+        else
         {
+            debug_assert!(
+                false,
+                "Freeing {count} pages at {start:p} overruns the managed region"
+            );
             return false;
-        }
+        };
 
         for i in start_idx..end_idx
         {
+            debug_assert!(
+                self.bit_is_set(i),
+                "Double free of page {:#x}",
+                self.base_addr + i * PAGE_SIZE
+            );
             self.set_bit(i, false);
         }
 
@@ -149,26 +157,37 @@ impl BitmapAlloc
 
     fn commit_alloc(&mut self, start: usize, count: usize) -> *mut u8
     {
-        for i in start..(start + count)
-        {
-            self.set_bit(i, true);
-        }
-
-        let Some(offset) = start.checked_mul(PAGE_SIZE)
+        // Resolve the address first, so a failure leaves the bitmap untouched.
+        let Some(addr) = start
+            .checked_mul(PAGE_SIZE)
+            .and_then(|offset| self.base_addr.checked_add(offset))
         else
         {
             return ptr::null_mut();
         };
 
-        let Some(addr) = self.base_addr.checked_add(offset)
-        else
+        for i in start..(start + count)
         {
-            return ptr::null_mut();
-        };
+            self.set_bit(i, true);
+        }
 
         addr as *mut u8
     }
 
+    /// Index of the page starting at `addr`, if it is page-aligned and inside
+    /// the managed region.
+    fn page_index(&self, addr: usize) -> Option<usize>
+    {
+        let offset = addr.checked_sub(self.base_addr)?;
+        if !offset.is_multiple_of(PAGE_SIZE)
+        {
+            return None;
+        }
+
+        let idx = offset / PAGE_SIZE;
+        (idx < self.total_pages).then_some(idx)
+    }
+
     fn clamped_page_range(
         &self,
         start: usize,
@@ -229,6 +248,7 @@ impl BitmapAlloc
     #[inline]
     fn bit_is_set(&self, idx: usize) -> bool
     {
+        debug_assert!(idx < self.total_pages, "Page index {idx} out of range");
         let word = idx / BITS_PER_WORD;
         let bit = idx % BITS_PER_WORD;
         ((self.bitmap[word] >> bit) & 1) != 0
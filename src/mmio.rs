@@ -1,6 +1,32 @@
 //! Generic MMIO register access primitives.
+//!
+//! Plain `read`/`write` are volatile but impose no ordering against normal
+//! memory. Drivers that hand buffers to a device, or that consume data a
+//! device wrote, use the `_ordered` variants instead so every fence lives
+//! here. Structures a device reads straight from RAM, such as VirtIO rings,
+//! use [`write_barrier`] and [`read_barrier`] between the plain accesses.
+//!
+//! `read_n`/`write_n` and their `_strided` forms copy whole blocks, one
+//! volatile access per element in ascending order: repeatedly at one address
+//! for a FIFO data register, or stepping through a register array.
 
-use core::ptr::NonNull;
+use core::{arch::asm, ptr::NonNull};
+
+/// Order every earlier memory write before every later one, e.g. a ring
+/// entry before the index that publishes it to a device.
+#[inline]
+pub fn write_barrier()
+{
+    unsafe { asm!("fence w, w", options(nostack, preserves_flags)) }
+}
+
+/// Order every earlier memory read before every later one, e.g. a
+/// device-written index before the buffers it says are ready.
+#[inline]
+pub fn read_barrier()
+{
+    unsafe { asm!("fence r, r", options(nostack, preserves_flags)) }
+}
 
 #[derive(Clone, Copy)]
 pub enum IoWidth
@@ -63,10 +89,25 @@ pub struct Register<T>
 
 impl<T: RegisterValue> Register<T>
 {
-    pub const fn new(ptr: *mut T, strategy: AccessStrategy) -> Self
+    /// Wrap the register at `ptr`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `ptr` is null or misaligned for the access width `strategy`
+    /// implies; either means the register map is wrong.
+    pub fn new(ptr: *mut T, strategy: AccessStrategy) -> Self
     {
         assert!(!ptr.is_null());
 
+        let aligned = match strategy
+        {
+            AccessStrategy::Shifted {
+                width: IoWidth::U32,
+            } => (ptr as *mut u32).is_aligned(),
+            _ => ptr.is_aligned(),
+        };
+        assert!(aligned, "Misaligned MMIO register");
+
         Self {
             ptr: unsafe { NonNull::new_unchecked(ptr as _) },
             strategy,
@@ -107,4 +148,69 @@ impl<T: RegisterValue> Register<T>
             }
         }
     }
+
+    /// The same kind of register `offset` bytes further on.
+    #[inline]
+    fn at(&self, offset: usize) -> Self
+    {
+        Self::new(self.ptr.as_ptr().wrapping_byte_add(offset), self.strategy)
+    }
+
+    /// Fill `buf` by reading this register once per element, as for a FIFO
+    /// data register.
+    #[inline]
+    pub fn read_n(&self, buf: &mut [T])
+    {
+        buf.iter_mut().for_each(|value| *value = self.read());
+    }
+
+    /// Write every element of `values` to this register in turn.
+    #[inline]
+    pub fn write_n(&self, values: &[T])
+    {
+        values.iter().for_each(|&value| self.write(value));
+    }
+
+    /// Fill `buf` from the register array starting here, whose elements are
+    /// `stride` bytes apart.
+    pub fn read_strided(&self, buf: &mut [T], stride: usize)
+    {
+        for (i, value) in buf.iter_mut().enumerate()
+        {
+            *value = self.at(i * stride).read();
+        }
+    }
+
+    /// Write `values` to the register array starting here, whose elements
+    /// are `stride` bytes apart.
+    pub fn write_strided(&self, values: &[T], stride: usize)
+    {
+        for (i, &value) in values.iter().enumerate()
+        {
+            self.at(i * stride).write(value);
+        }
+    }
+
+    /// Read the register, then order it before any later memory reads.
+    ///
+    /// Use when the value tells us device-written memory is ready (e.g. a
+    /// status bit), so we don't read that memory early.
+    #[inline]
+    pub fn read_ordered(&self) -> T
+    {
+        let value = self.read();
+        unsafe { asm!("fence i, r", options(nostack, preserves_flags)) }
+        value
+    }
+
+    /// Flush prior memory writes to devices, then write the register.
+    ///
+    /// Use for doorbells and data registers whose write hands memory (or a
+    /// previously staged byte) over to the device.
+    #[inline]
+    pub fn write_ordered(&self, value: T)
+    {
+        unsafe { asm!("fence w, o", options(nostack, preserves_flags)) }
+        self.write(value)
+    }
 }
@@ -1,28 +1,210 @@
 //! Minimal PLIC setup for per-hart interrupt acceptance.
+//!
+//! Each source is routed to handlers per hart: [`route`] records the handler
+//! for an `(irq, hart)` pair and enables the source on that hart's context
+//! only, and [`dispatch`] runs whatever the claiming hart has routed.
+//!
+//! Nothing is enabled anywhere until routed: [`init`] clears whatever enables
+//! firmware left on the hart's context. A source routed to several harts is
+//! still safe, since a claim hands each interrupt to exactly one of them, but
+//! [`route_exclusive`] keeps a device such as the UART on one hart.
+
+use alloc::collections::BTreeMap;
 
 use crate::{
+    arch::{self, Cpu},
+    interrupt,
     mmio::{AccessStrategy, Register},
     soc::plic::*,
+    sync::Mutex,
 };
 
+/// Runs in trap context for each claimed interrupt, with the source's number.
+pub type IrqHandler = fn(u32);
+
+// Keyed by `(irq, logical hart)`. Taken in trap context, so only ever with
+// interrupts disabled. Also serializes updates to the enable words, which
+// several sources share.
+static ROUTES: Mutex<BTreeMap<(u32, usize), IrqHandler>> = Mutex::new(BTreeMap::new());
+
+/// PLIC context taking `hart_id`'s (physical ID) S-mode interrupts; its
+/// M-mode context comes just before.
 #[inline]
-fn get_context(hart_id: usize) -> usize
+pub const fn get_context(hart_id: usize) -> usize
 {
     hart_id * 2 + 1
 }
 
+#[inline]
+fn reg(addr: usize) -> Register<u32>
+{
+    Register::new(addr as _, AccessStrategy::Direct)
+}
+
 #[inline]
 fn threshold_ptr(hart_id: usize) -> Register<u32>
 {
     let ctx = get_context(hart_id);
-    Register::new(
-        (THRESHOLD_BASE + (ctx * 0x1000)) as _,
-        AccessStrategy::Direct,
+    reg(THRESHOLD_BASE + ctx * CONTEXT_STRIDE)
+}
+
+#[inline]
+fn claim_ptr(hart_id: usize) -> Register<u32>
+{
+    let ctx = get_context(hart_id);
+    reg(THRESHOLD_BASE + ctx * CONTEXT_STRIDE + CLAIM_OFFSET)
+}
+
+/// Address of the enable word holding `irq`'s bit for `hart_id`'s context,
+/// and that bit.
+///
+/// Each context has one 32-bit enable word per 32 sources.
+#[inline]
+pub fn enable_location(hart_id: usize, irq: u32) -> (usize, u32)
+{
+    assert_valid(irq);
+
+    let ctx = get_context(hart_id);
+    let word = (irq / 32) as usize;
+    (
+        ENABLE_BASE + ctx * ENABLE_STRIDE + word * 4,
+        1 << (irq % 32),
     )
 }
 
 #[inline]
+fn enable_bit(hart_id: usize, irq: u32) -> (Register<u32>, u32)
+{
+    let (addr, bit) = enable_location(hart_id, irq);
+    (reg(addr), bit)
+}
+
+#[inline]
+fn assert_valid(irq: u32)
+{
+    assert!(
+        (1..MAX_SOURCES).contains(&irq),
+        "PLIC source {irq} out of range"
+    );
+}
+
+/// Set up `hart_id`'s (physical ID) context with every source disabled.
 pub fn init(hart_id: usize)
 {
+    let ctx = get_context(hart_id);
+    reg(ENABLE_BASE + ctx * ENABLE_STRIDE).write_strided(&[0; (MAX_SOURCES / 32) as usize], 4);
     threshold_ptr(hart_id).write(0);
 }
+
+/// Let `irq` interrupt `hart_id` (physical ID).
+pub fn enable(hart_id: usize, irq: u32)
+{
+    let (reg, bit) = enable_bit(hart_id, irq);
+    interrupt::with_disabled(|| {
+        let _routes = ROUTES.lock();
+        reg.write(reg.read() | bit);
+    });
+}
+
+/// Stop `irq` from interrupting `hart_id` (physical ID).
+pub fn disable(hart_id: usize, irq: u32)
+{
+    let (reg, bit) = enable_bit(hart_id, irq);
+    interrupt::with_disabled(|| {
+        let _routes = ROUTES.lock();
+        reg.write(reg.read() & !bit);
+    });
+}
+
+/// Whether `irq` may interrupt `hart_id` (physical ID).
+pub fn is_enabled(hart_id: usize, irq: u32) -> bool
+{
+    let (reg, bit) = enable_bit(hart_id, irq);
+    reg.read() & bit != 0
+}
+
+/// Handle `irq` with `handler` when `hart` (logical ID) claims it, and let it
+/// interrupt that hart. Returns the handler this replaces.
+///
+/// The source also needs a non-zero priority, see [`set_priority`]. Other
+/// harts keep whatever routes they have for the same source.
+pub fn route(irq: u32, hart: usize, handler: IrqHandler) -> Option<IrqHandler>
+{
+    assert_valid(irq);
+
+    let previous = interrupt::with_disabled(|| ROUTES.lock().insert((irq, hart), handler));
+    enable(Cpu::nth(hart).physical_id, irq);
+    previous
+}
+
+/// Like [`route`], but first unroute `irq` from every other hart, so only
+/// `hart` can claim it.
+pub fn route_exclusive(irq: u32, hart: usize, handler: IrqHandler) -> Option<IrqHandler>
+{
+    for other in arch::online_cpus().filter(|cpu| cpu.logical_id != hart)
+    {
+        unroute(irq, other.logical_id);
+    }
+    route(irq, hart, handler)
+}
+
+/// Stop `irq` from interrupting `hart` (logical ID), returning its handler.
+pub fn unroute(irq: u32, hart: usize) -> Option<IrqHandler>
+{
+    disable(Cpu::nth(hart).physical_id, irq);
+    interrupt::with_disabled(|| ROUTES.lock().remove(&(irq, hart)))
+}
+
+/// Run `hart`'s handler for `irq`, returning whether it has one.
+pub fn deliver(irq: u32, hart: usize) -> bool
+{
+    // Copy it out, so the handler runs without the table locked.
+    let handler = interrupt::with_disabled(|| ROUTES.lock().get(&(irq, hart)).copied());
+    handler.inspect(|handler| handler(irq)).is_some()
+}
+
+/// Claim and handle every interrupt pending for `cpu`.
+///
+/// A source with no handler here is disabled for this hart rather than left
+/// to fire again.
+pub fn dispatch(cpu: &Cpu)
+{
+    while let Some(irq) = claim(cpu.physical_id)
+    {
+        if !deliver(irq, cpu.logical_id)
+        {
+            let hart = cpu.logical_id;
+            log::warn!("PLIC source {irq} has no handler on hart {hart}");
+            disable(cpu.physical_id, irq);
+        }
+        complete(cpu.physical_id, irq);
+    }
+}
+
+/// Set `irq`'s priority; zero masks it on every context.
+pub fn set_priority(irq: u32, priority: u32)
+{
+    assert_valid(irq);
+    reg(PRIORITY_BASE + irq as usize * 4).write(priority.min(MAX_PRIORITY));
+}
+
+/// Claim the highest-priority pending interrupt for `hart_id`, if any.
+///
+/// The claim is ordered before later reads, so a handler never sees the
+/// device's memory from before the interrupt was raised.
+#[inline]
+pub fn claim(hart_id: usize) -> Option<u32>
+{
+    match claim_ptr(hart_id).read_ordered()
+    {
+        0 => None,
+        irq => Some(irq),
+    }
+}
+
+/// Signal that `irq`, previously claimed by `hart_id`, has been handled.
+#[inline]
+pub fn complete(hart_id: usize, irq: u32)
+{
+    claim_ptr(hart_id).write(irq);
+}
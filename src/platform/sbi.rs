@@ -3,19 +3,74 @@
 //! This module exposes kernel-facing wrappers for supervisor binary interface
 //! calls.
 
-use core::arch::asm;
+use core::{arch::asm, fmt};
 
+const EID_BASE: usize = 0x10;
 const EID_HSM: usize = 0x48534D;
 const EID_TIME: usize = 0x54494D45;
 const EID_SPI: usize = 0x735049;
+const EID_SRST: usize = 0x53525354;
 
 const EID_CONSOLE_PUTCHAR: usize = 0x01;
 const EID_CONSOLE_GETCHAR: usize = 0x02;
 
+const BASE_FID_GET_SPEC_VERSION: usize = 0;
+const BASE_FID_GET_IMPL_ID: usize = 1;
+const BASE_FID_GET_IMPL_VERSION: usize = 2;
+const BASE_FID_GET_MVENDORID: usize = 4;
+const BASE_FID_GET_MARCHID: usize = 5;
+const BASE_FID_GET_MIMPID: usize = 6;
+
 const HSM_FID_HART_START: usize = 0;
+const HSM_FID_HART_GET_STATUS: usize = 2;
 const HSM_FID_HART_SUSPEND: usize = 3;
 const TIME_FID_SET_TIMER: usize = 0;
 const SPI_FID_SEND_IPI: usize = 0;
+const SRST_FID_SYSTEM_RESET: usize = 0;
+
+/// What `system_reset` should do to the machine.
+#[repr(usize)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ResetType
+{
+    Shutdown = 0,
+    ColdReboot = 1,
+    WarmReboot = 2,
+}
+
+/// Why `system_reset` was called; QEMU turns `SystemFailure` into a failing
+/// exit status.
+#[repr(usize)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ResetReason
+{
+    NoReason = 0,
+    SystemFailure = 1,
+}
+
+/// HSM state of a hart, as reported by `hart_get_status`.
+#[repr(usize)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HartState
+{
+    Started = 0,
+    Stopped = 1,
+    StartPending = 2,
+    StopPending = 3,
+    Suspended = 4,
+    SuspendPending = 5,
+    ResumePending = 6,
+}
+
+impl HartState
+{
+    /// Whether the hart is running or on its way there.
+    #[inline]
+    pub fn is_starting(self) -> bool
+    {
+        matches!(self, Self::Started | Self::StartPending)
+    }
+}
 
 #[inline(always)]
 fn call(extension: usize, function: usize, arg0: usize, arg1: usize, arg2: usize)
@@ -38,6 +93,75 @@ fn call(extension: usize, function: usize, arg0: usize, arg1: usize, arg2: usize
     (error, value)
 }
 
+#[inline]
+fn base_call(function: usize) -> usize
+{
+    // Base extension calls can't fail; every FID used here is mandatory.
+    call(EID_BASE, function, 0, 0, 0).1
+}
+
+/// SBI specification version as `(major, minor)`.
+#[inline]
+pub fn spec_version() -> (usize, usize)
+{
+    let version = base_call(BASE_FID_GET_SPEC_VERSION);
+    ((version >> 24) & 0x7f, version & 0xff_ffff)
+}
+
+#[inline]
+pub fn impl_id() -> usize
+{
+    base_call(BASE_FID_GET_IMPL_ID)
+}
+
+#[inline]
+pub fn impl_version() -> usize
+{
+    base_call(BASE_FID_GET_IMPL_VERSION)
+}
+
+/// Name of a well-known SBI implementation.
+pub fn impl_name(id: usize) -> Option<&'static str>
+{
+    Some(match id
+    {
+        0 => "BBL",
+        1 => "OpenSBI",
+        2 => "Xvisor",
+        3 => "KVM",
+        4 => "RustSBI",
+        5 => "Diosix",
+        6 => "Coffer",
+        7 => "Xen",
+        8 => "PolarFire HSS",
+        9 => "coreboot",
+        10 => "oreboot",
+        11 => "bhyve",
+        _ => return None,
+    })
+}
+
+/// `mvendorid`; zero if not implemented.
+#[inline]
+pub fn mvendorid() -> usize
+{
+    base_call(BASE_FID_GET_MVENDORID)
+}
+
+/// `marchid`; zero if not implemented.
+#[inline]
+pub fn marchid() -> usize
+{
+    base_call(BASE_FID_GET_MARCHID)
+}
+
+/// `mimpid`; zero if not implemented.
+#[inline]
+pub fn mimpid() -> usize
+{
+    base_call(BASE_FID_GET_MIMPID)
+}
+
 #[inline]
 pub fn hart_start(hart_id: usize, start_addr: usize, opaque: usize) -> bool
 {
@@ -45,6 +169,28 @@ pub fn hart_start(hart_id: usize, start_addr: usize, opaque: usize) -> bool
     error == 0
 }
 
+/// Current HSM state of `hart_id`, or `None` if the firmware doesn't know it.
+pub fn hart_get_status(hart_id: usize) -> Option<HartState>
+{
+    let (error, value) = call(EID_HSM, HSM_FID_HART_GET_STATUS, hart_id, 0, 0);
+    if error != 0
+    {
+        return None;
+    }
+
+    Some(match value
+    {
+        0 => HartState::Started,
+        1 => HartState::Stopped,
+        2 => HartState::StartPending,
+        3 => HartState::StopPending,
+        4 => HartState::Suspended,
+        5 => HartState::SuspendPending,
+        6 => HartState::ResumePending,
+        _ => return None,
+    })
+}
+
 #[inline]
 pub fn hart_suspend() -> bool
 {
@@ -75,6 +221,21 @@ pub fn send_ipi(hart_mask: usize)
     call(EID_SPI, SPI_FID_SEND_IPI, hart_mask, 0, 0);
 }
 
+/// Shut down or reboot every hart.
+///
+/// Only returns if the firmware lacks the SRST extension or refuses.
+#[inline]
+pub fn system_reset(reset_type: ResetType, reason: ResetReason)
+{
+    call(
+        EID_SRST,
+        SRST_FID_SYSTEM_RESET,
+        reset_type as usize,
+        reason as usize,
+        0,
+    );
+}
+
 #[inline]
 pub fn console_putchar(c: usize)
 {
@@ -87,3 +248,19 @@ pub fn console_getchar() -> isize
     let (val, _) = call(EID_CONSOLE_GETCHAR, 0, 0, 0, 0);
     val as isize
 }
+
+/// Firmware console, usable before the UART driver or the heap exist.
+///
+/// Bytes go straight to `console_putchar`, so writing never buffers, locks,
+/// or allocates.
+pub struct Console;
+
+impl fmt::Write for Console
+{
+    #[inline]
+    fn write_str(&mut self, s: &str) -> fmt::Result
+    {
+        s.bytes().for_each(|c| console_putchar(c as usize));
+        Ok(())
+    }
+}
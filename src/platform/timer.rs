@@ -1,17 +1,98 @@
 //! Timer and software-interrupt helpers for scheduler preemption.
+//!
+//! Ticks are programmed through `sbi::set_timer` unless [`select_path`] finds
+//! the Sstc `stimecmp` CSR usable, which saves an ecall per tick. The kernel
+//! only runs in S-mode, so writing the CLINT's `mtimecmp` is no substitute:
+//! it raises a machine timer interrupt that firmware never forwards.
 
-use spin::Once;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
-use crate::platform::sbi;
+use crate::{
+    arch::{self, cause::exceptions::ILLEGAL_INSTRUCTION},
+    interrupt::{self, fault},
+    platform::sbi,
+    sync::OnceLock,
+};
 
-static INTERVAL: Once<u64> = Once::new();
+static TIMEBASE_HZ: OnceLock<u64> = OnceLock::new();
+static INTERVAL: OnceLock<u64> = OnceLock::new();
+static PATH: OnceLock<TimerPath> = OnceLock::new();
+
+// One more than the logical ID of the hart probing `stimecmp`, or zero.
+static PROBING: AtomicUsize = AtomicUsize::new(0);
+static PROBE_FAULTED: AtomicUsize = AtomicUsize::new(0);
 
 const SIP_SSIP: usize = 1 << 1;
 
-pub fn init(timebase_hz: Option<u64>)
+/// Assumed when the device tree doesn't say: `$RISKY_TIMEBASE_HZ` at build
+/// time if set, for boards with a known clock, else QEMU `virt`'s 10 MHz.
+pub const DEFAULT_TIMEBASE_HZ: u64 = match option_env!("RISKY_TIMEBASE_HZ")
+{
+    Some(hz) => parse_hz(hz),
+    None => 10_000_000,
+};
+
+/// Parse a decimal frequency at compile time, rejecting anything else.
+const fn parse_hz(s: &str) -> u64
+{
+    let bytes = s.as_bytes();
+    assert!(!bytes.is_empty(), "RISKY_TIMEBASE_HZ is empty");
+
+    let mut hz: u64 = 0;
+    let mut i = 0;
+    while i < bytes.len()
+    {
+        let digit = bytes[i];
+        assert!(digit.is_ascii_digit(), "RISKY_TIMEBASE_HZ must be decimal");
+        hz = hz * 10 + (digit - b'0') as u64;
+        i += 1;
+    }
+
+    assert!(hz != 0, "RISKY_TIMEBASE_HZ must be non-zero");
+    hz
+}
+
+/// Scheduler ticks per second.
+pub const TICK_HZ: u64 = 100; // 10ms
+
+pub fn init(timebase_hz: u64)
+{
+    let timebase_hz = TIMEBASE_HZ.call_once(|| timebase_hz);
+    INTERVAL.call_once(|| timebase_hz / TICK_HZ);
+}
+
+/// `hz` if the device tree gave one, else [`DEFAULT_TIMEBASE_HZ`] with a
+/// warning, since every time conversion will be off if the guess is wrong.
+pub fn timebase_or_default(hz: Option<u64>) -> u64
+{
+    hz.unwrap_or_else(|| {
+        log::warn!(
+            "No timebase-frequency in the device tree, assuming {} Hz.",
+            DEFAULT_TIMEBASE_HZ
+        );
+        DEFAULT_TIMEBASE_HZ
+    })
+}
+
+/// Frequency of the `time` counter, once `init` has run.
+#[inline]
+pub fn timebase_hz() -> Option<u64>
+{
+    TIMEBASE_HZ.get().copied()
+}
+
+/// Raw value of the monotonic `time` counter, usable before `init`.
+#[inline]
+pub fn now() -> u64
+{
+    read_time()
+}
+
+/// Convert a `time` counter delta to microseconds, once the timebase is known.
+#[inline]
+pub fn ticks_to_us(ticks: u64) -> Option<u64>
 {
-    let tick_hz = 100; // 10ms
-    INTERVAL.call_once(|| timebase_hz.unwrap_or(10_000_000) / tick_hz);
+    timebase_hz().map(|hz| (ticks as u128 * 1_000_000 / hz as u128) as u64)
 }
 
 #[cfg(target_arch = "riscv64")]
@@ -38,11 +119,48 @@ fn read_time() -> u64
 pub mod ipi
 {
     use super::*;
+    use crate::{
+        mmio::{AccessStrategy, Register},
+        soc::aclint::SETSSIP_STRIDE,
+    };
+
+    static SSWI_BASE: OnceLock<Option<usize>> = OnceLock::new();
 
+    /// Use the ACLINT SSWI at `base`, if the platform has one, for IPIs.
+    #[inline]
+    pub fn init(sswi_base: Option<usize>)
+    {
+        SSWI_BASE.call_once(|| sswi_base);
+    }
+
+    /// Raise a supervisor software interrupt on another hart.
+    ///
+    /// Writes the ACLINT SSWI directly when available, which avoids an SBI
+    /// call; otherwise asks the firmware. Either way the target sees `SSIP`
+    /// and clears it through `sip`.
     #[inline]
     pub fn send(physical_hart_id: usize)
     {
-        sbi::send_ipi(1 << physical_hart_id);
+        match SSWI_BASE.get().copied().flatten()
+        {
+            Some(base) => send_direct(base, physical_hart_id),
+            None => sbi::send_ipi(1 << physical_hart_id),
+        }
+    }
+
+    #[inline]
+    fn send_direct(base: usize, physical_hart_id: usize)
+    {
+        let setssip = base + physical_hart_id * SETSSIP_STRIDE;
+        // Whatever the sender wrote must be visible before the target wakes.
+        Register::<u32>::new(setssip as _, AccessStrategy::Direct).write_ordered(1);
+    }
+
+    /// Raise a supervisor software interrupt on the current hart.
+    #[inline]
+    pub fn raise_local()
+    {
+        unsafe { csr_set_i!("sip", SIP_SSIP) }
     }
 
     #[inline]
@@ -52,9 +170,107 @@ pub mod ipi
     }
 }
 
+/// How [`schedule_next`] programs the next tick.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TimerPath
+{
+    /// Write `stimecmp` directly (Sstc).
+    Direct,
+    /// Ask the firmware through `sbi::set_timer`.
+    Sbi,
+}
+
+impl TimerPath
+{
+    /// The path to use given whether `stimecmp` can be accessed.
+    #[inline]
+    pub fn select(stimecmp_usable: bool) -> Self
+    {
+        if stimecmp_usable
+        {
+            Self::Direct
+        }
+        else
+        {
+            Self::Sbi
+        }
+    }
+}
+
+/// The path chosen by [`select_path`]; SBI until then.
+#[inline]
+pub fn path() -> TimerPath
+{
+    PATH.get().copied().unwrap_or(TimerPath::Sbi)
+}
+
+/// Probe for `stimecmp` once and cache the path every hart will use.
+///
+/// Needs this hart's trap handling up. Harts are assumed alike: firmware
+/// enables Sstc for all of them or none.
+pub fn select_path() -> TimerPath
+{
+    *PATH.call_once(|| TimerPath::select(stimecmp_usable()))
+}
+
+/// Whether reading `stimecmp` works here, rather than trapping because the
+/// hart lacks Sstc or firmware left it disabled.
+pub fn stimecmp_usable() -> bool
+{
+    interrupt::with_disabled(|| {
+        PROBE_FAULTED.store(0, Ordering::Relaxed);
+        PROBING.store(arch::logical_hart_id() + 1, Ordering::Relaxed);
+        let previous = fault::register_handler(ILLEGAL_INSTRUCTION, skip_probe);
+
+        unsafe { csr_read!("0x14d") };
+
+        match previous
+        {
+            Some(handler) => fault::register_handler(ILLEGAL_INSTRUCTION, handler),
+            None => fault::unregister_handler(ILLEGAL_INSTRUCTION),
+        };
+        PROBING.store(0, Ordering::Relaxed);
+        PROBE_FAULTED.load(Ordering::Relaxed) == 0
+    })
+}
+
+// Only the probing hart's fault is expected; any other stays fatal.
+fn skip_probe(_addr: usize, _epc: usize) -> fault::FaultAction
+{
+    if PROBING.load(Ordering::Relaxed) != arch::logical_hart_id() + 1
+    {
+        return fault::FaultAction::Fatal;
+    }
+    PROBE_FAULTED.store(1, Ordering::Relaxed);
+    fault::FaultAction::Skip
+}
+
 #[inline]
 pub fn schedule_next()
 {
-    let now = read_time();
-    sbi::set_timer(now + INTERVAL.wait());
+    let next = read_time() + INTERVAL.wait();
+    match path()
+    {
+        TimerPath::Direct => write_stimecmp(next),
+        TimerPath::Sbi => sbi::set_timer(next),
+    }
+}
+
+#[cfg(target_arch = "riscv64")]
+#[inline]
+fn write_stimecmp(value: u64)
+{
+    unsafe { csr_write!("0x14d", value as usize) }
+}
+
+/// Park the low half at its maximum while the high half changes, so no
+/// intermediate deadline fires early.
+#[cfg(target_arch = "riscv32")]
+fn write_stimecmp(value: u64)
+{
+    unsafe {
+        csr_write!("0x14d", usize::MAX);
+        csr_write!("0x15d", (value >> 32) as usize);
+        csr_write!("0x14d", value as usize);
+    }
 }
@@ -0,0 +1,3784 @@
+//! In-kernel self-tests for CI, enabled by the `selftest` feature.
+//!
+//! Instead of the demo tasks, hart 0 spawns a runner that executes every test
+//! in `TESTS` as an ordinary task, reports each result, and shuts the machine
+//! down through SBI. A failure is reported as a system failure, which QEMU
+//! surfaces as a non-zero exit status.
+
+use alloc::{boxed::Box, vec, vec::Vec};
+use core::{
+    hint::{black_box, spin_loop},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{
+    arch::{self, CPU_VEC, Cpu},
+    boot,
+    collections::RingBuffer,
+    console,
+    crash::{self, PanicPolicy},
+    fdt, interrupt,
+    platform::{
+        sbi::{self, HartState, ResetReason, ResetType},
+        timer,
+    },
+    sync::{self, Mutex},
+    task::{self, MigrateError, Priority, SchedStats, Task, TaskId, TaskState, TrapContext},
+};
+
+type Outcome = Result<(), &'static str>;
+type SelfTest = (&'static str, fn() -> Outcome);
+
+const TESTS: &[SelfTest] = &[
+    ("heap round-trip", heap_round_trip),
+    ("ring buffer order", ring_buffer_order),
+    ("hart map", hart_map),
+    ("mutex across harts", mutex_across_harts),
+    ("nested critical sections", nested_critical_sections),
+    ("scheduler stats", scheduler_stats),
+    ("hart start retry", hart_start_retry),
+    ("futex wait/wake", futex_wait_wake),
+    ("cached task ID", cached_task_id),
+    ("idle only when nothing runs", idle_starved_while_busy),
+    ("heap span validation", heap_span_validation),
+    ("interrupt context detection", interrupt_context_detection),
+    ("console flush", console_flush),
+    ("separate UART instances", separate_uart_instances),
+    ("task migration", task_migration),
+    ("suspended spawn", suspended_spawn),
+    ("IRQ routing", irq_routing),
+    ("batch spawn", batch_spawn),
+    ("watchdog", watchdog_flags_stall),
+    ("register block copies", register_block_copies),
+    ("UART IRQ on one hart", uart_irq_one_hart),
+    ("timer path selection", timer_path_selection),
+    ("task panic boundary", task_panic_boundary),
+    ("heap avoids reserved memory", heap_avoids_reserved_memory),
+    ("load rebalancing", load_rebalancing),
+    ("formatted console output", formatted_console_output),
+    ("CPU budget overrun", cpu_budget_overrun),
+    ("hart ID accessors agree", hart_id_accessors),
+    ("last task exit goes idle", last_exit_goes_idle),
+    ("spawn returns task ID", spawn_returns_id),
+    ("print into a held console", print_into_held_console),
+    ("memory map layout", memory_map_layout),
+    ("semaphore FIFO wake order", semaphore_fifo),
+    ("ELF image validation", elf_image_validation),
+    ("spawn a bare function", spawn_bare_fn),
+    ("joiner sees exit code", join_exit_code),
+    ("higher band runs first", priority_bands),
+    ("yield alternation", yield_alternation),
+    ("corrupt contexts caught", corrupt_context_caught),
+    ("instruction length", instruction_length),
+    ("hart map set once", hart_map_set_once),
+    ("call_once runs once", call_once_runs_once),
+    ("Cpu::get on every hart", cpu_get_every_hart),
+    ("interrupt state per task", interrupt_state_per_task),
+    ("stacks outlive their tasks", stacks_outlive_tasks),
+    ("missing timebase", missing_timebase),
+    ("no tasks before hart setup", no_tasks_before_ready),
+    ("channel receive with timeout", channel_recv_timeout),
+    ("device tree dump", device_tree_dump),
+    ("heap poison", heap_poison),
+    ("panic while locked poisons", panic_poisons_lock),
+    ("preemption section holds", preempt_section_holds),
+    ("trace ring dump", trace_ring_dump),
+    ("held locks follow their task", held_locks_per_task),
+    ("low band ages past high load", low_band_ages),
+    ("zeroed and resized blocks", heap_zeroed_and_resized),
+    ("fault handler retry and skip", fault_retry_skip),
+    ("ticket lock grants in FIFO order", ticket_fifo_order),
+    ("RNG seeding", rng_seeding),
+    ("RAM disk transfers", ram_disk_transfers),
+    ("VirtIO ring and probe", virtio_ring_and_probe),
+    ("large task stack", large_task_stack),
+    ("trap stack canary", trap_stack_canary),
+    ("deferred work on the worker", deferred_on_worker),
+    ("timer interrupts counted", timer_interrupts_counted),
+    ("idle hook only when idle", idle_hook_only_idle),
+    ("nested boot phases", nested_boot_phases),
+    ("placement policies", placement_policies),
+    ("PLIC context and enable math", plic_math),
+    ("trap cause names", trap_cause_names),
+    ("malformed device trees rejected", malformed_device_trees),
+    ("log colors", log_colors),
+    ("boot banner decoding", boot_banner_decoding),
+    ("hart loads", hart_loads_reported),
+];
+
+/// Spawn the test runner.
+#[inline]
+pub fn spawn()
+{
+    // A panicking test must still end the run, as a failure.
+    crash::set_policy(PanicPolicy::Shutdown);
+    Task::spawn(run);
+}
+
+fn run()
+{
+    // Nor may a panic in a test be contained to the runner.
+    task::set_panic_fatal(true);
+
+    let mut failed = 0;
+    for (name, test) in TESTS
+    {
+        match test()
+        {
+            Ok(()) => log::info!("selftest {name}: ok"),
+            Err(why) =>
+            {
+                log::error!("selftest {name}: FAILED ({why})");
+                failed += 1;
+            }
+        }
+    }
+
+    log::info!("selftest: {} passed, {failed} failed", TESTS.len() - failed);
+
+    let reason = if failed == 0
+    {
+        ResetReason::NoReason
+    }
+    else
+    {
+        ResetReason::SystemFailure
+    };
+    console::flush();
+    sbi::system_reset(ResetType::Shutdown, reason);
+
+    log::error!("selftest: SBI system reset unavailable");
+}
+
+fn check(cond: bool, why: &'static str) -> Outcome
+{
+    if cond { Ok(()) } else { Err(why) }
+}
+
+fn heap_round_trip() -> Outcome
+{
+    for size in [1, 64, 4096, 64 * 1024]
+    {
+        let block = black_box(alloc::vec![0xa5_u8; size].into_boxed_slice());
+        check(block.iter().all(|&b| b == 0xa5), "block contents changed")?;
+    }
+
+    let values = (0..10_000).collect::<Vec<usize>>();
+    check(
+        values.iter().enumerate().all(|(i, &v)| i == v),
+        "vector contents changed",
+    )?;
+
+    let boxed = Box::new([0_u64; 32]);
+    check(boxed.as_ptr().is_aligned(), "misaligned allocation")
+}
+
+fn ring_buffer_order() -> Outcome
+{
+    let mut ring = RingBuffer::<usize, 4>::new();
+    for i in 0..4
+    {
+        check(ring.push(i).is_ok(), "push into non-full ring failed")?;
+    }
+    check(ring.push(4) == Err(4), "push into full ring succeeded")?;
+    check(ring.overruns() == 1, "overrun not counted")?;
+
+    for i in 0..4
+    {
+        check(ring.pop() == Some(i), "values out of order")?;
+    }
+    check(ring.pop().is_none(), "empty ring returned a value")
+}
+
+fn hart_map() -> Outcome
+{
+    let cpus = CPU_VEC.wait();
+    check(!cpus.is_empty(), "no harts")?;
+
+    for cpu in cpus.iter()
+    {
+        check(
+            fdt::harts::try_to_logical(cpu.physical_id) == Some(cpu.logical_id),
+            "physical ID doesn't map back to its logical ID",
+        )?;
+    }
+
+    check(timer::timebase_hz().is_some(), "timebase unknown")
+}
+
+fn mutex_across_harts() -> Outcome
+{
+    const ROUNDS: usize = 1000;
+
+    static COUNTER: Mutex<usize> = Mutex::new(0);
+    static FINISHED: AtomicUsize = AtomicUsize::new(0);
+
+    // Default placement spreads these over every hart.
+    let workers = CPU_VEC.wait().len() * 2;
+    for _ in 0..workers
+    {
+        Task::spawn(|| {
+            for _ in 0..ROUNDS
+            {
+                interrupt::with_disabled(|| {
+                    let mut counter = COUNTER.lock();
+                    // Split so that a second holder would lose updates.
+                    let value = black_box(*counter);
+                    *counter = value + 1;
+                });
+            }
+            FINISHED.fetch_add(1, Ordering::Release);
+        });
+    }
+
+    while FINISHED.load(Ordering::Acquire) < workers
+    {
+        task::yield_now();
+    }
+
+    let total = interrupt::with_disabled(|| *COUNTER.lock());
+    check(total == workers * ROUNDS, "lost updates")
+}
+
+fn nested_critical_sections() -> Outcome
+{
+    let was_enabled = interrupt::is_enabled();
+
+    critical_section::with(|_| {
+        check(!interrupt::is_enabled(), "outer section left interrupts on")?;
+        critical_section::with(|_| {
+            check(!interrupt::is_enabled(), "inner section left interrupts on")
+        })?;
+        check(
+            !interrupt::is_enabled(),
+            "inner release re-enabled interrupts",
+        )
+    })?;
+
+    check(
+        interrupt::is_enabled() == was_enabled,
+        "outer release didn't restore interrupts",
+    )
+}
+
+fn scheduler_stats() -> Outcome
+{
+    const YIELDS: usize = 50;
+    const SPIN_TICKS: u64 = 5;
+
+    static FINISHED: AtomicUsize = AtomicUsize::new(0);
+
+    // Placement is up to the scheduler, so compare totals over every hart.
+    let total = || {
+        (0..CPU_VEC.wait().len())
+            .filter_map(task::stats)
+            .fold(SchedStats::default(), |a, b| SchedStats {
+                switches: a.switches + b.switches,
+                preemptions: a.preemptions + b.preemptions,
+                yields: a.yields + b.yields,
+                idle_ticks: a.idle_ticks + b.idle_ticks,
+                busy_ticks: a.busy_ticks + b.busy_ticks,
+                budget_overruns: a.budget_overruns + b.budget_overruns,
+            })
+    };
+    let before = total();
+
+    Task::spawn(|| {
+        for _ in 0..YIELDS
+        {
+            task::yield_now();
+        }
+        FINISHED.fetch_add(1, Ordering::Release);
+    });
+    Task::spawn(|| {
+        let tick = timer::timebase_hz().unwrap_or(timer::DEFAULT_TIMEBASE_HZ) / 100;
+        let deadline = timer::now() + tick * SPIN_TICKS;
+        while timer::now() < deadline
+        {
+            spin_loop();
+        }
+        FINISHED.fetch_add(1, Ordering::Release);
+    });
+
+    while FINISHED.load(Ordering::Acquire) < 2
+    {
+        task::yield_now();
+    }
+
+    let after = total();
+    check(after.yields - before.yields >= YIELDS, "yields not counted")?;
+    check(after.switches > before.switches, "switches not counted")?;
+    check(
+        after.busy_ticks - before.busy_ticks >= SPIN_TICKS as usize - 1,
+        "busy ticks not counted",
+    )?;
+    check(
+        after.preemptions - before.preemptions <= after.switches - before.switches,
+        "more preemptions than switches",
+    )
+}
+
+fn hart_start_retry() -> Outcome
+{
+    use core::cell::Cell;
+
+    // Fails once, then starts.
+    let calls = Cell::new(0);
+    let started = boot::start_with_retry(
+        || {
+            calls.set(calls.get() + 1);
+            calls.get() > 1
+        },
+        || Some(HartState::Stopped),
+    );
+    check(started && calls.get() == 2, "flaky start not retried")?;
+
+    // Reports failure, but the hart is coming up anyway.
+    let calls = Cell::new(0);
+    let started = boot::start_with_retry(
+        || {
+            calls.set(calls.get() + 1);
+            false
+        },
+        || Some(HartState::StartPending),
+    );
+    check(started && calls.get() == 1, "pending hart not accepted")?;
+
+    // Never starts.
+    let calls = Cell::new(0);
+    let started = boot::start_with_retry(
+        || {
+            calls.set(calls.get() + 1);
+            false
+        },
+        || None,
+    );
+    check(
+        !started && calls.get() == boot::HART_START_ATTEMPTS,
+        "dead hart not given up on",
+    )?;
+
+    check(
+        arch::online_cpus().count() == CPU_VEC.wait().len(),
+        "a hart failed to start",
+    )
+}
+
+fn futex_wait_wake() -> Outcome
+{
+    static FLAG: AtomicUsize = AtomicUsize::new(0);
+    static WAITING: AtomicUsize = AtomicUsize::new(0);
+    static DONE: AtomicUsize = AtomicUsize::new(0);
+
+    check(!sync::futex_wait(&FLAG, 1), "waited on a stale value")?;
+
+    Task::spawn(|| {
+        WAITING.store(1, Ordering::Release);
+        while FLAG.load(Ordering::Acquire) == 0
+        {
+            sync::futex_wait(&FLAG, 0);
+        }
+        DONE.store(1, Ordering::Release);
+    });
+
+    while WAITING.load(Ordering::Acquire) == 0
+    {
+        task::yield_now();
+    }
+    check(
+        DONE.load(Ordering::Acquire) == 0,
+        "waiter ran ahead of the flag",
+    )?;
+
+    FLAG.store(1, Ordering::Release);
+    sync::futex_wake(&FLAG, usize::MAX);
+
+    while DONE.load(Ordering::Acquire) == 0
+    {
+        task::yield_now();
+    }
+    check(
+        sync::futex_wake(&FLAG, usize::MAX) == 0,
+        "waiter left queued after waking",
+    )
+}
+
+fn cached_task_id() -> Outcome
+{
+    static MISMATCHES: AtomicUsize = AtomicUsize::new(0);
+    static FINISHED: AtomicUsize = AtomicUsize::new(0);
+
+    let matches_scheduler = || {
+        interrupt::with_disabled(|| {
+            Cpu::get().scheduler.lock().task().id == arch::current_task_id()
+        })
+    };
+
+    // Tasks that keep yielding to each other, so every check follows a switch.
+    let workers = CPU_VEC.wait().len() * 2;
+    for _ in 0..workers
+    {
+        Task::spawn(move || {
+            for _ in 0..20
+            {
+                if !matches_scheduler()
+                {
+                    MISMATCHES.fetch_add(1, Ordering::Relaxed);
+                }
+                task::yield_now();
+            }
+            FINISHED.fetch_add(1, Ordering::Release);
+        });
+    }
+
+    while FINISHED.load(Ordering::Acquire) < workers
+    {
+        check(matches_scheduler(), "runner's cached ID is stale")?;
+        task::yield_now();
+    }
+
+    check(
+        MISMATCHES.load(Ordering::Relaxed) == 0,
+        "cached ID differs from the scheduler's",
+    )
+}
+
+fn idle_starved_while_busy() -> Outcome
+{
+    const BUSY_TICKS: u64 = 5;
+
+    static STARTED: AtomicUsize = AtomicUsize::new(0);
+    static STOP: AtomicUsize = AtomicUsize::new(0);
+    static FINISHED: AtomicUsize = AtomicUsize::new(0);
+
+    let idle_ticks = || {
+        (0..CPU_VEC.wait().len())
+            .filter_map(task::stats)
+            .map(|stats| stats.idle_ticks)
+            .sum::<usize>()
+    };
+
+    // Round-robin placement puts two spinners on every hart.
+    task::set_placement(task::Placement::RoundRobin);
+    let workers = arch::online_cpus().count() * 2;
+    for _ in 0..workers
+    {
+        Task::spawn(|| {
+            STARTED.fetch_add(1, Ordering::Release);
+            while STOP.load(Ordering::Acquire) == 0
+            {
+                spin_loop();
+            }
+            FINISHED.fetch_add(1, Ordering::Release);
+        });
+    }
+
+    while STARTED.load(Ordering::Acquire) < workers
+    {
+        task::yield_now();
+    }
+
+    let before = idle_ticks();
+    let tick = timer::timebase_hz().unwrap_or(timer::DEFAULT_TIMEBASE_HZ) / 100;
+    let deadline = timer::now() + tick * BUSY_TICKS;
+    while timer::now() < deadline
+    {
+        task::yield_now();
+    }
+    let after = idle_ticks();
+
+    STOP.store(1, Ordering::Release);
+    while FINISHED.load(Ordering::Acquire) < workers
+    {
+        task::yield_now();
+    }
+
+    check(after == before, "idle ran while tasks were runnable")
+}
+
+fn heap_span_validation() -> Outcome
+{
+    use alloc::string::ToString;
+
+    use crate::memory::heap::{self, SpanError};
+
+    // 256 KiB of RAM, kernel image in the first 64 KiB, FDT in the last page.
+    let ram = 0x8000_0000..0x8004_0000;
+    let kernel = 0x8000_0000..0x8001_0000;
+    let fdt = 0x8003_f000..0x8004_0000;
+    let heap_at = |start: usize, size: usize| start..start + size;
+
+    check(
+        heap::check_span(&heap_at(0x8001_0000, 0x2_0000), &ram, &kernel, &fdt).is_ok(),
+        "valid span rejected",
+    )?;
+
+    // The default 512 KiB initial heap can't fit in this RAM at all.
+    let overflow = heap::check_span(&heap_at(0x8001_0000, 512 * 1024), &ram, &kernel, &fdt);
+    check(
+        overflow == Err(SpanError::OutsideRam),
+        "overflow past RAM accepted",
+    )?;
+    check(
+        overflow.unwrap_err().to_string() == "runs past the end of RAM",
+        "unclear overflow diagnostic",
+    )?;
+
+    check(
+        heap::check_span(&heap_at(0x8000_f000, 0x2000), &ram, &kernel, &fdt)
+            == Err(SpanError::OverlapsKernel),
+        "kernel overlap accepted",
+    )?;
+    check(
+        heap::check_span(&heap_at(0x8003_0000, 0x1_0000), &ram, &kernel, &fdt)
+            == Err(SpanError::OverlapsFdt),
+        "FDT overlap accepted",
+    )
+}
+
+fn interrupt_context_detection() -> Outcome
+{
+    check(!arch::in_interrupt(), "task context seen as a trap")?;
+    check(task::may_block(), "task context may not block")?;
+
+    // Stand in for `trap_handler`; a real trap can't run a check and return
+    // here to report it.
+    let (in_trap, may_block) = interrupt::with_disabled(|| {
+        let cpu = Cpu::get();
+        cpu.trap_depth.fetch_add(1, Ordering::Relaxed);
+        let seen = (arch::in_interrupt(), task::may_block());
+        cpu.trap_depth.fetch_sub(1, Ordering::Relaxed);
+        seen
+    });
+    check(in_trap, "simulated trap not detected")?;
+    check(!may_block, "blocking allowed inside a trap")?;
+
+    check(!arch::in_interrupt(), "trap depth left raised")
+}
+
+fn console_flush() -> Outcome
+{
+    use crate::drivers::uart;
+
+    // Skip any capture sink so the line goes through the UART's TX ring.
+    let sink = console::reset_sink();
+    crate::println!("selftest: flushing the console");
+    console::flush();
+    let pending = uart::tx_pending();
+    if let Some(sink) = sink
+    {
+        console::set_sink(sink);
+    }
+
+    check(pending == 0, "bytes left in the TX ring after flush")
+}
+
+fn separate_uart_instances() -> Outcome
+{
+    use core::fmt::Write;
+
+    use crate::{
+        drivers::uart::Uart,
+        fdt::uart::UartInfo,
+        mmio::IoWidth,
+        soc::uart::{THR_OFFSET, lsr},
+    };
+
+    // RAM standing in for two register blocks, both reporting an idle
+    // transmitter so writes go straight through.
+    let mut regs = [[0_u8; 8]; 2];
+    for block in &mut regs
+    {
+        block[lsr::ADDR_OFFSET] = lsr::TX_IDLE | lsr::TX_EMPTY;
+    }
+
+    let mut uarts = regs.each_mut().map(|block| {
+        Uart::with_info(UartInfo {
+            base: block.as_mut_ptr() as usize,
+            shift: 0,
+            width: IoWidth::U8,
+            irq: None,
+        })
+    });
+
+    let _ = uarts[0].write_str("a");
+    let _ = uarts[1].write_str("b");
+    let _ = uarts[0].write_str("c");
+
+    let thr = regs.map(|block| unsafe { (&raw const block[THR_OFFSET]).read_volatile() });
+    check(thr == *b"cb", "write landed on the wrong instance")
+}
+
+fn task_migration() -> Outcome
+{
+    const UNSET: usize = usize::MAX;
+
+    static TASK_ID: AtomicUsize = AtomicUsize::new(UNSET);
+    static GO: AtomicUsize = AtomicUsize::new(0);
+    static RAN_ON: AtomicUsize = AtomicUsize::new(UNSET);
+
+    let me = Cpu::get().logical_id;
+    let Some(target) = arch::online_cpus()
+        .map(|cpu| cpu.logical_id)
+        .find(|&id| id != me)
+    else
+    {
+        // Nowhere to migrate to.
+        return Ok(());
+    };
+
+    // Keep the task on this hart, behind us in the run queue.
+    let placement = task::placement();
+    task::set_placement(task::Placement::Local);
+    Task::spawn(|| {
+        TASK_ID.store(arch::current_task_id().get(), Ordering::Release);
+        while GO.load(Ordering::Acquire) == 0
+        {
+            task::yield_now();
+        }
+        RAN_ON.store(Cpu::get().logical_id, Ordering::Release);
+    });
+    task::set_placement(placement);
+
+    while TASK_ID.load(Ordering::Acquire) == UNSET
+    {
+        task::yield_now();
+    }
+    let id = TaskId::from_raw(TASK_ID.load(Ordering::Acquire));
+
+    check(
+        task::migrate_task(id, usize::MAX) == Err(MigrateError::NoSuchHart),
+        "migrated to a missing hart",
+    )?;
+    check(
+        task::migrate_task(arch::current_task_id(), target) == Err(MigrateError::Running),
+        "migrated a running task",
+    )?;
+    // We are running, so the task is waiting behind us.
+    check(task::migrate_task(id, target).is_ok(), "migration refused")?;
+
+    GO.store(1, Ordering::Release);
+    while RAN_ON.load(Ordering::Acquire) == UNSET
+    {
+        task::yield_now();
+    }
+    check(
+        RAN_ON.load(Ordering::Acquire) == target,
+        "task didn't run on the target hart",
+    )
+}
+
+fn suspended_spawn() -> Outcome
+{
+    static RAN: AtomicUsize = AtomicUsize::new(0);
+
+    let handle = Task::spawn_suspended(|| {
+        RAN.fetch_add(1, Ordering::Release);
+    });
+
+    // Plenty of chances to run, on whichever hart it landed.
+    let tick = timer::timebase_hz().unwrap_or(timer::DEFAULT_TIMEBASE_HZ) / 100;
+    let deadline = timer::now() + tick * 3;
+    while timer::now() < deadline
+    {
+        task::yield_now();
+    }
+    check(RAN.load(Ordering::Acquire) == 0, "suspended task ran")?;
+
+    handle.resume();
+    while RAN.load(Ordering::Acquire) == 0
+    {
+        task::yield_now();
+    }
+
+    // It has run (and exited) already; resuming again changes nothing.
+    handle.resume();
+    check(RAN.load(Ordering::Acquire) == 1, "task ran more than once")
+}
+
+fn irq_routing() -> Outcome
+{
+    use crate::platform::plic;
+
+    // Unused on QEMU `virt`: the UART is 10, virtio 1..=8, PCIe 32..=35.
+    const IRQ_A: u32 = 20;
+    const IRQ_B: u32 = 21;
+
+    static HANDLED_A: AtomicUsize = AtomicUsize::new(0);
+    static HANDLED_B: AtomicUsize = AtomicUsize::new(0);
+
+    let mut harts = arch::online_cpus();
+    let (Some(first), Some(second)) = (harts.next(), harts.next())
+    else
+    {
+        // Nothing to route between.
+        return Ok(());
+    };
+    let (hart_a, hart_b) = (first.logical_id, second.logical_id);
+
+    plic::route(IRQ_A, hart_a, |_| {
+        HANDLED_A.fetch_add(1, Ordering::Relaxed);
+    });
+    plic::route(IRQ_B, hart_b, |_| {
+        HANDLED_B.fetch_add(1, Ordering::Relaxed);
+    });
+
+    let enabled = |cpu: &Cpu, irq| plic::is_enabled(cpu.physical_id, irq);
+    let result = check(
+        enabled(first, IRQ_A) && !enabled(first, IRQ_B),
+        "first hart's enables are wrong",
+    )
+    .and_then(|()| {
+        check(
+            enabled(second, IRQ_B) && !enabled(second, IRQ_A),
+            "second hart's enables are wrong",
+        )
+    })
+    .and_then(|()| {
+        check(
+            plic::deliver(IRQ_A, hart_a) && !plic::deliver(IRQ_B, hart_a),
+            "first hart handled the wrong source",
+        )
+    })
+    .and_then(|()| {
+        check(
+            plic::deliver(IRQ_B, hart_b) && !plic::deliver(IRQ_A, hart_b),
+            "second hart handled the wrong source",
+        )
+    })
+    .and_then(|()| {
+        check(
+            HANDLED_A.load(Ordering::Relaxed) == 1 && HANDLED_B.load(Ordering::Relaxed) == 1,
+            "handlers ran the wrong number of times",
+        )
+    });
+
+    plic::unroute(IRQ_A, hart_a);
+    plic::unroute(IRQ_B, hart_b);
+    result
+}
+
+fn batch_spawn() -> Outcome
+{
+    use alloc::sync::Arc;
+
+    const TASKS: usize = 100;
+
+    let harts = CPU_VEC.wait().len();
+    let online = arch::online_cpus().count();
+    let ran_on = Arc::<[AtomicUsize]>::from_iter((0..harts).map(|_| AtomicUsize::new(0)));
+
+    let placement = task::placement();
+    task::set_placement(task::Placement::RoundRobin);
+    let woken = Task::spawn_batch((0..TASKS).map(|_| {
+        let ran_on = ran_on.clone();
+        move || {
+            ran_on[Cpu::get().logical_id].fetch_add(1, Ordering::Relaxed);
+        }
+    }));
+    task::set_placement(placement);
+
+    check(woken <= online, "more than one IPI per hart")?;
+
+    let total = || {
+        ran_on
+            .iter()
+            .map(|n| n.load(Ordering::Relaxed))
+            .sum::<usize>()
+    };
+    while total() < TASKS
+    {
+        task::yield_now();
+    }
+
+    // Round-robin spreads them evenly over the harts that are up.
+    let (low, high) = (TASKS / online, TASKS.div_ceil(online));
+    check(
+        arch::online_cpus()
+            .map(|cpu| ran_on[cpu.logical_id].load(Ordering::Relaxed))
+            .all(|n| (low..=high).contains(&n)),
+        "uneven distribution",
+    )
+}
+
+fn watchdog_flags_stall() -> Outcome
+{
+    use crate::watchdog;
+
+    check(timer::timebase_hz().is_some(), "timebase unknown")?;
+    let timeout = timer::timebase_hz().unwrap() * watchdog::TIMEOUT_US / 1_000_000;
+
+    // Masked, so no real tick refreshes the heartbeat under us.
+    interrupt::with_disabled(|| {
+        let cpu = Cpu::get();
+        let saved = cpu.heartbeat.last();
+
+        check(!watchdog::is_stalled(cpu), "live hart reported stalled")?;
+
+        let stale = timer::now().wrapping_sub(timeout * 2) as usize;
+        cpu.heartbeat.set_last(stale.max(1));
+        let stalled = watchdog::is_stalled(cpu);
+        cpu.heartbeat.set_last(saved);
+
+        check(stalled, "stalled heartbeat not flagged")
+    })
+}
+
+fn register_block_copies() -> Outcome
+{
+    use crate::mmio::{AccessStrategy, IoWidth, Register};
+
+    // Same address: every element goes through one register.
+    let mut fifo = 0_u8;
+    let reg = Register::new(&raw mut fifo, AccessStrategy::Direct);
+    reg.write_n(b"abc");
+    let last = unsafe { (&raw const fifo).read_volatile() };
+    check(last == b'c', "FIFO write did not end on the last byte")?;
+    let mut read = [0; 3];
+    reg.read_n(&mut read);
+    check(read == [b'c'; 3], "FIFO read did not repeat the register")?;
+
+    // Strided: byte registers spaced a word apart, as with `reg-shift = 2`.
+    let mut words = [0_u32; 4];
+    let block = &raw mut words;
+    let reg = Register::new(
+        block.cast::<u8>(),
+        AccessStrategy::Shifted {
+            width: IoWidth::U32,
+        },
+    );
+    reg.write_strided(&[1, 2, 3, 4], 4);
+    let written = unsafe { block.read_volatile() };
+    check(
+        written == [1, 2, 3, 4],
+        "strided write landed in the wrong slots",
+    )?;
+
+    unsafe { block.write_volatile([5, 6, 7, 8]) };
+    let mut read = [0; 4];
+    reg.read_strided(&mut read, 4);
+    check(
+        read == [5, 6, 7, 8],
+        "strided read returned the wrong slots",
+    )
+}
+
+fn uart_irq_one_hart() -> Outcome
+{
+    use crate::{drivers::uart, platform::plic};
+
+    let Some(irq) = uart::UART
+        .get()
+        .and_then(|uart| interrupt::with_disabled(|| uart.lock().irq()))
+    else
+    {
+        // No interrupt to route.
+        return Ok(());
+    };
+
+    let harts_with_irq = || {
+        arch::online_cpus()
+            .filter(|cpu| plic::is_enabled(cpu.physical_id, irq))
+            .map(|cpu| cpu.logical_id)
+            .collect::<Vec<_>>()
+    };
+    check(
+        harts_with_irq() == [uart::IRQ_HART],
+        "UART IRQ not on its hart alone after boot",
+    )?;
+
+    // Routed somewhere else first, to check the exclusive route takes it away.
+    let mut harts = arch::online_cpus().map(|cpu| cpu.logical_id);
+    let first = harts.next().unwrap();
+    let designated = harts.last().unwrap_or(first);
+
+    plic::route(irq, first, |_| {});
+    plic::route_exclusive(irq, designated, |_| {});
+    let routed = harts_with_irq();
+    plic::unroute(irq, designated);
+    uart::route_irq();
+
+    check(
+        routed == [designated],
+        "UART IRQ enabled outside the designated hart",
+    )?;
+    check(
+        harts_with_irq() == [uart::IRQ_HART],
+        "UART IRQ not routed back",
+    )
+}
+
+fn timer_path_selection() -> Outcome
+{
+    use crate::platform::timer::TimerPath;
+
+    check(
+        TimerPath::select(true) == TimerPath::Direct,
+        "usable stimecmp not chosen",
+    )?;
+    check(
+        TimerPath::select(false) == TimerPath::Sbi,
+        "SBI not chosen without stimecmp",
+    )?;
+
+    // The cached choice must match what the hart can actually do.
+    check(
+        timer::path() == TimerPath::select(timer::stimecmp_usable()),
+        "cached timer path disagrees with a fresh probe",
+    )
+}
+
+fn task_panic_boundary() -> Outcome
+{
+    static REACHED: AtomicUsize = AtomicUsize::new(0);
+    static SURVIVOR_RAN: AtomicUsize = AtomicUsize::new(0);
+
+    Task::spawn(|| {
+        REACHED.store(1, Ordering::Release);
+        if black_box(true)
+        {
+            panic!("selftest: deliberate task panic");
+        }
+        REACHED.store(2, Ordering::Release);
+    });
+    while REACHED.load(Ordering::Acquire) == 0
+    {
+        task::yield_now();
+    }
+
+    Task::spawn(|| {
+        SURVIVOR_RAN.store(1, Ordering::Release);
+    });
+    while SURVIVOR_RAN.load(Ordering::Acquire) == 0
+    {
+        task::yield_now();
+    }
+
+    check(
+        REACHED.load(Ordering::Acquire) == 1,
+        "panicking task kept running",
+    )?;
+    check(!crash::is_stopping(), "task panic stopped the system")
+}
+
+/// Just enough of a flattened device tree writer to describe memory layouts.
+#[derive(Default)]
+struct DtbWriter
+{
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+}
+
+impl DtbWriter
+{
+    fn token(&mut self, token: u32)
+    {
+        self.structure.extend(token.to_be_bytes());
+    }
+
+    fn pad(&mut self)
+    {
+        while !self.structure.len().is_multiple_of(4)
+        {
+            self.structure.push(0);
+        }
+    }
+
+    fn begin(&mut self, name: &str)
+    {
+        self.token(1);
+        self.structure.extend(name.bytes().chain([0]));
+        self.pad();
+    }
+
+    fn prop(&mut self, name: &str, value: &[u8])
+    {
+        self.token(3);
+        self.token(value.len() as u32);
+        self.token(self.strings.len() as u32);
+        self.strings.extend(name.bytes().chain([0]));
+        self.structure.extend(value);
+        self.pad();
+    }
+
+    fn cells(&mut self, name: &str, cells: &[u32])
+    {
+        let value = cells
+            .iter()
+            .flat_map(|c| c.to_be_bytes())
+            .collect::<Vec<_>>();
+        self.prop(name, &value);
+    }
+
+    fn end(&mut self)
+    {
+        self.token(2);
+    }
+
+    /// The finished blob, in words so it is suitably aligned.
+    fn finish(mut self) -> Vec<u64>
+    {
+        const HEADER: usize = 40;
+        const RSVMAP: usize = 16; // Just the terminating empty entry
+
+        self.token(9);
+        let off_struct = HEADER + RSVMAP;
+        let off_strings = off_struct + self.structure.len();
+        let total = off_strings + self.strings.len();
+
+        let header = [
+            0xd00d_feed,
+            total,
+            off_struct,
+            off_strings,
+            HEADER,
+            17, // Version
+            16, // Last compatible version
+            0,
+            self.strings.len(),
+            self.structure.len(),
+        ];
+        let bytes = header
+            .iter()
+            .flat_map(|&field| (field as u32).to_be_bytes())
+            .chain([0; RSVMAP])
+            .chain(self.structure)
+            .chain(self.strings)
+            .collect::<Vec<_>>();
+
+        let mut words = alloc::vec![0_u64; total.div_ceil(8)];
+        unsafe { core::ptr::copy_nonoverlapping(bytes.as_ptr(), words.as_mut_ptr().cast(), total) };
+        words
+    }
+}
+
+fn heap_avoids_reserved_memory() -> Outcome
+{
+    use crate::memory::{heap, pmm::BitmapAlloc};
+
+    const RAM: (usize, usize) = (0x8000_0000, 0x10_0000);
+    const RESERVED: (usize, usize) = (0x8001_0000, 0x4000);
+    const PAGES: usize = RAM.1 / arch::PAGE_SIZE;
+
+    // A static `/reserved-memory` region without `no-map`, as for a DMA pool.
+    let mut dtb = DtbWriter::default();
+    dtb.begin("");
+    dtb.cells("#address-cells", &[1]);
+    dtb.cells("#size-cells", &[1]);
+    dtb.begin("memory@80000000");
+    dtb.prop("device_type", b"memory\0");
+    dtb.cells("reg", &[RAM.0 as u32, RAM.1 as u32]);
+    dtb.end();
+    dtb.begin("reserved-memory");
+    dtb.cells("#address-cells", &[1]);
+    dtb.cells("#size-cells", &[1]);
+    dtb.prop("ranges", &[]);
+    dtb.begin("dma@80010000");
+    dtb.cells("reg", &[RESERVED.0 as u32, RESERVED.1 as u32]);
+    dtb.end();
+    dtb.end();
+    dtb.end();
+    let blob = dtb.finish();
+
+    let dev_tree = fdt::fdt(blob.as_ptr().cast()).map_err(|_| "test DTB rejected")?;
+    let reserved = || fdt::mem::reserved_regions(&dev_tree);
+    check(
+        reserved().any(|region| region == RESERVED),
+        "reserved region not found",
+    )?;
+
+    // A span that would straddle it is moved past it.
+    let (reserved_start, reserved_end) = (RESERVED.0, RESERVED.0 + RESERVED.1);
+    let placed = heap::place(reserved_start - 0x1000, RAM.0 + RAM.1, 0x3000, reserved);
+    check(
+        placed == Some(reserved_end),
+        "span placed over reserved memory",
+    )?;
+
+    // `BitmapAlloc` keeps its bitmap forever; a few words is all it takes.
+    let bitmap =
+        Box::leak(alloc::vec![0_usize; PAGES.div_ceil(usize::BITS as usize)].into_boxed_slice());
+    let mut pmm =
+        unsafe { BitmapAlloc::new(core::ptr::NonNull::from(bitmap).cast(), PAGES, RAM.0) };
+    heap::mark_from_fdt(&mut pmm, &dev_tree);
+
+    let mut handed_out = 0;
+    loop
+    {
+        let page = pmm.alloc_pages(1) as usize;
+        if page == 0
+        {
+            break;
+        }
+        check(
+            !(reserved_start..reserved_end).contains(&page),
+            "reserved page handed out",
+        )?;
+        handed_out += 1;
+    }
+
+    check(
+        handed_out == PAGES - RESERVED.1 / arch::PAGE_SIZE,
+        "free pages lost",
+    )
+}
+
+fn load_rebalancing() -> Outcome
+{
+    use crate::task::balance;
+
+    const TASKS: usize = 8;
+
+    static GO: AtomicUsize = AtomicUsize::new(0);
+    static DONE: AtomicUsize = AtomicUsize::new(0);
+
+    let Some(crowded) = arch::online_cpus()
+        .map(|cpu| cpu.logical_id)
+        .find(|&id| id != Cpu::get().logical_id)
+    else
+    {
+        // Nothing to balance between.
+        return Ok(());
+    };
+
+    // Only the passes below may move anything.
+    let was_enabled = balance::is_enabled();
+    balance::set_enabled(false);
+
+    // Pile every task onto one hart, where they take turns waiting.
+    let handles = (0..TASKS)
+        .map(|_| {
+            Task::spawn_suspended(|| {
+                while GO.load(Ordering::Acquire) == 0
+                {
+                    task::yield_now();
+                }
+                DONE.fetch_add(1, Ordering::Release);
+            })
+        })
+        .collect::<Vec<_>>();
+    for handle in &handles
+    {
+        handle.resume();
+        while task::migrate_task(handle.id(), crowded) == Err(MigrateError::Running)
+        {
+            task::yield_now();
+        }
+    }
+
+    let spread = || {
+        let loads = || arch::online_cpus().map(|cpu| cpu.load());
+        loads().max().unwrap_or(0) - loads().min().unwrap_or(0)
+    };
+    let before = spread();
+
+    for _ in 0..TASKS
+    {
+        if balance::rebalance() == 0
+        {
+            break;
+        }
+    }
+    // One task per hart may be running rather than queued at any moment.
+    let after = spread();
+
+    GO.store(1, Ordering::Release);
+    while DONE.load(Ordering::Acquire) < TASKS
+    {
+        task::yield_now();
+    }
+    balance::set_enabled(was_enabled);
+
+    check(before > 2, "tasks never piled up")?;
+    check(after <= 2, "queues still unbalanced after rebalancing")
+}
+
+fn formatted_console_output() -> Outcome
+{
+    use crate::console::Capture;
+
+    static CAPTURE: Capture = Capture::new();
+
+    let previous = console::set_sink(&CAPTURE);
+    crate::print!(
+        "[{:>6}|{:<6}|{:^7}|{:*^5}|{:08.3}|{:#06x}|{:+}]",
+        "r",
+        "l",
+        "mid",
+        1,
+        -1.5,
+        255,
+        7
+    );
+    match previous
+    {
+        Some(sink) => console::set_sink(sink),
+        None => console::reset_sink(),
+    };
+
+    check(
+        CAPTURE.take() == "[     r|l     |  mid  |**1**|-001.500|0x00ff|+7]",
+        "padding or alignment lost",
+    )?;
+
+    // The buffered UART path queues formatted pieces whole, wrapping the ring.
+    let mut ring = RingBuffer::<u8, 8>::new();
+    check(
+        ring.push_slice(b"abcdef") == 6,
+        "short slice not taken whole",
+    )?;
+    ring.pop();
+    ring.pop();
+    ring.pop();
+    check(ring.push_slice(b"ghijkl") == 5, "slice overfilled the ring")?;
+    let mut drained = Vec::new();
+    while let Some(byte) = ring.pop()
+    {
+        drained.push(byte);
+    }
+    check(drained == b"defghijk", "wrapped slice out of order")?;
+    check(ring.overruns() == 1, "partial push not counted")
+}
+
+fn cpu_budget_overrun() -> Outcome
+{
+    use crate::task::{Budget, BudgetAction};
+
+    const BUDGET_US: u64 = 30_000;
+
+    static SPINS: [AtomicUsize; 2] = [const { AtomicUsize::new(0) }; 2];
+    static STOP: AtomicUsize = AtomicUsize::new(0);
+    static RELEASED: AtomicUsize = AtomicUsize::new(0);
+
+    let overruns = || {
+        arch::online_cpus()
+            .filter_map(|cpu| task::stats(cpu.logical_id))
+            .map(|stats| stats.budget_overruns)
+            .sum::<usize>()
+    };
+    let wait_for_overrun = |before: usize| {
+        while overruns() == before
+        {
+            task::yield_now();
+        }
+    };
+    // Give a spinning task a few ticks' worth of chances to run.
+    let progressed = |spins: &AtomicUsize| {
+        let start = spins.load(Ordering::Relaxed);
+        let deadline = timer::now() + timer::timebase_hz().unwrap_or(0) / 10;
+        while timer::now() < deadline
+        {
+            task::yield_now();
+        }
+        spins.load(Ordering::Relaxed) != start
+    };
+
+    let before = overruns();
+    Task::spawn_with_budget(Budget::new(BUDGET_US, BudgetAction::Warn), || {
+        while STOP.load(Ordering::Relaxed) == 0
+        {
+            SPINS[0].fetch_add(1, Ordering::Relaxed);
+        }
+    });
+    wait_for_overrun(before);
+    let warned_runs_on = progressed(&SPINS[0]);
+    STOP.store(1, Ordering::Relaxed);
+    check(warned_runs_on, "warned task stopped running")?;
+
+    let before = overruns();
+    Task::spawn_with_budget(Budget::new(BUDGET_US, BudgetAction::Kill), || {
+        loop
+        {
+            SPINS[1].fetch_add(1, Ordering::Relaxed);
+        }
+    });
+    wait_for_overrun(before);
+    check(!progressed(&SPINS[1]), "killed task kept running")?;
+
+    // Killed holding a lock, it would never release it.
+    let before = overruns();
+    Task::spawn_with_budget(Budget::new(BUDGET_US, BudgetAction::Kill), || {
+        let lock = Mutex::new(());
+        let guard = lock.lock();
+        let hz = timer::timebase_hz().unwrap_or(timer::DEFAULT_TIMEBASE_HZ);
+        let until = timer::now() + 3 * BUDGET_US * hz / 1_000_000;
+        while timer::now() < until
+        {
+            spin_loop();
+        }
+        drop(guard);
+        RELEASED.store(1, Ordering::Release);
+        loop
+        {
+            spin_loop();
+        }
+    });
+    wait_for_overrun(before);
+    check(
+        RELEASED.load(Ordering::Acquire) == 1,
+        "task killed holding a lock",
+    )
+}
+
+fn hart_id_accessors() -> Outcome
+{
+    // Pin the task so every read below is from the same hart.
+    task::without_preemption(|| {
+        let cpu = Cpu::get();
+        check(
+            Cpu::try_get().is_some_and(|found| core::ptr::eq(found, cpu)),
+            "try_get disagrees with get",
+        )?;
+        check(
+            arch::thread_pointer() == cpu as *const Cpu as usize,
+            "tp doesn't hold this hart's Cpu",
+        )?;
+
+        let (logical, physical) = (arch::logical_hart_id(), arch::physical_hart_id());
+        check(
+            fdt::harts::to_physical(logical) == physical,
+            "logical ID maps to another physical ID",
+        )?;
+        check(
+            fdt::harts::to_logical(physical) == logical,
+            "physical ID maps to another logical ID",
+        )
+    })
+}
+
+fn last_exit_goes_idle() -> Outcome
+{
+    use crate::task::TaskKind;
+
+    const UNSET: usize = usize::MAX;
+
+    static RAN_ON: AtomicUsize = AtomicUsize::new(UNSET);
+
+    // Our own hart always has this runner to go back to, so land the task on
+    // another one. Round-robin gets there within one lap.
+    let me = Cpu::get().logical_id;
+    let placement = task::placement();
+    task::set_placement(task::Placement::RoundRobin);
+    let mut hart = me;
+    for _ in 0..arch::online_cpus().count()
+    {
+        RAN_ON.store(UNSET, Ordering::Relaxed);
+        Task::spawn(|| RAN_ON.store(Cpu::get().logical_id, Ordering::Release));
+        while RAN_ON.load(Ordering::Acquire) == UNSET
+        {
+            task::yield_now();
+        }
+
+        hart = RAN_ON.load(Ordering::Acquire);
+        if hart != me
+        {
+            break;
+        }
+    }
+    task::set_placement(placement);
+
+    if hart == me
+    {
+        // Single hart: nothing else to watch.
+        return Ok(());
+    }
+
+    // With the task gone and nothing queued, the hart must settle in idle
+    // (after its worker reaps the task) rather than resume the dead one.
+    let cpu = Cpu::nth(hart);
+    let idle = || {
+        interrupt::with_disabled(|| {
+            let scheduler = cpu.scheduler.lock();
+            scheduler.task().kind == TaskKind::Idle && !scheduler.has_waiting()
+        })
+    };
+    let deadline = timer::now() + timer::timebase_hz().unwrap_or(0) / 10;
+    while !idle()
+    {
+        if timer::now() >= deadline
+        {
+            return Err("hart never went idle after its last task exited");
+        }
+        task::yield_now();
+    }
+    Ok(())
+}
+
+fn spawn_returns_id() -> Outcome
+{
+    use crate::task::balance;
+
+    const UNSET: usize = usize::MAX;
+
+    static SEEN: AtomicUsize = AtomicUsize::new(UNSET);
+    static GO: AtomicUsize = AtomicUsize::new(0);
+
+    // Keep the task on this hart, so it can only be waiting while we run: an
+    // idle hart could otherwise take it and run it.
+    let was_enabled = balance::is_enabled();
+    balance::set_enabled(false);
+    let placement = task::placement();
+    task::set_placement(task::Placement::Local);
+    let id = Task::spawn(|| {
+        SEEN.store(arch::current_task_id().get(), Ordering::Release);
+        while GO.load(Ordering::Acquire) == 0
+        {
+            task::yield_now();
+        }
+    });
+    task::set_placement(placement);
+
+    let queued = task::state_of(id) == Some(TaskState::Ready);
+    while SEEN.load(Ordering::Acquire) == UNSET
+    {
+        task::yield_now();
+    }
+    let waiting = task::state_of(id) == Some(TaskState::Ready);
+    GO.store(1, Ordering::Release);
+    balance::set_enabled(was_enabled);
+
+    check(queued, "new task not queued under its ID")?;
+    check(
+        SEEN.load(Ordering::Acquire) == id.get(),
+        "task runs under another ID",
+    )?;
+    check(waiting, "waiting task lost its state")?;
+    check(
+        task::state_of(TaskId::from_raw(UNSET)).is_none(),
+        "state for a task that never existed",
+    )?;
+
+    // Once it exits and the worker frees it, the ID names nothing.
+    let deadline = timer::now() + timer::timebase_hz().unwrap_or(0) / 10;
+    while task::state_of(id).is_some()
+    {
+        if timer::now() >= deadline
+        {
+            return Err("exited task never freed");
+        }
+        task::yield_now();
+    }
+    Ok(())
+}
+
+fn print_into_held_console() -> Outcome
+{
+    use crate::{console::Capture, drivers::uart};
+
+    static CAPTURE: Capture = Capture::new();
+
+    let Some(uart_mutex) = uart::UART.get()
+    else
+    {
+        return Ok(());
+    };
+
+    // A handler can only find the console held on its own hart by trapping
+    // out of a write in progress. Recreate that: the nested write must go
+    // around the held locks, not wait on them.
+    let previous = console::set_sink(&CAPTURE);
+    interrupt::with_disabled(|| {
+        let _uart = uart_mutex.lock();
+        let cpu = Cpu::get();
+        cpu.in_console.store(true, Ordering::Relaxed);
+        crate::println!();
+        cpu.in_console.store(false, Ordering::Relaxed);
+    });
+    crate::print!("x");
+    match previous
+    {
+        Some(sink) => console::set_sink(sink),
+        None => console::reset_sink(),
+    };
+
+    // Only the second print reached the sink; the nested one went to SBI.
+    check(
+        CAPTURE.take() == "x",
+        "re-entrant print took the normal path",
+    )
+}
+
+fn memory_map_layout() -> Outcome
+{
+    use crate::memory::map::{self, RegionKind};
+
+    let regions = map::regions();
+    check(
+        regions.iter().all(|r| r.start < r.end),
+        "empty or inverted region",
+    )?;
+    check(
+        regions.windows(2).all(|w| w[0].end <= w[1].start),
+        "regions out of order or overlapping",
+    )?;
+
+    let find = |kind: RegionKind, at: usize| {
+        regions
+            .iter()
+            .any(|r| r.kind == kind && r.contains(&(at..at + 1)))
+    };
+    check(
+        find(RegionKind::Image, memory_map_layout as *const () as usize),
+        "kernel text outside the kernel image",
+    )?;
+    let boxed = Box::new(0u8);
+    check(
+        find(RegionKind::Heap, &*boxed as *const u8 as usize),
+        "allocation outside the heap",
+    )?;
+    check(
+        map::hart_stacks().all(|(_, stack, trap_stack)| {
+            [stack, trap_stack].iter().all(|range| {
+                regions
+                    .iter()
+                    .any(|r| r.kind == RegionKind::Heap && r.contains(range))
+            })
+        }),
+        "hart stack outside the heap",
+    )
+}
+
+fn semaphore_fifo() -> Outcome
+{
+    use crate::sync::Semaphore;
+
+    const TASKS: usize = 5;
+
+    static SEM: Semaphore = Semaphore::new(0);
+    static DONE: AtomicUsize = AtomicUsize::new(0);
+    static ORDER: [AtomicUsize; TASKS] = [const { AtomicUsize::new(usize::MAX) }; _];
+
+    // Queue the waiters one at a time, each blocked before the next exists.
+    let placement = task::placement();
+    task::set_placement(task::Placement::Local);
+    for i in 0..TASKS
+    {
+        let id = Task::spawn(move || {
+            SEM.acquire();
+            ORDER[DONE.fetch_add(1, Ordering::AcqRel)].store(i, Ordering::Release);
+        });
+        while task::state_of(id) != Some(TaskState::Blocked)
+        {
+            task::yield_now();
+        }
+    }
+    task::set_placement(placement);
+
+    check(!SEM.try_acquire(), "took a permit ahead of the waiters")?;
+
+    for (i, slot) in ORDER.iter().enumerate()
+    {
+        SEM.release();
+        while DONE.load(Ordering::Acquire) == i
+        {
+            task::yield_now();
+        }
+        check(
+            DONE.load(Ordering::Acquire) == i + 1,
+            "one release granted several",
+        )?;
+        check(
+            slot.load(Ordering::Acquire) == i,
+            "waiter woken out of order",
+        )?;
+    }
+
+    SEM.release();
+    check(SEM.try_acquire(), "free permit not taken")
+}
+
+/// A native-class RISC-V ELF of `kind` for `machine`, with one `PT_LOAD`
+/// header per `(vaddr, file_size, mem_size)`, each segment's bytes filled
+/// with its index plus one and laid out back to back after the headers.
+fn elf_image(kind: u16, machine: u16, entry: usize, segments: &[(usize, usize, usize)]) -> Vec<u8>
+{
+    use crate::elf::layout::*;
+
+    const PT_LOAD: u32 = 1;
+
+    let put = |image: &mut Vec<u8>, at: usize, bytes: &[u8]| {
+        image[at..at + bytes.len()].copy_from_slice(bytes)
+    };
+    let word = |value: usize| value.to_le_bytes();
+
+    let headers = EHDR_SIZE + segments.len() * PHDR_SIZE;
+    let payload: usize = segments.iter().map(|&(_, file_size, _)| file_size).sum();
+    let mut image = vec![0; headers + payload];
+
+    put(&mut image, 0, b"\x7fELF");
+    put(&mut image, 4, &[CLASS, 1, 1]);
+    put(&mut image, 16, &kind.to_le_bytes());
+    put(&mut image, 18, &machine.to_le_bytes());
+    put(&mut image, E_ENTRY, &word(entry)[..WORD]);
+    put(&mut image, E_PHOFF, &word(EHDR_SIZE)[..WORD]);
+    put(&mut image, E_PHENTSIZE, &(PHDR_SIZE as u16).to_le_bytes());
+    put(&mut image, E_PHNUM, &(segments.len() as u16).to_le_bytes());
+
+    let mut offset = headers;
+    for (i, &(vaddr, file_size, mem_size)) in segments.iter().enumerate()
+    {
+        let ph = EHDR_SIZE + i * PHDR_SIZE;
+        put(&mut image, ph, &PT_LOAD.to_le_bytes());
+        put(&mut image, ph + P_OFFSET, &word(offset)[..WORD]);
+        put(&mut image, ph + P_VADDR, &word(vaddr)[..WORD]);
+        put(&mut image, ph + P_FILESZ, &word(file_size)[..WORD]);
+        put(&mut image, ph + P_MEMSZ, &word(mem_size)[..WORD]);
+        image[offset..offset + file_size].fill(i as u8 + 1);
+        offset += file_size;
+    }
+    image
+}
+
+fn elf_image_validation() -> Outcome
+{
+    use crate::{
+        arch::PAGE_SIZE,
+        elf::{Elf, ElfError},
+        memory::heap,
+    };
+
+    const ET_EXEC: u16 = 2;
+    const ET_DYN: u16 = 3;
+    const RISCV: u16 = 243;
+    const X86_64: u16 = 62;
+
+    // Text at 0, then data with a zeroed tail on the next page.
+    let segments = [(0, 16, 16), (PAGE_SIZE, 8, 0x20)];
+    let image = elf_image(ET_DYN, RISCV, 4, &segments);
+
+    let elf = Elf::parse(&image).map_err(|_| "valid image rejected")?;
+    check(
+        elf.segments()
+            .map(|seg| (seg.vaddr, seg.file_size, seg.mem_size))
+            .eq(segments),
+        "segment layout misread",
+    )?;
+    check(
+        elf.extent() == Ok((0, PAGE_SIZE + 0x20)),
+        "wrong image extent",
+    )?;
+
+    let loaded = elf.load().map_err(|_| "valid image failed to load")?;
+    let copy =
+        unsafe { core::slice::from_raw_parts(loaded.base as *const u8, loaded.pages * PAGE_SIZE) };
+    let copied = copy[..16].iter().all(|&b| b == 1)
+        && copy[PAGE_SIZE..PAGE_SIZE + 8].iter().all(|&b| b == 2)
+        && copy[PAGE_SIZE + 8..PAGE_SIZE + 0x20]
+            .iter()
+            .all(|&b| b == 0);
+    let entry_ok = loaded.entry == loaded.base + 4;
+    heap::pmm()
+        .lock()
+        .free_pages(loaded.base as *mut u8, loaded.pages);
+    check(loaded.pages == 2, "wrong page count")?;
+    check(copied, "segments copied to the wrong place")?;
+    check(entry_ok, "entry not relocated with the image")?;
+
+    let mut bad_magic = image.clone();
+    bad_magic[0] = 0;
+    check(
+        Elf::parse(&bad_magic).err() == Some(ElfError::BadMagic),
+        "bad magic accepted",
+    )?;
+    check(
+        Elf::parse(&elf_image(ET_DYN, X86_64, 4, &segments)).err() == Some(ElfError::WrongMachine),
+        "foreign machine accepted",
+    )?;
+    check(
+        Elf::parse(&elf_image(ET_EXEC, RISCV, 4, &segments)).err()
+            == Some(ElfError::UnsupportedType),
+        "fixed-address executable accepted",
+    )?;
+
+    let overlapping = elf_image(ET_DYN, RISCV, 4, &[(0, 16, 16), (8, 8, 8)]);
+    check(
+        Elf::parse(&overlapping).and_then(|elf| elf.extent()) == Err(ElfError::BadSegment),
+        "overlapping segments accepted",
+    )
+}
+
+fn spawn_bare_fn() -> Outcome
+{
+    static RAN: AtomicUsize = AtomicUsize::new(0);
+
+    extern "C" fn entry()
+    {
+        RAN.fetch_add(1, Ordering::Release);
+    }
+
+    let id = Task::spawn_fn(entry);
+    while RAN.load(Ordering::Acquire) == 0
+    {
+        task::yield_now();
+    }
+
+    // Returning from `entry` must land in `Task::exit`, so the task is
+    // reaped rather than left running off the end of its function.
+    let deadline = timer::now() + timer::timebase_hz().unwrap_or(0) / 10;
+    while task::state_of(id).is_some()
+    {
+        if timer::now() >= deadline
+        {
+            return Err("bare function task never exited");
+        }
+        task::yield_now();
+    }
+    check(RAN.load(Ordering::Acquire) == 1, "entry ran more than once")
+}
+
+fn join_exit_code() -> Outcome
+{
+    let failing = Task::spawn_joinable(|| Task::exit_with(3));
+    let clean = Task::spawn_joinable(|| {});
+
+    check(failing.join() == 3, "exit code lost")?;
+    check(clean.join() == 0, "normal return not exit code 0")
+}
+
+/// Run `spawn` with preemption off and new tasks placed locally, so the tasks
+/// it returns queue behind us and none starts before the last is queued.
+/// Returns whether they all stayed on this hart; a busy one sends them on.
+fn spawn_here(spawn: impl FnOnce() -> [TaskId; 2]) -> bool
+{
+    let placement = task::placement();
+    task::set_placement(task::Placement::Local);
+
+    let here = task::without_preemption(|| {
+        let ids = spawn();
+        interrupt::with_disabled(|| {
+            let scheduler = Cpu::get().scheduler.lock();
+            ids.iter().all(|&id| scheduler.is_waiting(id))
+        })
+    });
+
+    task::set_placement(placement);
+    here
+}
+
+/// Block until `counter` reaches `target`. Unlike yielding, this lets tasks in
+/// lower bands run meanwhile.
+fn wait_for(counter: &AtomicUsize, target: usize)
+{
+    loop
+    {
+        let seen = counter.load(Ordering::Acquire);
+        if seen >= target
+        {
+            return;
+        }
+        sync::futex_wait(counter, seen);
+    }
+}
+
+fn priority_bands() -> Outcome
+{
+    use crate::task::balance;
+
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    static LOW_AT: AtomicUsize = AtomicUsize::new(0);
+    static HIGH_AT: AtomicUsize = AtomicUsize::new(0);
+    static DONE: AtomicUsize = AtomicUsize::new(0);
+
+    fn finish(at: &AtomicUsize)
+    {
+        at.store(NEXT.fetch_add(1, Ordering::Relaxed), Ordering::Relaxed);
+        DONE.fetch_add(1, Ordering::Release);
+        sync::futex_wake(&DONE, 1);
+    }
+
+    // Nothing may move them apart once queued.
+    let was_enabled = balance::is_enabled();
+    balance::set_enabled(false);
+
+    // The low task is queued first, so only its band can put it second.
+    let here = spawn_here(|| {
+        [
+            Task::spawn_with_priority(Priority::Low, || finish(&LOW_AT)),
+            Task::spawn_with_priority(Priority::High, || finish(&HIGH_AT)),
+        ]
+    });
+    wait_for(&DONE, 2);
+    balance::set_enabled(was_enabled);
+
+    if !here
+    {
+        // Queued on different harts, so their order proves nothing.
+        return Ok(());
+    }
+    check(
+        HIGH_AT.load(Ordering::Relaxed) < LOW_AT.load(Ordering::Relaxed),
+        "low-priority task ran first",
+    )
+}
+
+fn yield_alternation() -> Outcome
+{
+    use crate::task::balance;
+
+    const ROUNDS: usize = 16;
+
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    static TURNS: [AtomicUsize; 2 * ROUNDS] = [const { AtomicUsize::new(0) }; 2 * ROUNDS];
+    static DONE: AtomicUsize = AtomicUsize::new(0);
+
+    fn take_turns(tag: usize)
+    {
+        for _ in 0..ROUNDS
+        {
+            // A tick between the two would let the other task go twice.
+            task::without_preemption(|| {
+                TURNS[NEXT.fetch_add(1, Ordering::Relaxed)].store(tag, Ordering::Relaxed);
+                task::yield_now();
+            });
+        }
+        DONE.fetch_add(1, Ordering::Release);
+        sync::futex_wake(&DONE, 1);
+    }
+
+    let was_enabled = balance::is_enabled();
+    balance::set_enabled(false);
+
+    let here = spawn_here(|| [Task::spawn(|| take_turns(1)), Task::spawn(|| take_turns(2))]);
+    wait_for(&DONE, 2);
+    balance::set_enabled(was_enabled);
+
+    if !here
+    {
+        // Each had a hart to itself.
+        return Ok(());
+    }
+    check(
+        TURNS
+            .windows(2)
+            .all(|pair| pair[0].load(Ordering::Relaxed) != pair[1].load(Ordering::Relaxed)),
+        "tasks didn't alternate",
+    )
+}
+
+fn corrupt_context_caught() -> Outcome
+{
+    // The check panics, so each case runs in a task of its own and is told
+    // apart by its exit code. A caught task is leaked, as panics don't unwind.
+    let check_with = |corrupt: fn(&mut TrapContext)| {
+        Task::spawn_joinable(move || {
+            let mut task = Task::worker();
+            corrupt(&mut task.context);
+            task.check_context();
+        })
+        .join()
+    };
+
+    check(check_with(|_| {}) == 0, "fresh context rejected")?;
+    check(
+        check_with(|ctx| ctx.pc = 0) == task::PANIC_EXIT_CODE,
+        "null pc accepted",
+    )?;
+    check(
+        check_with(|ctx| ctx.pc = ctx.sp - 16) == task::PANIC_EXIT_CODE,
+        "pc on the stack accepted",
+    )?;
+    check(
+        check_with(|ctx| ctx.pc += 1) == task::PANIC_EXIT_CODE,
+        "misaligned pc accepted",
+    )?;
+    check(
+        check_with(|ctx| ctx.sp += 4096) == task::PANIC_EXIT_CODE,
+        "sp past the stack accepted",
+    )
+}
+
+fn instruction_length() -> Outcome
+{
+    use crate::interrupt::fault;
+
+    // `c.nop`, then `nop` (`addi x0, x0, 0`) as two little-endian parcels, then
+    // `c.ebreak`.
+    static CODE: [u16; 4] = [0x0001, 0x0013, 0x0000, 0x9002];
+
+    let at = |parcel: usize| fault::instruction_len(&CODE[parcel] as *const u16 as usize);
+    check(at(0) == 2, "compressed instruction not 2 bytes")?;
+    check(at(1) == 4, "standard instruction not 4 bytes")?;
+    check(
+        at(3) == 2,
+        "compressed instruction after a standard one not 2 bytes",
+    )
+}
+
+fn hart_map_set_once() -> Outcome
+{
+    use crate::sync::OnceLock;
+
+    // Harts that don't exist, so any of them showing up in the map would mean
+    // the boot map was replaced.
+    const PHANTOM: [u32; 2] = [0x40, 0x41];
+
+    let mut dtb = DtbWriter::default();
+    dtb.begin("");
+    dtb.cells("#address-cells", &[1]);
+    dtb.cells("#size-cells", &[1]);
+    dtb.begin("cpus");
+    dtb.cells("#address-cells", &[1]);
+    dtb.cells("#size-cells", &[0]);
+    for id in PHANTOM
+    {
+        dtb.begin(&alloc::format!("cpu@{id:x}"));
+        dtb.prop("device_type", b"cpu\0");
+        dtb.cells("reg", &[id]);
+        dtb.end();
+    }
+    dtb.end();
+    dtb.end();
+    let blob = dtb.finish();
+    let dev_tree = fdt::fdt(blob.as_ptr().cast()).map_err(|_| "test DTB rejected")?;
+
+    check(
+        fdt::harts::parse_hart_count(&dev_tree, PHANTOM[0] as usize).ok() == Some(PHANTOM.len()),
+        "second tree miscounted",
+    )?;
+    check(
+        PHANTOM
+            .iter()
+            .all(|&id| fdt::harts::try_to_logical(id as usize).is_none()),
+        "second parse replaced the map",
+    )?;
+    for cpu in CPU_VEC.wait().iter()
+    {
+        check(
+            fdt::harts::try_to_physical(cpu.logical_id) == Some(cpu.physical_id),
+            "boot map entry changed",
+        )?;
+    }
+
+    // `wait` blocks readers until the one write lands, as the map's do.
+    static CELL: OnceLock<usize> = OnceLock::new();
+    static SEEN: AtomicUsize = AtomicUsize::new(0);
+    static STARTED: AtomicUsize = AtomicUsize::new(0);
+
+    Task::spawn(|| {
+        STARTED.store(1, Ordering::Release);
+        SEEN.store(*CELL.wait(), Ordering::Release);
+    });
+    while STARTED.load(Ordering::Acquire) == 0
+    {
+        task::yield_now();
+    }
+    check(SEEN.load(Ordering::Acquire) == 0, "wait returned early")?;
+
+    check(CELL.set(7).is_ok(), "first set refused")?;
+    check(CELL.set(8) == Err(8), "second set accepted")?;
+    while SEEN.load(Ordering::Acquire) == 0
+    {
+        task::yield_now();
+    }
+    check(
+        SEEN.load(Ordering::Acquire) == 7,
+        "waiter saw the wrong value",
+    )
+}
+
+fn call_once_runs_once() -> Outcome
+{
+    use crate::sync::OnceLock;
+
+    static CELL: OnceLock<usize> = OnceLock::new();
+    static RUNS: AtomicUsize = AtomicUsize::new(0);
+    static WRONG: AtomicUsize = AtomicUsize::new(0);
+    static GO: AtomicUsize = AtomicUsize::new(0);
+    static DONE: AtomicUsize = AtomicUsize::new(0);
+
+    // Several per hart, all released at once.
+    let callers = CPU_VEC.wait().len() * 2;
+    for _ in 0..callers
+    {
+        Task::spawn(|| {
+            while GO.load(Ordering::Acquire) == 0
+            {
+                spin_loop();
+            }
+            let value = CELL.call_once(|| {
+                RUNS.fetch_add(1, Ordering::Relaxed);
+                // Linger, so the others arrive while it is being set.
+                for _ in 0..10_000
+                {
+                    spin_loop();
+                }
+                42
+            });
+            if *value != 42
+            {
+                WRONG.fetch_add(1, Ordering::Relaxed);
+            }
+            DONE.fetch_add(1, Ordering::Release);
+        });
+    }
+
+    GO.store(1, Ordering::Release);
+    while DONE.load(Ordering::Acquire) < callers
+    {
+        task::yield_now();
+    }
+
+    check(
+        RUNS.load(Ordering::Relaxed) == 1,
+        "initializer ran more than once",
+    )?;
+    check(
+        WRONG.load(Ordering::Relaxed) == 0,
+        "a caller saw another value",
+    )
+}
+
+fn cpu_get_every_hart() -> Outcome
+{
+    const ROUNDS: usize = 8;
+
+    static SEEN: AtomicUsize = AtomicUsize::new(0);
+    static WRONG: AtomicUsize = AtomicUsize::new(0);
+    static DONE: AtomicUsize = AtomicUsize::new(0);
+
+    let cpus = CPU_VEC.wait();
+    let online = arch::online_cpus()
+        .filter(|cpu| cpu.logical_id < usize::BITS as usize)
+        .fold(0, |mask, cpu| mask | 1 << cpu.logical_id);
+
+    let placement = task::placement();
+    task::set_placement(task::Placement::RoundRobin);
+
+    // Each batch puts one task on every hart; more only cover for a task that
+    // was moved before it ran.
+    let mut spawned = 0;
+    for _ in 0..ROUNDS
+    {
+        for _ in 0..cpus.len()
+        {
+            Task::spawn(|| {
+                task::without_preemption(|| {
+                    let cpu = Cpu::get();
+                    let matches = CPU_VEC
+                        .wait()
+                        .get(cpu.logical_id)
+                        .is_some_and(|entry| core::ptr::eq(entry, cpu))
+                        && fdt::harts::try_to_physical(cpu.logical_id) == Some(cpu.physical_id);
+                    if !matches
+                    {
+                        WRONG.fetch_add(1, Ordering::Relaxed);
+                    }
+                    if cpu.logical_id < usize::BITS as usize
+                    {
+                        SEEN.fetch_or(1 << cpu.logical_id, Ordering::Relaxed);
+                    }
+                });
+                DONE.fetch_add(1, Ordering::Release);
+            });
+        }
+        spawned += cpus.len();
+
+        while DONE.load(Ordering::Acquire) < spawned
+        {
+            task::yield_now();
+        }
+        if SEEN.load(Ordering::Relaxed) & online == online
+        {
+            break;
+        }
+    }
+    task::set_placement(placement);
+
+    check(
+        WRONG.load(Ordering::Relaxed) == 0,
+        "Cpu::get isn't this hart's entry",
+    )?;
+    check(
+        SEEN.load(Ordering::Relaxed) & online == online,
+        "a hart never ran a task",
+    )
+}
+
+fn interrupt_state_per_task() -> Outcome
+{
+    const ROUNDS: usize = 20;
+
+    static LEAKS: AtomicUsize = AtomicUsize::new(0);
+    static DONE: AtomicUsize = AtomicUsize::new(0);
+
+    fn expect_enabled(enabled: bool)
+    {
+        if interrupt::is_enabled() != enabled
+        {
+            LEAKS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn finish()
+    {
+        DONE.fetch_add(1, Ordering::Release);
+        sync::futex_wake(&DONE, 1);
+    }
+
+    // Yields from inside a critical section, so it is switched out the moment
+    // the section re-enables interrupts, and back in with them enabled.
+    let masked = || {
+        for _ in 0..ROUNDS
+        {
+            interrupt::with_disabled(|| {
+                task::yield_now();
+                expect_enabled(false);
+            });
+            expect_enabled(true);
+        }
+        finish();
+    };
+    // Sharing a hart, it is switched in whenever the masked task is switched
+    // out.
+    let open = || {
+        for _ in 0..ROUNDS
+        {
+            task::yield_now();
+            expect_enabled(true);
+        }
+        finish();
+    };
+
+    spawn_here(|| [Task::spawn(masked), Task::spawn(open)]);
+    wait_for(&DONE, 2);
+
+    check(
+        LEAKS.load(Ordering::Relaxed) == 0,
+        "interrupt state leaked across a switch",
+    )
+}
+
+fn stacks_outlive_tasks() -> Outcome
+{
+    const TASKS: usize = 8;
+    const PATTERN: u8 = 0x5A;
+
+    // Each fills part of its stack, then keeps checking it while the others
+    // exit and their stacks are freed, poisoned in debug builds, and handed
+    // out again. A stack freed early would change under its task.
+    let handles = (0..TASKS)
+        .map(|_| {
+            Task::spawn_joinable(|| {
+                let fill = black_box([PATTERN; 4096]);
+                for _ in 0..TASKS
+                {
+                    task::yield_now();
+                    drop(black_box(vec![0_u8; 4096]));
+                    if black_box(&fill).iter().any(|&b| b != PATTERN)
+                    {
+                        Task::exit_with(1);
+                    }
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let ids = handles.iter().map(|h| h.id()).collect::<Vec<_>>();
+    for handle in handles
+    {
+        check(handle.join() == 0, "stack changed while its task ran")?;
+    }
+
+    // Joined means reaped: out of every queue, so nothing can free it again.
+    check(
+        ids.iter().all(|&id| task::state_of(id).is_none()),
+        "reaped task still queued",
+    )
+}
+
+fn missing_timebase() -> Outcome
+{
+    use crate::logger;
+
+    let tree = |timebase: Option<u32>| {
+        let mut dtb = DtbWriter::default();
+        dtb.begin("");
+        dtb.cells("#address-cells", &[1]);
+        dtb.cells("#size-cells", &[1]);
+        dtb.begin("cpus");
+        dtb.cells("#address-cells", &[1]);
+        dtb.cells("#size-cells", &[0]);
+        if let Some(hz) = timebase
+        {
+            dtb.cells("timebase-frequency", &[hz]);
+        }
+        dtb.begin("cpu@0");
+        dtb.prop("device_type", b"cpu\0");
+        dtb.cells("reg", &[0]);
+        dtb.end();
+        dtb.end();
+        dtb.end();
+        dtb.finish()
+    };
+
+    let blob = tree(Some(1_000_000));
+    let dev_tree = fdt::fdt(blob.as_ptr().cast()).map_err(|_| "test DTB rejected")?;
+    let hz = fdt::timer::timebase_hz(&dev_tree);
+    check(hz == Some(1_000_000), "timebase not read")?;
+    check(
+        timer::timebase_or_default(hz) == 1_000_000,
+        "given timebase replaced",
+    )?;
+
+    let blob = tree(None);
+    let dev_tree = fdt::fdt(blob.as_ptr().cast()).map_err(|_| "test DTB rejected")?;
+    let hz = fdt::timer::timebase_hz(&dev_tree);
+    check(hz.is_none(), "timebase found in a tree without one")?;
+
+    let warnings = logger::warnings();
+    check(
+        timer::timebase_or_default(hz) == timer::DEFAULT_TIMEBASE_HZ,
+        "default timebase not used",
+    )?;
+    check(logger::warnings() > warnings, "no warning logged")
+}
+
+fn no_tasks_before_ready() -> Outcome
+{
+    check(
+        arch::online_cpus().all(Cpu::is_ready),
+        "online hart never finished setup",
+    )?;
+
+    // A hart that never got through setup was left offline, and must never
+    // have been handed anything to run.
+    for cpu in CPU_VEC.wait().iter().filter(|cpu| !cpu.is_ready())
+    {
+        check(!cpu.is_online(), "unready hart online")?;
+        check(cpu.load() == 0, "task queued on an unready hart")?;
+        check(
+            cpu.sched_counters.snapshot().switches == 0,
+            "unready hart switched tasks",
+        )?;
+    }
+
+    // New tasks only ever land on harts that are ready, the one that reports
+    // back included.
+    static UNREADY: AtomicUsize = AtomicUsize::new(0);
+    static DONE: AtomicUsize = AtomicUsize::new(0);
+
+    let tasks = CPU_VEC.wait().len() * 2;
+    for _ in 0..tasks
+    {
+        Task::spawn(|| {
+            if !Cpu::get().is_ready()
+            {
+                UNREADY.fetch_add(1, Ordering::Relaxed);
+            }
+            DONE.fetch_add(1, Ordering::Release);
+        });
+    }
+    while DONE.load(Ordering::Acquire) < tasks
+    {
+        task::yield_now();
+    }
+    check(
+        UNREADY.load(Ordering::Relaxed) == 0,
+        "task ran on an unready hart",
+    )
+}
+
+fn channel_recv_timeout() -> Outcome
+{
+    use crate::sync::{RecvError, channel};
+
+    let ms = timer::timebase_hz().unwrap_or(timer::DEFAULT_TIMEBASE_HZ) / 1000;
+
+    // Timeout: nothing is ever sent, and the wait must block rather than spin.
+    static ELAPSED: AtomicUsize = AtomicUsize::new(0);
+
+    let (tx, rx) = channel::<usize>();
+    let receiver = Task::spawn_joinable(move || {
+        let start = timer::now();
+        let result = rx.recv_timeout(50 * ms);
+        ELAPSED.store((timer::now() - start) as usize, Ordering::Relaxed);
+        if result != Err(RecvError::Timeout)
+        {
+            Task::exit_with(1);
+        }
+    });
+
+    let mut blocked = false;
+    let code = loop
+    {
+        if let Some(code) = receiver.try_join()
+        {
+            break code;
+        }
+        blocked |= task::state_of(receiver.id()) == Some(TaskState::Blocked);
+        task::yield_now();
+    };
+    check(code == 0, "no timeout reported")?;
+    check(
+        ELAPSED.load(Ordering::Relaxed) as u64 >= 50 * ms,
+        "timed out early",
+    )?;
+    check(blocked, "receiver never blocked")?;
+    drop(tx);
+
+    // Message: a send part way through the wait ends it at once, with the
+    // message.
+    let pause = move || {
+        let deadline = timer::now() + 10 * ms;
+        while timer::now() < deadline
+        {
+            task::yield_now();
+        }
+    };
+
+    let (tx, rx) = channel();
+    Task::spawn(move || {
+        pause();
+        let _ = tx.send(7_usize);
+    });
+    let start = timer::now();
+    check(
+        rx.recv_timeout(10_000 * ms) == Ok(7),
+        "message not received",
+    )?;
+    check(
+        timer::now() - start < 1000 * ms,
+        "message didn't end the wait",
+    )?;
+
+    // Disconnect: the last sender leaving wakes the receiver too.
+    let (tx, rx) = channel::<usize>();
+    Task::spawn(move || {
+        pause();
+        drop(tx);
+    });
+    let start = timer::now();
+    check(
+        rx.recv_timeout(10_000 * ms) == Err(RecvError::Disconnected),
+        "disconnect not reported",
+    )?;
+    check(
+        timer::now() - start < 1000 * ms,
+        "disconnect didn't end the wait",
+    )
+}
+
+fn device_tree_dump() -> Outcome
+{
+    use crate::console::Capture;
+
+    static CAPTURE: Capture = Capture::new();
+
+    let mut dtb = DtbWriter::default();
+    dtb.begin("");
+    dtb.cells("#address-cells", &[1]);
+    dtb.begin("chosen");
+    dtb.prop("bootargs", b"console=ttyS0\0");
+    dtb.end();
+    dtb.begin("uart@10000000");
+    dtb.prop("compatible", b"ns16550a\0ns16550\0");
+    dtb.cells("reg", &[0x1000_0000, 0x100]);
+    dtb.prop("mac", &[1, 2, 3]);
+    dtb.cells("long", &[7; 20]);
+    dtb.prop("dma-coherent", &[]);
+    dtb.end();
+    dtb.end();
+    let blob = dtb.finish();
+    let dev_tree = fdt::fdt(blob.as_ptr().cast()).map_err(|_| "test DTB rejected")?;
+
+    let previous = console::set_sink(&CAPTURE);
+    fdt::dump::dump(&dev_tree);
+    match previous
+    {
+        Some(sink) => console::set_sink(sink),
+        None => console::reset_sink(),
+    };
+    let out = CAPTURE.take();
+
+    let has_line = |line: &str| out.lines().any(|l| l.trim() == line);
+    check(has_line("/ {"), "root node missing")?;
+    check(has_line("chosen {"), "child node missing")?;
+    check(has_line("#address-cells = <0x1>;"), "cell missing")?;
+    check(has_line("bootargs = \"console=ttyS0\";"), "string missing")?;
+    check(
+        has_line("compatible = \"ns16550a\", \"ns16550\";"),
+        "string list missing",
+    )?;
+    check(has_line("reg = <0x10000000 0x100>;"), "cells missing")?;
+    check(has_line("mac = [01 02 03];"), "bytes missing")?;
+    check(has_line("dma-coherent;"), "empty property missing")?;
+    check(
+        out.lines()
+            .any(|l| l.trim().starts_with("long = <") && l.ends_with(" ...>;")),
+        "long value not truncated",
+    )?;
+    check(
+        out.lines().filter(|l| l.trim() == "};").count() == 3,
+        "nodes not closed",
+    )
+}
+
+fn heap_poison() -> Outcome
+{
+    use alloc::alloc::{alloc, dealloc, realloc};
+    use core::alloc::Layout;
+
+    use crate::memory::heap::{ALLOC_POISON, FREE_POISON};
+
+    const SMALL: usize = 256;
+    const GROWN: usize = 4096;
+
+    // Release builds don't poison.
+    if !cfg!(debug_assertions)
+    {
+        return Ok(());
+    }
+
+    let layout = Layout::from_size_align(SMALL, 8).map_err(|_| "bad layout")?;
+    let grown_layout = Layout::from_size_align(GROWN, 8).map_err(|_| "bad layout")?;
+    let filled = |pattern: u8| u64::from_ne_bytes([pattern; 8]);
+    // `talc` keeps its free-list links at the ends of a free chunk, so read
+    // from the middle.
+    let middle = |block: *mut u8| unsafe { block.add(SMALL / 2).cast::<u64>().read_volatile() };
+
+    let block = unsafe { alloc(layout) };
+    check(!block.is_null(), "allocation failed")?;
+    check(
+        middle(block) == filled(ALLOC_POISON),
+        "new block not poisoned",
+    )?;
+    unsafe {
+        block.write_bytes(0x5a, SMALL);
+        dealloc(block, layout);
+    }
+    check(
+        middle(block) == filled(FREE_POISON),
+        "freed block not poisoned",
+    )?;
+
+    // A block with another live one less than `GROWN` bytes above it can't
+    // grow in place, so growing it has to move it.
+    let blocks = (0..16)
+        .map(|_| unsafe { alloc(layout) })
+        .collect::<Vec<_>>();
+    check(blocks.iter().all(|b| !b.is_null()), "allocation failed")?;
+    let pinned = blocks.iter().copied().find(|&b| {
+        blocks
+            .iter()
+            .any(|&above| above > b && (above as usize) < b as usize + GROWN)
+    });
+
+    let mut outcome = Err("no block pinned by a neighbour");
+    if let Some(pinned) = pinned
+    {
+        unsafe { pinned.write_bytes(0x5a, SMALL) };
+        let grown = unsafe { realloc(pinned, layout, GROWN) };
+        outcome = check(!grown.is_null(), "realloc failed")
+            .and_then(|()| check(grown != pinned, "pinned block grew in place"))
+            .and_then(|()| {
+                check(
+                    middle(pinned) == filled(FREE_POISON),
+                    "moved-from block not poisoned",
+                )
+            })
+            .and_then(|()| {
+                let contents = unsafe { core::slice::from_raw_parts(grown, GROWN) };
+                check(
+                    contents[..SMALL].iter().all(|&b| b == 0x5a),
+                    "contents not copied",
+                )?;
+                check(
+                    contents[SMALL..].iter().all(|&b| b == ALLOC_POISON),
+                    "grown tail not poisoned",
+                )
+            });
+        unsafe {
+            if grown.is_null()
+            {
+                dealloc(pinned, layout);
+            }
+            else
+            {
+                dealloc(grown, grown_layout);
+            }
+        }
+    }
+    for block in blocks.into_iter().filter(|&b| Some(b) != pinned)
+    {
+        unsafe { dealloc(block, layout) };
+    }
+    outcome
+}
+
+fn panic_poisons_lock() -> Outcome
+{
+    use crate::sync::PoisonError;
+
+    let lock = Mutex::new(0_usize);
+    let guard = lock.lock();
+    check(!lock.is_poisoned(), "held lock poisoned without a panic")?;
+
+    // Do what the panic handler does on a hart that panics holding `lock`,
+    // then take that back so this hart can carry on.
+    let next = interrupt::with_disabled(|| {
+        sync::poison_held_locks();
+        let next = lock.lock_checked().map(drop);
+        sync::clear_poison();
+        next
+    });
+    check(next == Err(PoisonError), "poison not reported")?;
+
+    drop(guard);
+    check(lock.lock_checked().is_ok(), "released lock still poisoned")
+}
+
+fn preempt_section_holds() -> Outcome
+{
+    use core::sync::atomic::AtomicBool;
+
+    use crate::task::balance;
+
+    static STOP: AtomicBool = AtomicBool::new(false);
+    static NEVER: AtomicUsize = AtomicUsize::new(0);
+
+    let ms = timer::timebase_hz().unwrap_or(timer::DEFAULT_TIMEBASE_HZ) / 1000;
+
+    // A task a tick would switch to, kept on this hart.
+    let was_enabled = balance::is_enabled();
+    balance::set_enabled(false);
+    let placement = task::placement();
+    task::set_placement(task::Placement::Local);
+    let rival = Task::spawn_joinable(|| {
+        while !STOP.load(Ordering::Relaxed)
+        {
+            spin_loop();
+        }
+    });
+    task::set_placement(placement);
+
+    let held = task::without_preemption(|| {
+        let counters = &Cpu::get().sched_counters;
+        let before = counters.snapshot();
+        while counters.snapshot().busy_ticks < before.busy_ticks + 3
+        {
+            spin_loop();
+        }
+        counters.snapshot().switches == before.switches
+    });
+
+    // Blocking switches away, but the task must come back inside its section.
+    let resumed_inside = task::without_preemption(|| {
+        sync::futex_wait_until(&NEVER, 0, timer::now() + 20 * ms);
+        !Cpu::get().preemptible()
+    });
+
+    STOP.store(true, Ordering::Relaxed);
+    rival.join();
+    balance::set_enabled(was_enabled);
+
+    check(held, "tick switched tasks inside the section")?;
+    check(resumed_inside, "section lost across a block")
+}
+
+fn trace_ring_dump() -> Outcome
+{
+    use alloc::string::String;
+
+    use crate::{console::Capture, trace};
+
+    const ID: u16 = 7;
+    const EVENTS: usize = trace::CAPACITY + 10;
+
+    static CAPTURE: Capture = Capture::new();
+
+    fn capture(f: impl FnOnce()) -> String
+    {
+        let previous = console::set_sink(&CAPTURE);
+        f();
+        match previous
+        {
+            Some(sink) => console::set_sink(sink),
+            None => console::reset_sink(),
+        };
+        CAPTURE.take()
+    }
+
+    let was_enabled = trace::is_enabled();
+    trace::set_enabled(true);
+    // No trap or switch may land in the ring between the marks and the dump.
+    let (out, busy) = interrupt::with_disabled(|| {
+        let cpu = Cpu::get();
+        let out = capture(|| {
+            trace::dump(cpu);
+            (0..EVENTS).for_each(|i| trace::record(trace::EventKind::Mark(ID), [i, 0]));
+            trace::dump(cpu);
+        });
+        let held = cpu.trace.lock();
+        let busy = capture(|| trace::dump(cpu));
+        drop(held);
+        (out, busy)
+    });
+    trace::set_enabled(was_enabled);
+
+    // Everything up to the second header came from the first dump.
+    let Some((_, out)) = out.rsplit_once("events overwritten):")
+    else
+    {
+        return Err("no dump header");
+    };
+    let marks = out
+        .lines()
+        .filter_map(|line| {
+            // "<time> us  mark <id> <a> <b>"
+            let mut fields = line.split_whitespace().skip_while(|&f| f != "mark").skip(1);
+            if fields.next()?.parse::<u16>().ok()? != ID
+            {
+                return None;
+            }
+            usize::from_str_radix(fields.next()?.strip_prefix("0x")?, 16).ok()
+        })
+        .collect::<Vec<_>>();
+    check(
+        marks.len() == trace::CAPACITY,
+        "wrong number of events decoded",
+    )?;
+    check(
+        marks.iter().copied().eq(EVENTS - trace::CAPACITY..EVENTS),
+        "events out of order or not the newest",
+    )?;
+    check(busy.contains("ring busy"), "busy ring not skipped")
+}
+
+fn held_locks_per_task() -> Outcome
+{
+    use crate::sync::TicketMutex;
+
+    static SEEN: AtomicUsize = AtomicUsize::new(usize::MAX);
+    static NEVER: AtomicUsize = AtomicUsize::new(0);
+
+    let ms = timer::timebase_hz().unwrap_or(timer::DEFAULT_TIMEBASE_HZ) / 1000;
+
+    check(
+        sync::held_locks() == 0,
+        "locks counted before any was taken",
+    )?;
+    let lock = Mutex::new(());
+    let ticket = TicketMutex::new(());
+    let (one, two) = {
+        let _guard = lock.lock();
+        let one = sync::held_locks();
+        let _ticket = ticket.lock();
+        (one, sync::held_locks())
+    };
+    check(one == 1 && two == 2, "guards not counted")?;
+    check(sync::held_locks() == 0, "released guards still counted")?;
+
+    // Another task on this hart runs while this one is blocked holding a
+    // lock, and must not be charged for it.
+    let placement = task::placement();
+    task::set_placement(task::Placement::Local);
+    let observer = Task::spawn_joinable(|| SEEN.store(sync::held_locks(), Ordering::Relaxed));
+    task::set_placement(placement);
+
+    let held_across = {
+        let _guard = lock.lock();
+        sync::futex_wait_until(&NEVER, 0, timer::now() + 20 * ms);
+        sync::held_locks()
+    };
+    observer.join();
+
+    check(held_across == 1, "count lost across a switch")?;
+    check(
+        SEEN.load(Ordering::Relaxed) == 0,
+        "another task charged for this one's lock",
+    )?;
+    check(lock.try_lock().is_some(), "lock left held")?;
+    check(sync::held_locks() == 0, "count left behind")
+}
+
+fn low_band_ages() -> Outcome
+{
+    use crate::task::balance;
+
+    const RATE: usize = 2;
+    // Two bands to climb, with slack for tick jitter; without aging it waits
+    // out the spinner's 100 ticks.
+    const BOUND: u64 = 4 * RATE as u64 + 4;
+
+    static WAITED: AtomicUsize = AtomicUsize::new(usize::MAX);
+    static LOW_RAN: AtomicUsize = AtomicUsize::new(0);
+    static DONE: AtomicUsize = AtomicUsize::new(0);
+
+    fn finish()
+    {
+        DONE.fetch_add(1, Ordering::Release);
+        sync::futex_wake(&DONE, 1);
+    }
+
+    let tick = timer::timebase_hz().unwrap_or(timer::DEFAULT_TIMEBASE_HZ) / timer::TICK_HZ;
+    let rate = task::aging_rate();
+    task::set_aging_rate(RATE);
+    // Nothing may move the low task away from the load.
+    let was_enabled = balance::is_enabled();
+    balance::set_enabled(false);
+
+    let start = timer::now();
+    let here = spawn_here(|| {
+        [
+            // Never yields, so only aging lets the low task in; it still
+            // gives up after a while, so a failure can't hang the run.
+            Task::spawn_with_priority(Priority::High, move || {
+                while LOW_RAN.load(Ordering::Acquire) == 0 && timer::now() - start < 100 * tick
+                {
+                    spin_loop();
+                }
+                finish();
+            }),
+            Task::spawn_with_priority(Priority::Low, move || {
+                WAITED.store(((timer::now() - start) / tick) as usize, Ordering::Relaxed);
+                LOW_RAN.store(1, Ordering::Release);
+                finish();
+            }),
+        ]
+    });
+    wait_for(&DONE, 2);
+
+    balance::set_enabled(was_enabled);
+    task::set_aging_rate(rate);
+
+    if !here
+    {
+        return Ok(());
+    }
+    check(
+        WAITED.load(Ordering::Relaxed) as u64 <= BOUND,
+        "low-priority task starved past the aging bound",
+    )
+}
+
+fn heap_zeroed_and_resized() -> Outcome
+{
+    use alloc::alloc::{alloc, alloc_zeroed, dealloc, realloc};
+    use core::alloc::Layout;
+
+    const SMALL: usize = 256;
+    const GROWN: usize = 4096;
+    const SHRUNK: usize = 64;
+
+    let layout = Layout::from_size_align(SMALL, 8).map_err(|_| "bad layout")?;
+    let grown_layout = Layout::from_size_align(GROWN, 8).map_err(|_| "bad layout")?;
+    let shrunk_layout = Layout::from_size_align(SHRUNK, 8).map_err(|_| "bad layout")?;
+    let pattern = |i: usize| (i * 7 + 3) as u8;
+    let keeps = |block: *mut u8, len: usize| {
+        (0..len).all(|i| unsafe { block.add(i).read_volatile() } == pattern(i))
+    };
+
+    // Neighbours pin the lower blocks, so some of them move when grown and
+    // the topmost can grow in place.
+    let blocks = (0..16)
+        .map(|_| unsafe { alloc(layout) })
+        .collect::<Vec<_>>();
+    check(blocks.iter().all(|b| !b.is_null()), "allocation failed")?;
+    for &block in &blocks
+    {
+        for i in 0..SMALL
+        {
+            unsafe { block.add(i).write(pattern(i)) };
+        }
+    }
+
+    let mut outcome = Ok(());
+    for block in blocks
+    {
+        let grown = unsafe { realloc(block, layout, GROWN) };
+        if grown.is_null()
+        {
+            unsafe { dealloc(block, layout) };
+            outcome = outcome.and(Err("realloc grow failed"));
+            continue;
+        }
+        outcome = outcome.and(check(keeps(grown, SMALL), "grow lost the prefix"));
+
+        let shrunk = unsafe { realloc(grown, grown_layout, SHRUNK) };
+        if shrunk.is_null()
+        {
+            unsafe { dealloc(grown, grown_layout) };
+            outcome = outcome.and(Err("realloc shrink failed"));
+            continue;
+        }
+        outcome = outcome.and(check(keeps(shrunk, SHRUNK), "shrink lost the prefix"));
+        // Leave dirty bytes behind for the zeroed allocations below.
+        unsafe {
+            shrunk.write_bytes(0xa5, SHRUNK);
+            dealloc(shrunk, shrunk_layout);
+        }
+    }
+    outcome?;
+
+    // The freed blocks were dirty; zeroed ones carved from them must not be.
+    let zeroed = (0..16)
+        .map(|_| unsafe { alloc_zeroed(layout) })
+        .collect::<Vec<_>>();
+    let clean = zeroed.iter().all(|&block| {
+        !block.is_null() && (0..SMALL).all(|i| unsafe { block.add(i).read_volatile() } == 0)
+    });
+    for block in zeroed.into_iter().filter(|b| !b.is_null())
+    {
+        unsafe { dealloc(block, layout) };
+    }
+    check(clean, "alloc_zeroed returned dirty memory")
+}
+
+fn fault_retry_skip() -> Outcome
+{
+    use core::arch::asm;
+
+    use crate::{arch::cause::exceptions::BREAKPOINT, interrupt::fault};
+
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+    static FIRST_EPC: AtomicUsize = AtomicUsize::new(0);
+    static RETRIED_EPC: AtomicUsize = AtomicUsize::new(0);
+
+    // Retries once, which only traps again if the same instruction really
+    // runs again, then steps over it.
+    fn retry_once(_addr: usize, epc: usize) -> fault::FaultAction
+    {
+        match CALLS.fetch_add(1, Ordering::Relaxed)
+        {
+            0 =>
+            {
+                FIRST_EPC.store(epc, Ordering::Relaxed);
+                fault::FaultAction::Retry
+            }
+            _ =>
+            {
+                RETRIED_EPC.store(epc, Ordering::Relaxed);
+                fault::FaultAction::Skip
+            }
+        }
+    }
+
+    fn skip(_addr: usize, _epc: usize) -> fault::FaultAction
+    {
+        CALLS.fetch_add(1, Ordering::Relaxed);
+        fault::FaultAction::Skip
+    }
+
+    let previous = fault::register_handler(BREAKPOINT, retry_once);
+    CALLS.store(0, Ordering::Relaxed);
+    unsafe { asm!("ebreak") };
+    let retries = CALLS.load(Ordering::Relaxed);
+
+    // Each breakpoint is followed by an instruction of its own size, which
+    // only runs if the skip lands exactly on it: short by 2, the 4-byte
+    // `ebreak` would resume on its own upper half, an illegal instruction.
+    fault::register_handler(BREAKPOINT, skip);
+    CALLS.store(0, Ordering::Relaxed);
+    let standard: usize;
+    let compressed: usize;
+    unsafe {
+        asm!(
+            ".option push",
+            ".option norvc",
+            "li {standard}, 0",
+            "ebreak",
+            "li {standard}, 1",
+            ".option pop",
+            ".option push",
+            ".option rvc",
+            "li {compressed}, 0",
+            "c.ebreak",
+            "c.li {compressed}, 1",
+            ".option pop",
+            standard = out(reg) standard,
+            compressed = out(reg) compressed,
+        )
+    };
+    let skips = CALLS.load(Ordering::Relaxed);
+
+    match previous
+    {
+        Some(handler) => fault::register_handler(BREAKPOINT, handler),
+        None => fault::unregister_handler(BREAKPOINT),
+    };
+
+    check(retries == 2, "retried instruction did not trap again")?;
+    check(
+        FIRST_EPC.load(Ordering::Relaxed) == RETRIED_EPC.load(Ordering::Relaxed),
+        "retry resumed somewhere else",
+    )?;
+    check(skips == 2, "skipped breakpoints not both taken")?;
+    check(
+        standard == 1,
+        "skip over a 4-byte instruction missed its successor",
+    )?;
+    check(
+        compressed == 1,
+        "skip over a 2-byte instruction missed its successor",
+    )
+}
+
+fn ticket_fifo_order() -> Outcome
+{
+    use crate::sync::TicketMutex;
+
+    const WAITERS: usize = 4;
+
+    static LOCK: TicketMutex<Vec<usize>> = TicketMutex::new(Vec::new());
+
+    let ms = timer::timebase_hz().unwrap_or(timer::DEFAULT_TIMEBASE_HZ) / 1000;
+
+    // Hold the lock while the waiters queue one at a time, so their ticket
+    // order is known.
+    let mut guard = LOCK.lock();
+    guard.clear();
+    let mut queued = true;
+    let mut waiters = Vec::with_capacity(WAITERS);
+    for index in 0..WAITERS
+    {
+        waiters.push(Task::spawn_joinable(move || LOCK.lock().push(index)));
+
+        let deadline = timer::now() + 500 * ms;
+        while LOCK.waiters() <= index && timer::now() < deadline
+        {
+            task::yield_now();
+        }
+        queued &= LOCK.waiters() == index + 1;
+    }
+    drop(guard);
+
+    for waiter in waiters
+    {
+        waiter.join();
+    }
+    check(queued, "waiter never queued")?;
+    check(
+        LOCK.lock().iter().copied().eq(0..WAITERS),
+        "lock not granted in request order",
+    )
+}
+
+fn rng_seeding() -> Outcome
+{
+    use crate::arch::Rng;
+
+    const DRAWS: usize = 16;
+
+    static FIRST: Mutex<Vec<(usize, usize)>> = Mutex::new(Vec::new());
+
+    let draws = |rng: &Rng| (0..DRAWS).map(|_| rng.next()).collect::<Vec<_>>();
+    let seeded = |seed: usize| {
+        let rng = Rng::new();
+        rng.seed(seed);
+        draws(&rng)
+    };
+
+    check(seeded(42) == seeded(42), "same seed, different sequence")?;
+    check(seeded(42) != seeded(43), "different seeds, same sequence")?;
+    check(
+        seeded(0) == draws(&Rng::new()),
+        "zero seed not replaced by the default",
+    )?;
+    check(
+        seeded(0).iter().all(|&x| x != 0),
+        "generator reached the zero state",
+    )?;
+
+    // Round-robin placement visits every hart in turn, so each of these lands
+    // on a different one and takes the first draw from its generator.
+    FIRST.lock().clear();
+    let harts = arch::online_cpus().count();
+    let placement = task::placement();
+    task::set_placement(task::Placement::RoundRobin);
+    let tasks = (0..harts)
+        .map(|_| {
+            Task::spawn_joinable(|| {
+                let draw = arch::random();
+                FIRST.lock().push((arch::logical_hart_id(), draw));
+            })
+        })
+        .collect::<Vec<_>>();
+    task::set_placement(placement);
+    for task in tasks
+    {
+        task.join();
+    }
+
+    let mut first = core::mem::take(&mut *FIRST.lock());
+    first.sort_unstable();
+    first.dedup_by_key(|&mut (hart, _)| hart);
+    let diverged = first
+        .iter()
+        .enumerate()
+        .all(|(i, &(_, a))| first[i + 1..].iter().all(|&(_, b)| a != b));
+    check(diverged, "two harts drew the same first value")
+}
+
+fn ram_disk_transfers() -> Outcome
+{
+    use crate::drivers::block::{BLOCK_SIZE, BlockDevice, BlockError, RamDisk};
+
+    const BLOCKS: usize = 4;
+
+    let mut disk = RamDisk::new(BLOCKS);
+    check(disk.block_count() == BLOCKS, "wrong block count")?;
+
+    let written = core::array::from_fn::<u8, BLOCK_SIZE, _>(|i| i as u8 ^ 0x5a);
+    let mut read = [0; BLOCK_SIZE];
+    check(disk.write_block(2, &written).is_ok(), "write failed")?;
+    check(disk.read_block(2, &mut read).is_ok(), "read failed")?;
+    check(read == written, "written block not read back")?;
+    check(
+        disk.read_block(1, &mut read).is_ok() && read.iter().all(|&b| b == 0),
+        "write spilled into a neighbour",
+    )?;
+
+    check(
+        disk.read_block(BLOCKS, &mut read) == Err(BlockError::OutOfRange),
+        "read past the end accepted",
+    )?;
+    check(
+        disk.write_block(BLOCKS, &written) == Err(BlockError::OutOfRange),
+        "write past the end accepted",
+    )?;
+
+    let mut span = vec![0xa5; 2 * BLOCK_SIZE];
+    check(disk.write_blocks(1, &span).is_ok(), "span write failed")?;
+    span.fill(0);
+    check(
+        disk.read_blocks(1, &mut span).is_ok() && span.iter().all(|&b| b == 0xa5),
+        "span not read back",
+    )?;
+    check(
+        disk.read_blocks(0, &mut span[..BLOCK_SIZE + 1]) == Err(BlockError::Misaligned),
+        "partial block read accepted",
+    )?;
+    check(
+        disk.write_blocks(0, &span[..BLOCK_SIZE - 1]) == Err(BlockError::Misaligned),
+        "partial block write accepted",
+    )?;
+    check(
+        disk.write_blocks(BLOCKS - 1, &span) == Err(BlockError::OutOfRange),
+        "span past the end accepted",
+    )?;
+    check(
+        disk.read_block(BLOCKS - 1, &mut read).is_ok() && read.iter().all(|&b| b == 0),
+        "rejected span partly written",
+    )?;
+
+    // An image that isn't a whole number of blocks is padded with zeros.
+    let mut image = RamDisk::from_image(&[0xff; BLOCK_SIZE + 3]);
+    check(image.block_count() == 2, "image not rounded up to a block")?;
+    check(image.read_block(1, &mut read).is_ok(), "image read failed")?;
+    check(
+        read[..3] == [0xff; 3] && read[3..].iter().all(|&b| b == 0),
+        "image tail not zero-padded",
+    )
+}
+
+fn virtio_ring_and_probe() -> Outcome
+{
+    use crate::{
+        drivers::{
+            block::{BLOCK_SIZE, BlockDevice, BlockError},
+            virtio::{self, VirtioBlock},
+        },
+        soc::virtio::*,
+    };
+
+    const SECTORS: u32 = 64;
+    const RO: u32 = 1 << 5;
+
+    virtio::check_ring_wrap()?;
+
+    // RAM standing in for a transport, which reads back whatever was written:
+    // enough for the handshake, as no status bit is ever refused.
+    let transport = |version: u32, features: u32| {
+        let mut regs = vec![0_u32; CONFIG_OFFSET / 4 + 2];
+        regs[MAGIC_VALUE_OFFSET / 4] = MAGIC;
+        regs[VERSION_OFFSET / 4] = version;
+        regs[DEVICE_ID_OFFSET / 4] = DEVICE_ID_BLOCK;
+        regs[DEVICE_FEATURES_OFFSET / 4] = features;
+        regs[QUEUE_NUM_MAX_OFFSET / 4] = 16;
+        regs[CONFIG_OFFSET / 4] = SECTORS;
+        regs
+    };
+    let probe = |regs: &mut [u32]| VirtioBlock::probe(regs.as_mut_ptr() as usize);
+
+    let mut legacy_regs = transport(1, RO);
+    let mut legacy = probe(&mut legacy_regs).ok_or("legacy device rejected")?;
+    let at = |regs: &[u32], offset: usize| regs[offset / 4];
+    check(
+        legacy.block_count() == SECTORS as usize * 512 / BLOCK_SIZE,
+        "legacy capacity misread",
+    )?;
+    check(
+        at(&legacy_regs, GUEST_PAGE_SIZE_OFFSET) == arch::PAGE_SIZE as u32
+            && at(&legacy_regs, QUEUE_PFN_OFFSET) != 0,
+        "legacy queue not set up by page frame",
+    )?;
+    check(
+        at(&legacy_regs, STATUS_OFFSET) & status::FEATURES_OK == 0
+            && at(&legacy_regs, STATUS_OFFSET) & status::DRIVER_OK != 0,
+        "legacy handshake wrong",
+    )?;
+    check(
+        legacy.write_block(0, &[0; BLOCK_SIZE]) == Err(BlockError::Io),
+        "write to a read-only device accepted",
+    )?;
+    drop(legacy);
+
+    let mut modern_regs = transport(2, 0);
+    let modern = probe(&mut modern_regs).ok_or("modern device rejected")?;
+    check(
+        at(&modern_regs, STATUS_OFFSET) & (status::FEATURES_OK | status::DRIVER_OK)
+            == status::FEATURES_OK | status::DRIVER_OK,
+        "modern handshake wrong",
+    )?;
+    check(
+        at(&modern_regs, DRIVER_FEATURES_OFFSET) == 1,
+        "modern device not offered VERSION_1",
+    )?;
+    check(
+        at(&modern_regs, QUEUE_READY_OFFSET) == 1
+            && at(&modern_regs, QUEUE_PFN_OFFSET) == 0
+            && at(&modern_regs, QUEUE_DESC_LOW_OFFSET) != 0,
+        "modern queue not set up by address",
+    )?;
+    drop(modern);
+
+    let mut unknown = transport(3, 0);
+    check(probe(&mut unknown).is_none(), "unknown version accepted")?;
+    let mut not_virtio = transport(1, 0);
+    not_virtio[MAGIC_VALUE_OFFSET / 4] = 0;
+    check(probe(&mut not_virtio).is_none(), "bad magic accepted")
+}
+
+fn large_task_stack() -> Outcome
+{
+    const STACK: usize = 64 * 1024;
+    const DEFAULT_STACK: usize = 16 * 1024;
+    const USED: usize = 48 * 1024;
+
+    static DEPTH: AtomicUsize = AtomicUsize::new(0);
+    static DONE: AtomicUsize = AtomicUsize::new(0);
+
+    #[inline(never)]
+    fn deep(top: usize) -> usize
+    {
+        // Filled in place: passing the array by value could copy it.
+        let mut frame = [0_u8; USED];
+        for (i, byte) in frame.iter_mut().enumerate()
+        {
+            *byte = i as u8;
+        }
+        let intact = black_box(&frame)
+            .iter()
+            .enumerate()
+            .all(|(i, &b)| b == i as u8);
+        if intact
+        {
+            top - frame.as_ptr() as usize
+        }
+        else
+        {
+            0
+        }
+    }
+
+    let ms = timer::timebase_hz().unwrap_or(timer::DEFAULT_TIMEBASE_HZ) / 1000;
+
+    DONE.store(0, Ordering::Relaxed);
+    let id = Task::spawn_with_stack(STACK, || {
+        let top = black_box(0_u8);
+        DEPTH.store(deep(&top as *const u8 as usize), Ordering::Relaxed);
+        DONE.store(1, Ordering::Release);
+        sync::futex_wake(&DONE, 1);
+    });
+    wait_for(&DONE, 1);
+
+    let deadline = timer::now() + 500 * ms;
+    while task::state_of(id).is_some_and(|state| state != TaskState::Dead)
+        && timer::now() < deadline
+    {
+        task::yield_now();
+    }
+
+    let depth = DEPTH.load(Ordering::Relaxed);
+    check(depth != 0, "deep frame corrupted")?;
+    check(
+        depth > DEFAULT_STACK,
+        "frame never went past a default-sized stack",
+    )?;
+    check(
+        task::state_of(id).is_none_or(|state| state == TaskState::Dead),
+        "task did not exit",
+    )
+}
+
+fn trap_stack_canary() -> Outcome
+{
+    check(Cpu::get().trap_stack_intact(), "canary missing at rest")?;
+
+    // No trap may run while it is clobbered, or the real check would fire.
+    let (caught, restored) = interrupt::with_disabled(|| {
+        let cpu = Cpu::get();
+        let bottom = cpu.trap_stack_bottom();
+        let canary = unsafe { bottom.read_volatile() };
+
+        unsafe { bottom.write_volatile(!canary) };
+        let caught = !cpu.trap_stack_intact();
+        unsafe { bottom.write_volatile(canary) };
+        (caught, cpu.trap_stack_intact())
+    });
+
+    check(caught, "clobbered canary not detected")?;
+    check(restored, "restored canary not accepted")
+}
+
+fn deferred_on_worker() -> Outcome
+{
+    use crate::{
+        interrupt::deferred::{self, DeferError},
+        task::TaskKind,
+    };
+
+    static RAN: AtomicUsize = AtomicUsize::new(0);
+    static MISPLACED: AtomicUsize = AtomicUsize::new(0);
+
+    fn work(hart: usize)
+    {
+        let cpu = Cpu::get();
+        let on_worker = interrupt::with_disabled(|| {
+            matches!(cpu.scheduler.lock().task().kind, TaskKind::Worker { .. })
+        });
+        if !on_worker || cpu.trap_depth.load(Ordering::Relaxed) != 0 || cpu.logical_id != hart
+        {
+            MISPLACED.fetch_add(1, Ordering::Relaxed);
+        }
+        RAN.fetch_add(1, Ordering::Release);
+        sync::futex_wake(&RAN, 1);
+    }
+
+    RAN.store(0, Ordering::Relaxed);
+    MISPLACED.store(0, Ordering::Relaxed);
+
+    // With interrupts off the worker can't drain the queue, so it fills up.
+    let (queued, overflow) = interrupt::with_disabled(|| {
+        let hart = arch::logical_hart_id();
+        let mut queued = 0;
+        loop
+        {
+            match deferred::defer(work, hart)
+            {
+                Ok(()) => queued += 1,
+                Err(error) => break (queued, error),
+            }
+        }
+    });
+    check(overflow == DeferError::Full, "full queue not reported")?;
+    check(
+        (1..=deferred::CAPACITY).contains(&queued),
+        "queue held the wrong number of entries",
+    )?;
+
+    wait_for(&RAN, queued);
+    check(
+        MISPLACED.load(Ordering::Relaxed) == 0,
+        "deferred work ran outside the hart's worker or in a trap",
+    )
+}
+
+fn timer_interrupts_counted() -> Outcome
+{
+    use crate::arch::cause::interrupts::SUPERVISOR_TIMER_INTERRUPT;
+
+    const TICKS: u64 = 5;
+
+    static NEVER: AtomicUsize = AtomicUsize::new(0);
+
+    let tick = timer::timebase_hz().unwrap_or(timer::DEFAULT_TIMEBASE_HZ) / timer::TICK_HZ;
+    let timer_count = || interrupt::stats().interrupts[SUPERVISOR_TIMER_INTERRUPT];
+
+    // Every online hart ticks throughout, so the sum grows at least as fast
+    // as any one of them; a spare tick covers the partial ones at each end.
+    let before = timer_count();
+    sync::futex_wait_until(&NEVER, 0, timer::now() + (TICKS + 1) * tick);
+    let after = timer_count();
+
+    check(
+        after - before >= TICKS as usize,
+        "fewer timer interrupts counted than ticks elapsed",
+    )
+}
+
+fn idle_hook_only_idle() -> Outcome
+{
+    use crate::task::{TaskKind, balance};
+
+    const TICKS: u64 = 5;
+
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+    static WHILE_RUNNABLE: AtomicUsize = AtomicUsize::new(0);
+    static STARTED: AtomicUsize = AtomicUsize::new(0);
+    static STOP: AtomicUsize = AtomicUsize::new(0);
+    static NEVER: AtomicUsize = AtomicUsize::new(0);
+
+    fn hook()
+    {
+        let scheduler = Cpu::get().scheduler.lock();
+        if scheduler.has_waiting() || scheduler.task().kind != TaskKind::Idle
+        {
+            WHILE_RUNNABLE.fetch_add(1, Ordering::Relaxed);
+        }
+        CALLS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    let tick = timer::timebase_hz().unwrap_or(timer::DEFAULT_TIMEBASE_HZ) / timer::TICK_HZ;
+    let nap = || sync::futex_wait_until(&NEVER, 0, timer::now() + TICKS * tick);
+
+    // The hook is per hart, so neither this task nor the spinner may move.
+    let was_enabled = balance::is_enabled();
+    balance::set_enabled(false);
+    let previous = task::set_idle_hook(hook);
+    CALLS.store(0, Ordering::Relaxed);
+    WHILE_RUNNABLE.store(0, Ordering::Relaxed);
+    STARTED.store(0, Ordering::Relaxed);
+    STOP.store(0, Ordering::Relaxed);
+
+    // Nothing else to run here while this task sleeps.
+    nap();
+    let idle_calls = CALLS.load(Ordering::Relaxed);
+
+    let placement = task::placement();
+    task::set_placement(task::Placement::Local);
+    let spinner = Task::spawn_joinable(|| {
+        STARTED.store(1, Ordering::Release);
+        while STOP.load(Ordering::Acquire) == 0
+        {
+            spin_loop();
+        }
+    });
+    task::set_placement(placement);
+
+    while STARTED.load(Ordering::Acquire) == 0
+    {
+        task::yield_now();
+    }
+    let before = CALLS.load(Ordering::Relaxed);
+    nap();
+    let busy_calls = CALLS.load(Ordering::Relaxed) - before;
+    STOP.store(1, Ordering::Release);
+    spinner.join();
+
+    match previous
+    {
+        Some(hook) => task::set_idle_hook(hook),
+        None => task::clear_idle_hook(),
+    };
+    balance::set_enabled(was_enabled);
+
+    check(idle_calls > 0, "hook never ran on an idle hart")?;
+    check(busy_calls == 0, "hook ran while a task was runnable")?;
+    check(
+        WHILE_RUNNABLE.load(Ordering::Relaxed) == 0,
+        "hook saw runnable work",
+    )
+}
+
+fn nested_boot_phases() -> Outcome
+{
+    use crate::console::Capture;
+
+    const INNER_US: u64 = 2000;
+
+    static CAPTURE: Capture = Capture::new();
+
+    let hz = timer::timebase_hz().unwrap_or(timer::DEFAULT_TIMEBASE_HZ);
+
+    let previous = console::set_sink(&CAPTURE);
+    {
+        let _outer = boot::phase("selftest outer");
+        let _inner = boot::phase("selftest inner");
+        let deadline = timer::now() + (INNER_US * hz).div_ceil(1_000_000);
+        while timer::now() < deadline
+        {
+            spin_loop();
+        }
+    }
+    match previous
+    {
+        Some(sink) => console::set_sink(sink),
+        None => console::reset_sink(),
+    };
+    let out = CAPTURE.take();
+
+    // Other harts may log meanwhile; only these phases' lines count.
+    let lines = out
+        .lines()
+        .filter(|l| l.contains("selftest outer") || l.contains("selftest inner"))
+        .collect::<Vec<_>>();
+    let order = lines
+        .iter()
+        .map(|l| (l.contains("Entering"), l.contains("selftest outer")))
+        .collect::<Vec<_>>();
+    check(
+        order == [(true, true), (true, false), (false, false), (false, true)],
+        "phases not entered and completed innermost-last",
+    )?;
+
+    let took = |line: &str| {
+        line.split_once(" in ")
+            .and_then(|(_, rest)| rest.strip_suffix(" us."))
+            .and_then(|n| n.parse::<u64>().ok())
+    };
+    let (Some(inner), Some(outer)) = (took(lines[2]), took(lines[3]))
+    else
+    {
+        return Err("phase timing not reported in us");
+    };
+    check(inner >= INNER_US, "inner phase timed too short")?;
+    check(
+        outer >= inner,
+        "outer phase shorter than the one it contains",
+    )
+}
+
+fn placement_policies() -> Outcome
+{
+    use crate::task::Placement;
+
+    static DONE: AtomicUsize = AtomicUsize::new(0);
+
+    fn finish()
+    {
+        DONE.fetch_add(1, Ordering::Release);
+        sync::futex_wake(&DONE, 1);
+    }
+
+    // Suspended tasks sit on the hart they were placed on without counting
+    // toward its load, and nothing rebalances them.
+    let hart_of = |id: TaskId| {
+        arch::online_cpus()
+            .find(|cpu| interrupt::with_disabled(|| cpu.scheduler.lock().state_of(id).is_some()))
+            .map(|cpu| cpu.logical_id)
+    };
+    let spawn = |policy: Placement, count: usize| {
+        let placement = task::placement();
+        task::set_placement(policy);
+        let handles = (0..count)
+            .map(|_| Task::spawn_suspended(finish))
+            .collect::<Vec<_>>();
+        task::set_placement(placement);
+        handles
+    };
+
+    let online = arch::online_cpus().count();
+    DONE.store(0, Ordering::Relaxed);
+
+    let (here, local) =
+        task::without_preemption(|| (arch::logical_hart_id(), spawn(Placement::Local, 3)));
+    let spread = spawn(Placement::RoundRobin, online);
+
+    let local_harts = local.iter().map(|h| hart_of(h.id())).collect::<Vec<_>>();
+    let mut spread_harts = spread.iter().map(|h| hart_of(h.id())).collect::<Vec<_>>();
+    spread_harts.sort_unstable();
+    spread_harts.dedup();
+
+    for handle in local.iter().chain(&spread)
+    {
+        handle.resume();
+    }
+    wait_for(&DONE, local.len() + spread.len());
+
+    check(
+        local_harts.iter().all(|&hart| hart == Some(here)),
+        "local placement left the spawning hart",
+    )?;
+    check(
+        spread_harts.len() == online && spread_harts.iter().all(Option::is_some),
+        "round-robin placement missed a hart",
+    )
+}
+
+fn plic_math() -> Outcome
+{
+    use crate::platform::plic;
+
+    // Each hart has an M-mode context, then its S-mode one.
+    const CONTEXTS: [(usize, usize); 4] = [(0, 1), (1, 3), (2, 5), (7, 15)];
+    // `(hart, irq, enable word address, bit)` on QEMU virt, whose enables
+    // start at 0x0c00_2000 with 0x80 bytes per context.
+    const ENABLES: [(usize, u32, usize, u32); 7] = [
+        (0, 1, 0x0c00_2080, 1 << 1),
+        (0, 31, 0x0c00_2080, 1 << 31),
+        (0, 32, 0x0c00_2084, 1 << 0),
+        (0, 33, 0x0c00_2084, 1 << 1),
+        (1, 10, 0x0c00_2180, 1 << 10),
+        (1, 33, 0x0c00_2184, 1 << 1),
+        (2, 1023, 0x0c00_237c, 1 << 31),
+    ];
+
+    check(
+        CONTEXTS
+            .iter()
+            .all(|&(hart, context)| plic::get_context(hart) == context),
+        "hart mapped to the wrong context",
+    )?;
+    check(
+        ENABLES
+            .iter()
+            .all(|&(hart, irq, addr, bit)| plic::enable_location(hart, irq) == (addr, bit)),
+        "source mapped to the wrong enable word or bit",
+    )
+}
+
+fn trap_cause_names() -> Outcome
+{
+    use crate::arch::cause;
+
+    const INTERRUPTS: &[(usize, &str)] = &[
+        (1, "Supervisor software interrupt"),
+        (3, "Machine software interrupt"),
+        (5, "Supervisor timer interrupt"),
+        (7, "Machine timer interrupt"),
+        (9, "Supervisor external interrupt"),
+        (11, "Machine external interrupt"),
+        (13, "Counter overflow interrupt"),
+        (0, "Reserved interrupt"),
+        (15, "Reserved interrupt"),
+        (16, "Platform-specific interrupt"),
+    ];
+    const EXCEPTIONS: &[(usize, &str)] = &[
+        (0, "Instruction address misaligned"),
+        (1, "Instruction access fault"),
+        (2, "Illegal instruction"),
+        (3, "Breakpoint"),
+        (4, "Load address misaligned"),
+        (5, "Load access fault"),
+        (6, "Store/AMO address misaligned"),
+        (7, "Store/AMO access fault"),
+        (8, "Environment call from U-mode"),
+        (9, "Environment call from S-mode"),
+        (11, "Environment call from M-mode"),
+        (12, "Instruction page fault"),
+        (13, "Load page fault"),
+        (15, "Store/AMO page fault"),
+        (18, "Software check"),
+        (19, "Hardware error"),
+        (24, "Custom exception"),
+        (63, "Custom exception"),
+        (10, "Reserved exception"),
+        (64, "Reserved exception"),
+    ];
+
+    check(
+        INTERRUPTS
+            .iter()
+            .all(|&(code, name)| cause::describe_interrupt(code) == name),
+        "interrupt code misnamed",
+    )?;
+    check(
+        EXCEPTIONS
+            .iter()
+            .all(|&(code, name)| cause::describe_exception(code) == name),
+        "exception code misnamed",
+    )?;
+    check(
+        cause::describe(cause::INTERRUPT_FLAG | 5) == "Supervisor timer interrupt"
+            && cause::describe(5) == "Load access fault",
+        "interrupt flag not honoured",
+    )
+}
+
+fn malformed_device_trees() -> Outcome
+{
+    use ::fdt::FdtError;
+
+    let mut dtb = DtbWriter::default();
+    dtb.begin("");
+    dtb.cells("#address-cells", &[1]);
+    dtb.end();
+    let blob = dtb.finish();
+    let parse = |blob: &[u64]| fdt::fdt(blob.as_ptr().cast()).err();
+
+    check(parse(&blob).is_none(), "well-formed DTB rejected")?;
+
+    // The first word holds the big-endian magic and totalsize, in that order.
+    let (magic, total) = (blob[0] as u32, (blob[0] >> 32) as u32);
+    let with_header = |magic: u32, total: u32| {
+        let mut bad = blob.clone();
+        bad[0] = magic as u64 | (total as u64) << 32;
+        bad
+    };
+
+    for bad_magic in [0, magic ^ 1, magic.swap_bytes()]
+    {
+        check(
+            matches!(
+                parse(&with_header(bad_magic, total)),
+                Some(FdtError::BadMagic)
+            ),
+            "bad magic accepted",
+        )?;
+    }
+
+    let length = u32::from_be(total);
+    for truncated in [0, 8, 40, length / 2, length - 1]
+    {
+        check(
+            matches!(
+                parse(&with_header(magic, truncated.to_be())),
+                Some(FdtError::BufferTooSmall)
+            ),
+            "truncated totalsize accepted",
+        )?;
+    }
+
+    let misaligned = unsafe { blob.as_ptr().cast::<u8>().add(4) };
+    check(
+        matches!(fdt::fdt(misaligned), Err(FdtError::BadPtr)),
+        "misaligned pointer accepted",
+    )?;
+    check(
+        matches!(fdt::fdt(core::ptr::null()), Err(FdtError::BadPtr)),
+        "null pointer accepted",
+    )
+}
+
+fn log_colors() -> Outcome
+{
+    use crate::{console::Capture, logger};
+
+    static CAPTURE: Capture = Capture::new();
+
+    let color = logger::color();
+    let previous = console::set_sink(&CAPTURE);
+    logger::set_color(true);
+    log::info!("selftest colored record");
+    logger::set_color(false);
+    log::info!("selftest plain record");
+    logger::set_color(color);
+    match previous
+    {
+        Some(sink) => console::set_sink(sink),
+        None => console::reset_sink(),
+    };
+    let out = CAPTURE.take();
+
+    // Other harts may log meanwhile; only these records count.
+    let line = |marker: &str| out.lines().find(|l| l.contains(marker));
+    let (Some(colored), Some(plain)) = (
+        line("selftest colored record"),
+        line("selftest plain record"),
+    )
+    else
+    {
+        return Err("record not printed");
+    };
+
+    check(
+        colored.contains("\x1b["),
+        "colored record has no ANSI escapes",
+    )?;
+    check(!plain.contains('\x1b'), "plain record has ANSI escapes")?;
+    check(
+        plain.starts_with("[INFO] (") && plain.ends_with(") selftest plain record"),
+        "plain record misformatted",
+    )
+}
+
+fn boot_banner_decoding() -> Outcome
+{
+    use alloc::format;
+
+    use crate::boot::{Id, Isa};
+
+    let misa = |xlen: usize, letters: &str| {
+        letters
+            .bytes()
+            .fold(xlen << (usize::BITS - 2), |misa, l| misa | 1 << (l - b'a'))
+    };
+    let cases = [
+        (misa(2, "imafdcsu"), "rv64imafdc"),
+        (misa(1, "imacsu"), "rv32imac"),
+        (misa(2, "iv"), "rv64iv"),
+        // Canonical order first, then the rest alphabetically.
+        (misa(2, "cdfimahx"), "rv64imafdchx"),
+        (misa(1, "e"), "rv32e"),
+        (misa(0, "i"), "rv?i"),
+    ];
+
+    check(
+        cases
+            .iter()
+            .all(|&(value, name)| format!("{}", Isa(value)) == name),
+        "misa decoded to the wrong ISA string",
+    )?;
+    check(
+        format!("{}", Id(0)) == "n/a" && format!("{}", Id(0x489)) == "0x489",
+        "ID registers misformatted",
+    )
+}
+
+fn hart_loads_reported() -> Outcome
+{
+    use crate::task::balance;
+
+    const QUEUED: usize = 3;
+
+    static DONE: AtomicUsize = AtomicUsize::new(0);
+
+    fn finish()
+    {
+        DONE.fetch_add(1, Ordering::Release);
+        sync::futex_wake(&DONE, 1);
+    }
+
+    let load_of = |hart: usize| {
+        arch::hart_loads()
+            .into_iter()
+            .find_map(|(id, load)| (id == hart).then_some(load))
+    };
+
+    DONE.store(0, Ordering::Relaxed);
+    let was_enabled = balance::is_enabled();
+    balance::set_enabled(false);
+    let placement = task::placement();
+    task::set_placement(task::Placement::Local);
+
+    // Suspended tasks don't count toward the load, so all of them go where
+    // the first one does; once resumed they wait behind this task until it
+    // can be switched out.
+    let (stayed, before, after) = task::without_preemption(|| {
+        let here = arch::logical_hart_id();
+        let before = load_of(here);
+        let handles = (0..QUEUED)
+            .map(|_| Task::spawn_suspended(finish))
+            .collect::<Vec<_>>();
+        for handle in &handles
+        {
+            handle.resume();
+        }
+        let stayed = handles.iter().all(|handle| handle.hart() == here);
+        (stayed, before, load_of(here))
+    });
+
+    task::set_placement(placement);
+    wait_for(&DONE, QUEUED);
+    balance::set_enabled(was_enabled);
+
+    let (Some(before), Some(after)) = (before, after)
+    else
+    {
+        return Err("this hart missing from the loads");
+    };
+    // Placed elsewhere because this queue was already long, they were free to
+    // run at once, so the load proves nothing.
+    if stayed
+    {
+        check(
+            after == before + QUEUED,
+            "queued tasks not reflected in the load",
+        )?;
+    }
+    check(
+        arch::hart_loads().len() == arch::online_cpus().count(),
+        "not every online hart reported",
+    )
+}
@@ -1,10 +1,140 @@
 //! SoC register layout constants.
+//!
+//! The maps are typed in by hand, so each module checks its own against the
+//! spec in a `const` block: a wrong or overlapping offset fails the build
+//! instead of pointing a driver at the wrong register.
 
 // Registers for Hart 0 M-Mode
 pub mod plic
 {
     pub const BASE: usize = 0x0c00_0000;
+    pub const PRIORITY_BASE: usize = BASE;
+    pub const ENABLE_BASE: usize = BASE + 0x2000;
+    pub const ENABLE_STRIDE: usize = 0x80; // Per context
     pub const THRESHOLD_BASE: usize = BASE + 0x200000;
+    pub const CLAIM_OFFSET: usize = 4; // From each context's threshold register
+    pub const CONTEXT_STRIDE: usize = 0x1000;
+
+    pub const MAX_SOURCES: u32 = 1024; // Source 0 is reserved
+    pub const MAX_PRIORITY: u32 = 7;
+
+    /// Contexts the PLIC spec leaves room for in the enable and threshold
+    /// arrays.
+    pub const MAX_CONTEXTS: usize = 15872;
+
+    const PENDING_OFFSET: usize = 0x1000;
+    const SPAN: usize = 0x400_0000;
+
+    const _: () = check_map(
+        BASE,
+        PRIORITY_BASE,
+        ENABLE_BASE,
+        ENABLE_STRIDE,
+        THRESHOLD_BASE,
+        CLAIM_OFFSET,
+        CONTEXT_STRIDE,
+        MAX_SOURCES,
+    );
+    const _: () = assert!(MAX_PRIORITY > 0, "PLIC priority 0 means never interrupt");
+
+    /// Fail const evaluation unless a PLIC map matches the spec layout:
+    /// priorities, pending bits, per-context enables, then per-context
+    /// threshold/claim pairs, each region ending before the next begins.
+    ///
+    /// `just check-maps` builds `checks/plic_map_rejected.rs`, a map with the
+    /// enable words one page too high, on the host and expects this to reject
+    /// it.
+    #[allow(clippy::too_many_arguments)]
+    pub const fn check_map(
+        base: usize,
+        priority_base: usize,
+        enable_base: usize,
+        enable_stride: usize,
+        threshold_base: usize,
+        claim_offset: usize,
+        context_stride: usize,
+        max_sources: u32,
+    )
+    {
+        let sources = max_sources as usize;
+
+        assert!(
+            base.is_multiple_of(SPAN),
+            "PLIC base must be 64 MiB aligned"
+        );
+        assert!(priority_base == base, "PLIC priorities start at the base");
+        assert!(
+            sources.is_multiple_of(32) && sources * 4 <= PENDING_OFFSET,
+            "PLIC priorities overlap the pending bits"
+        );
+        assert!(
+            enable_base - base == 0x2000,
+            "PLIC enables start at base + 0x2000"
+        );
+        assert!(enable_stride == 0x80, "PLIC enable blocks are 0x80 apart");
+        assert!(
+            sources / 8 <= enable_stride,
+            "PLIC sources overflow an enable block"
+        );
+        assert!(
+            enable_base + MAX_CONTEXTS * enable_stride <= threshold_base,
+            "PLIC enables overlap the thresholds"
+        );
+        assert!(
+            threshold_base - base == 0x20_0000,
+            "PLIC thresholds start at base + 0x200000"
+        );
+        assert!(context_stride == 0x1000, "PLIC contexts are 0x1000 apart");
+        assert!(
+            claim_offset == 4,
+            "PLIC claim sits right after the threshold"
+        );
+        assert!(
+            threshold_base + MAX_CONTEXTS * context_stride <= base + SPAN,
+            "PLIC contexts run past the end of the device"
+        );
+    }
+}
+
+/// Core-Local Interruptor (CLINT) timer registers
+pub mod clint
+{
+    pub const BASE: usize = 0x0200_0000;
+    pub const MTIMECMP_BASE: usize = BASE + 0x4000;
+    pub const MTIMECMP_STRIDE: usize = 8; // One 64-bit register per hart
+    pub const MTIME: usize = BASE + 0xbff8;
+
+    /// Harts the CLINT layout leaves room for.
+    pub const MAX_HARTS: usize = 4095;
+
+    const MSIP_STRIDE: usize = 4;
+
+    const _: () = {
+        assert!(
+            BASE.is_multiple_of(0x1_0000),
+            "CLINT base must be 64 KiB aligned"
+        );
+        assert!(
+            MAX_HARTS * MSIP_STRIDE <= MTIMECMP_BASE - BASE,
+            "CLINT msip overlaps mtimecmp"
+        );
+        assert!(
+            MTIMECMP_BASE - BASE == 0x4000,
+            "CLINT mtimecmp starts at base + 0x4000"
+        );
+        assert!(MTIMECMP_STRIDE == 8, "CLINT mtimecmp registers are 64-bit");
+        assert!(
+            MTIMECMP_BASE + MAX_HARTS * MTIMECMP_STRIDE <= MTIME,
+            "CLINT mtimecmp overlaps mtime"
+        );
+        assert!(MTIME - BASE == 0xbff8, "CLINT mtime sits at base + 0xbff8");
+    };
+}
+
+/// ACLINT supervisor software interrupt device (SSWI)
+pub mod aclint
+{
+    pub const SETSSIP_STRIDE: usize = 4; // One 32-bit register per hart
 }
 
 /// Universal Asynchronous Receiver/Transmitter (UART) constants
@@ -23,5 +153,112 @@ pub mod uart
 
         pub const RX_READY: u8 = 1 << 0; // The Data Ready bit
         pub const TX_IDLE: u8 = 1 << 5; // The Transmit Holding Register Empty bit
+        pub const TX_EMPTY: u8 = 1 << 6; // Holding and shift registers both empty
+
+        const _: () = assert!(
+            RX_READY & TX_IDLE == 0 && RX_READY & TX_EMPTY == 0 && TX_IDLE & TX_EMPTY == 0,
+            "UART LSR bits overlap"
+        );
+    }
+
+    // A 16550 decodes eight registers, before `reg-shift` spreads them out.
+    const _: () = assert!(
+        RBR_OFFSET < 8
+            && THR_OFFSET < 8
+            && IER_OFFSET < 8
+            && FCR_OFFSET < 8
+            && lsr::ADDR_OFFSET < 8,
+        "UART register outside the 16550 window"
+    );
+}
+
+/// VirtIO over MMIO register layout (virtio spec 1.x, section 4.2.2)
+pub mod virtio
+{
+    pub const MAGIC: u32 = 0x7472_6976; // "virt", little-endian
+    pub const DEVICE_ID_BLOCK: u32 = 2;
+
+    pub const MAGIC_VALUE_OFFSET: usize = 0x000;
+    pub const VERSION_OFFSET: usize = 0x004;
+    pub const DEVICE_ID_OFFSET: usize = 0x008;
+    pub const DEVICE_FEATURES_OFFSET: usize = 0x010;
+    pub const DEVICE_FEATURES_SEL_OFFSET: usize = 0x014;
+    pub const DRIVER_FEATURES_OFFSET: usize = 0x020;
+    pub const DRIVER_FEATURES_SEL_OFFSET: usize = 0x024;
+    pub const GUEST_PAGE_SIZE_OFFSET: usize = 0x028; // Legacy only
+    pub const QUEUE_SEL_OFFSET: usize = 0x030;
+    pub const QUEUE_NUM_MAX_OFFSET: usize = 0x034;
+    pub const QUEUE_NUM_OFFSET: usize = 0x038;
+    pub const QUEUE_ALIGN_OFFSET: usize = 0x03c; // Legacy only
+    pub const QUEUE_PFN_OFFSET: usize = 0x040; // Legacy only
+    pub const QUEUE_READY_OFFSET: usize = 0x044;
+    pub const QUEUE_NOTIFY_OFFSET: usize = 0x050;
+    pub const INTERRUPT_STATUS_OFFSET: usize = 0x060;
+    pub const INTERRUPT_ACK_OFFSET: usize = 0x064;
+    pub const STATUS_OFFSET: usize = 0x070;
+    pub const QUEUE_DESC_LOW_OFFSET: usize = 0x080;
+    pub const QUEUE_DESC_HIGH_OFFSET: usize = 0x084;
+    pub const QUEUE_DRIVER_LOW_OFFSET: usize = 0x090;
+    pub const QUEUE_DRIVER_HIGH_OFFSET: usize = 0x094;
+    pub const QUEUE_DEVICE_LOW_OFFSET: usize = 0x0a0;
+    pub const QUEUE_DEVICE_HIGH_OFFSET: usize = 0x0a4;
+    pub const CONFIG_OFFSET: usize = 0x100;
+
+    // In address order; `CONFIG_OFFSET` last, since device config follows
+    // every common register.
+    const _: () = assert!(
+        is_register_map(&[
+            MAGIC_VALUE_OFFSET,
+            VERSION_OFFSET,
+            DEVICE_ID_OFFSET,
+            DEVICE_FEATURES_OFFSET,
+            DEVICE_FEATURES_SEL_OFFSET,
+            DRIVER_FEATURES_OFFSET,
+            DRIVER_FEATURES_SEL_OFFSET,
+            GUEST_PAGE_SIZE_OFFSET,
+            QUEUE_SEL_OFFSET,
+            QUEUE_NUM_MAX_OFFSET,
+            QUEUE_NUM_OFFSET,
+            QUEUE_ALIGN_OFFSET,
+            QUEUE_PFN_OFFSET,
+            QUEUE_READY_OFFSET,
+            QUEUE_NOTIFY_OFFSET,
+            INTERRUPT_STATUS_OFFSET,
+            INTERRUPT_ACK_OFFSET,
+            STATUS_OFFSET,
+            QUEUE_DESC_LOW_OFFSET,
+            QUEUE_DESC_HIGH_OFFSET,
+            QUEUE_DRIVER_LOW_OFFSET,
+            QUEUE_DRIVER_HIGH_OFFSET,
+            QUEUE_DEVICE_LOW_OFFSET,
+            QUEUE_DEVICE_HIGH_OFFSET,
+            CONFIG_OFFSET,
+        ]),
+        "VirtIO registers misaligned or overlapping"
+    );
+
+    /// Whether `offsets` are 32-bit registers in strictly ascending order,
+    /// so no two of them share a byte.
+    const fn is_register_map(offsets: &[usize]) -> bool
+    {
+        let mut i = 0;
+        while i < offsets.len()
+        {
+            if !offsets[i].is_multiple_of(4) || (i > 0 && offsets[i] < offsets[i - 1] + 4)
+            {
+                return false;
+            }
+            i += 1;
+        }
+        true
+    }
+
+    pub mod status
+    {
+        pub const ACKNOWLEDGE: u32 = 1 << 0;
+        pub const DRIVER: u32 = 1 << 1;
+        pub const DRIVER_OK: u32 = 1 << 2;
+        pub const FEATURES_OK: u32 = 1 << 3;
+        pub const FAILED: u32 = 1 << 7;
     }
 }
@@ -0,0 +1,148 @@
+//! In-kernel synchronization primitives.
+//!
+//! These locks only provide mutual exclusion between harts; they never touch
+//! the local interrupt state. Callers that share a lock with a trap handler
+//! must still wrap the critical section in `interrupt::with_disabled`.
+//!
+//! The exception is the `critical-section` implementation in `critical`,
+//! which masks interrupts itself as that crate's contract requires.
+
+mod channel;
+mod critical;
+mod futex;
+mod mutex;
+mod once;
+mod semaphore;
+mod ticket;
+
+use core::{
+    hint::spin_loop,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+pub use channel::{Receiver, RecvError, Sender, channel};
+pub use futex::{futex_tick, futex_wait, futex_wait_until, futex_wake};
+pub use mutex::{Mutex, MutexGuard, PoisonError};
+pub use once::OnceLock;
+pub use semaphore::Semaphore;
+pub use ticket::{TicketMutex, TicketMutexGuard};
+
+use crate::arch::{self, Cpu};
+
+// `tp` of the hart that panicked, zero if none has.
+static POISONER: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of `Mutex` and `TicketMutex` guards the running task holds. In a
+/// trap handler, those of the task it interrupted.
+///
+/// Locks taken by trap handlers, which release them before returning, or
+/// before `Cpu::set` aren't counted. Nor are `Semaphore` permits, which
+/// needn't be returned by the task that took them.
+#[inline]
+pub fn held_locks() -> usize
+{
+    if arch::thread_pointer() == 0
+    {
+        return 0;
+    }
+    Cpu::get().held_locks.load(Ordering::Relaxed)
+}
+
+/// The counter behind [`held_locks`], if a lock taken here counts.
+#[inline]
+fn lock_counter() -> Option<&'static AtomicUsize>
+{
+    // `_start` clears `tp`, so this is `None` until `Cpu::set`.
+    if arch::thread_pointer() == 0
+    {
+        return None;
+    }
+    let cpu = Cpu::get();
+    (cpu.trap_depth.load(Ordering::Relaxed) == 0).then_some(&cpu.held_locks)
+}
+
+#[inline]
+fn note_locked()
+{
+    if let Some(counter) = lock_counter()
+    {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[inline]
+fn note_unlocked()
+{
+    if let Some(counter) = lock_counter()
+    {
+        counter.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Poison every `Mutex` the current hart holds, so that
+/// [`Mutex::lock_checked`] on them fails instead of waiting forever.
+///
+/// Called from the panic handler: nothing unwinds, so those locks are never
+/// released. Has no effect without the `lock-poisoning` feature.
+#[inline]
+pub fn poison_held_locks()
+{
+    POISONER.store(arch::thread_pointer(), Ordering::Release);
+}
+
+/// Undo [`poison_held_locks`], for tests that simulate a panic on a hart that
+/// then carries on.
+#[cfg(feature = "selftest")]
+#[inline]
+pub fn clear_poison()
+{
+    POISONER.store(0, Ordering::Release);
+}
+
+/// Bounded exponential backoff for contended spin loops.
+pub struct Backoff
+{
+    step: u32,
+}
+
+impl Backoff
+{
+    // Caps a single backoff round at 2^6 = 64 `spin_loop` hints.
+    const MAX_STEP: u32 = 6;
+
+    #[inline]
+    pub const fn new() -> Self
+    {
+        Self { step: 0 }
+    }
+
+    /// Spin for the current backoff window, then double it (up to the cap).
+    #[inline]
+    pub fn spin(&mut self)
+    {
+        for _ in 0..(1 << self.step)
+        {
+            spin_loop();
+        }
+
+        if self.step < Self::MAX_STEP
+        {
+            self.step += 1;
+        }
+    }
+
+    #[inline]
+    pub fn reset(&mut self)
+    {
+        self.step = 0;
+    }
+}
+
+impl Default for Backoff
+{
+    #[inline]
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
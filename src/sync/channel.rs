@@ -0,0 +1,182 @@
+//! Multi-producer, single-consumer channel between tasks.
+//!
+//! Receivers block on a futex until a message arrives, the last sender goes
+//! or the deadline passes, so they cost their hart nothing while they wait.
+
+use alloc::{collections::VecDeque, sync::Arc};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use super::{Mutex, futex_wait, futex_wait_until, futex_wake};
+use crate::{interrupt, platform::timer};
+
+/// Why a receive returned without a message.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RecvError
+{
+    /// Nothing queued right now (`try_recv` only).
+    Empty,
+    /// The deadline passed with nothing queued (`recv_timeout` only).
+    Timeout,
+    /// Every `Sender` is gone and the queue is drained.
+    Disconnected,
+}
+
+struct Shared<T>
+{
+    queue: Mutex<VecDeque<T>>,
+    senders: AtomicUsize,
+    receiver_alive: AtomicBool,
+    /// Bumped after every send and when the last sender goes; the receiver
+    /// waits on it.
+    events: AtomicUsize,
+}
+
+impl<T> Shared<T>
+{
+    #[inline]
+    fn notify(&self)
+    {
+        self.events.fetch_add(1, Ordering::Release);
+        futex_wake(&self.events, 1);
+    }
+}
+
+pub struct Sender<T>
+{
+    shared: Arc<Shared<T>>,
+}
+
+pub struct Receiver<T>
+{
+    shared: Arc<Shared<T>>,
+}
+
+/// Create a channel; clone the `Sender` for more producers.
+pub fn channel<T>() -> (Sender<T>, Receiver<T>)
+{
+    let shared = Arc::new(Shared {
+        queue: Mutex::new(VecDeque::new()),
+        senders: AtomicUsize::new(1),
+        receiver_alive: AtomicBool::new(true),
+        events: AtomicUsize::new(0),
+    });
+
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+impl<T> Sender<T>
+{
+    /// Queue `value`, handing it back if the receiver is gone.
+    ///
+    /// May allocate, so this must not be called from a trap handler.
+    pub fn send(&self, value: T) -> Result<(), T>
+    {
+        if !self.shared.receiver_alive.load(Ordering::Acquire)
+        {
+            return Err(value);
+        }
+
+        interrupt::with_disabled(|| self.shared.queue.lock().push_back(value));
+        self.shared.notify();
+        Ok(())
+    }
+}
+
+impl<T> Clone for Sender<T>
+{
+    fn clone(&self) -> Self
+    {
+        self.shared.senders.fetch_add(1, Ordering::Relaxed);
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T>
+{
+    fn drop(&mut self)
+    {
+        if self.shared.senders.fetch_sub(1, Ordering::Release) == 1
+        {
+            self.shared.notify();
+        }
+    }
+}
+
+impl<T> Receiver<T>
+{
+    /// Take the oldest message without waiting.
+    pub fn try_recv(&self) -> Result<T, RecvError>
+    {
+        // Sample the sender count first: a message sent by the last sender
+        // before it dropped is then guaranteed to be seen by the pop below.
+        let disconnected = self.shared.senders.load(Ordering::Acquire) == 0;
+
+        match interrupt::with_disabled(|| self.shared.queue.lock().pop_front())
+        {
+            Some(value) => Ok(value),
+            None if disconnected => Err(RecvError::Disconnected),
+            None => Err(RecvError::Empty),
+        }
+    }
+
+    /// Wait for the next message, failing only once every sender is gone.
+    pub fn recv(&self) -> Result<T, RecvError>
+    {
+        loop
+        {
+            // Sampled before looking, so a send in between isn't slept through.
+            let seen = self.shared.events.load(Ordering::Acquire);
+            match self.try_recv()
+            {
+                Err(RecvError::Empty) =>
+                {
+                    futex_wait(&self.shared.events, seen);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Like [`Receiver::recv`], but give up after `ticks` of the `time`
+    /// counter, rounded up to the next timer tick.
+    ///
+    /// A message that arrives just as the deadline passes is still returned:
+    /// the queue is checked once more after waking before reporting
+    /// `Timeout`.
+    pub fn recv_timeout(&self, ticks: u64) -> Result<T, RecvError>
+    {
+        let deadline = timer::now().saturating_add(ticks);
+
+        loop
+        {
+            let seen = self.shared.events.load(Ordering::Acquire);
+            match self.try_recv()
+            {
+                Err(RecvError::Empty) if timer::now() >= deadline =>
+                {
+                    return Err(RecvError::Timeout);
+                }
+                Err(RecvError::Empty) =>
+                {
+                    futex_wait_until(&self.shared.events, seen, deadline);
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T>
+{
+    fn drop(&mut self)
+    {
+        self.shared.receiver_alive.store(false, Ordering::Release);
+    }
+}
@@ -0,0 +1,64 @@
+//! `critical-section` implementation, for ecosystem crates built on it.
+//!
+//! A critical section masks local interrupts, like `interrupt::with_disabled`,
+//! and also holds one global lock so that it excludes the other harts too.
+//! Nesting on the same hart is allowed: only the outermost `acquire` takes the
+//! lock, and each `release` restores the `sstatus.SIE` its `acquire` saw.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use critical_section::RawRestoreState;
+
+use super::Backoff;
+use crate::{arch, interrupt};
+
+// Bits of the restore token.
+const WAS_ENABLED: RawRestoreState = 1 << 0;
+const TOOK_LOCK: RawRestoreState = 1 << 1;
+
+// `tp` of the hart inside a critical section, zero if none is.
+static OWNER: AtomicUsize = AtomicUsize::new(0);
+
+struct KernelCriticalSection;
+critical_section::set_impl!(KernelCriticalSection);
+
+unsafe impl critical_section::Impl for KernelCriticalSection
+{
+    unsafe fn acquire() -> RawRestoreState
+    {
+        let mut state = 0;
+        if interrupt::is_enabled()
+        {
+            state |= WAS_ENABLED;
+        }
+        interrupt::disable();
+
+        // Before `Cpu::set` every hart shares `tp`, but only hart 0 runs then.
+        let me = arch::thread_pointer().max(1);
+        if OWNER.load(Ordering::Relaxed) != me
+        {
+            let mut backoff = Backoff::new();
+            while OWNER
+                .compare_exchange_weak(0, me, Ordering::Acquire, Ordering::Relaxed)
+                .is_err()
+            {
+                backoff.spin();
+            }
+            state |= TOOK_LOCK;
+        }
+
+        state
+    }
+
+    unsafe fn release(state: RawRestoreState)
+    {
+        if state & TOOK_LOCK != 0
+        {
+            OWNER.store(0, Ordering::Release);
+        }
+        if state & WAS_ENABLED != 0
+        {
+            interrupt::enable();
+        }
+    }
+}
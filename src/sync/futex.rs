@@ -0,0 +1,213 @@
+//! Address-keyed wait and wake, for building blocking primitives.
+//!
+//! Waiters are kept in a small fixed table of queues hashed by address, so
+//! nothing needs a wait queue of its own: any `AtomicUsize` can be waited on.
+//! Each queue is FIFO: `futex_wait` appends and `futex_wake` takes the oldest
+//! waiters first, so no waiter is passed over by ones that came later.
+//! Locks are always taken bucket first, then a hart's scheduler.
+//!
+//! [`futex_wait_until`] also arms an alarm. The timer interrupt only spots
+//! alarms that are due, and defers the wake to the worker, since waking may
+//! allocate.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::Mutex;
+use crate::{
+    arch::{self, Cpu},
+    interrupt::{self, deferred},
+    platform::timer,
+    task::{self, TaskId, TaskState},
+};
+
+const BUCKETS: usize = 16;
+
+struct Waiter
+{
+    addr: usize,
+    hart: usize,
+    task: TaskId,
+}
+
+static QUEUES: [Mutex<Vec<Waiter>>; BUCKETS] = [const { Mutex::new(Vec::new()) }; _];
+
+/// Deadline of a [`futex_wait_until`] still in progress.
+struct Alarm
+{
+    deadline: u64,
+    addr: usize,
+    hart: usize,
+    task: TaskId,
+}
+
+static ALARMS: Mutex<Vec<Alarm>> = Mutex::new(Vec::new());
+
+#[inline]
+fn bucket(addr: usize) -> &'static Mutex<Vec<Waiter>>
+{
+    // Atomics are word-aligned, so the low bits carry no information.
+    &QUEUES[(addr / size_of::<usize>()) % BUCKETS]
+}
+
+/// Block the current task while `*addr == expected`.
+///
+/// The comparison and the block are atomic with respect to [`futex_wake`]: a
+/// wake issued after the value changes is never lost. Returns `false` without
+/// blocking if the value already differs. Wakeups can be spurious, so callers
+/// re-check their condition in a loop.
+///
+/// Must be called from a task with interrupts enabled.
+pub fn futex_wait(addr: &AtomicUsize, expected: usize) -> bool
+{
+    debug_assert!(task::may_block(), "futex_wait called from a trap handler");
+    debug_assert!(interrupt::is_enabled(), "futex_wait with interrupts masked");
+
+    let key = addr as *const AtomicUsize as usize;
+    let blocked = interrupt::with_disabled(|| {
+        let mut queue = bucket(key).lock();
+        if addr.load(Ordering::Acquire) != expected
+        {
+            return false;
+        }
+
+        let cpu = Cpu::get();
+        let mut scheduler = cpu.scheduler.lock();
+        let task = scheduler.task_mut();
+        task.state = TaskState::Blocked;
+        queue.push(Waiter {
+            addr: key,
+            hart: cpu.logical_id,
+            task: task.id,
+        });
+        true
+    });
+
+    if blocked
+    {
+        // Switch away now; we resume right here once woken.
+        timer::ipi::raise_local();
+    }
+    blocked
+}
+
+/// Like [`futex_wait`], but also wake once the `time` counter reaches
+/// `deadline`, to within a timer tick.
+///
+/// A timeout looks just like a spurious wakeup: callers re-check both their
+/// condition and the time.
+pub fn futex_wait_until(addr: &AtomicUsize, expected: usize, deadline: u64) -> bool
+{
+    let key = addr as *const AtomicUsize as usize;
+    let task = arch::current_task_id();
+
+    // Armed first, so it can't miss a block that happens right after.
+    interrupt::with_disabled(|| {
+        ALARMS.lock().push(Alarm {
+            deadline,
+            addr: key,
+            hart: arch::logical_hart_id(),
+            task,
+        })
+    });
+
+    let blocked = futex_wait(addr, expected);
+
+    // Woken either way by now; don't let a stale alarm cut a later wait short.
+    interrupt::with_disabled(|| {
+        ALARMS
+            .lock()
+            .retain(|alarm| alarm.task != task || alarm.addr != key)
+    });
+    blocked
+}
+
+/// Called from the timer interrupt; has the worker fire this hart's due
+/// alarms.
+pub fn futex_tick(cpu: &Cpu)
+{
+    let now = timer::now();
+    let due = ALARMS
+        .lock()
+        .iter()
+        .any(|alarm| alarm.hart == cpu.logical_id && alarm.deadline <= now);
+
+    if due
+    {
+        // A full queue just means the next tick tries again.
+        let _ = deferred::defer(fire_alarms, 0);
+    }
+}
+
+/// Wake every task whose alarm is due.
+fn fire_alarms(_: usize)
+{
+    let now = timer::now();
+    let due = interrupt::with_disabled(|| {
+        let mut alarms = ALARMS.lock();
+        alarms
+            .extract_if(.., |alarm| alarm.deadline <= now)
+            .collect::<Vec<_>>()
+    });
+
+    for alarm in due
+    {
+        interrupt::with_disabled(|| {
+            let mut queue = bucket(alarm.addr).lock();
+            if let Some(i) = queue
+                .iter()
+                .position(|w| w.addr == alarm.addr && w.task == alarm.task)
+            {
+                wake(queue.remove(i));
+            }
+        });
+    }
+}
+
+/// Make `waiter` runnable, with its bucket locked by the caller. Returns
+/// whether it was still blocked.
+fn wake(waiter: Waiter) -> bool
+{
+    let cpu = Cpu::nth(waiter.hart);
+    if !cpu.scheduler.lock().unblock(waiter.task)
+    {
+        return false;
+    }
+
+    if waiter.hart != arch::logical_hart_id()
+    {
+        timer::ipi::send(cpu.physical_id);
+    }
+    true
+}
+
+/// Wake up to `count` tasks blocked in [`futex_wait`] on `addr`, oldest first,
+/// returning how many were woken.
+///
+/// Update the value before calling this. May allocate, so not for trap
+/// handlers.
+pub fn futex_wake(addr: &AtomicUsize, count: usize) -> usize
+{
+    let key = addr as *const AtomicUsize as usize;
+    let mut woken = 0;
+
+    interrupt::with_disabled(|| {
+        let mut queue = bucket(key).lock();
+        let mut i = 0;
+        while woken < count && i < queue.len()
+        {
+            if queue[i].addr != key
+            {
+                i += 1;
+                continue;
+            }
+
+            if wake(queue.remove(i))
+            {
+                woken += 1;
+            }
+        }
+    });
+
+    woken
+}
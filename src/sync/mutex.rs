@@ -0,0 +1,211 @@
+//! Test-and-test-and-set spin lock with exponential backoff.
+
+#[cfg(feature = "lock-poisoning")]
+use core::sync::atomic::AtomicUsize;
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+#[cfg(feature = "lock-poisoning")]
+use super::POISONER;
+use super::{Backoff, note_locked, note_unlocked};
+#[cfg(feature = "lock-poisoning")]
+use crate::arch;
+
+pub struct Mutex<T: ?Sized>
+{
+    locked: AtomicBool,
+    // Holder's `tp`, zero while unlocked; see `is_poisoned`.
+    #[cfg(feature = "lock-poisoning")]
+    owner: AtomicUsize,
+    data: UnsafeCell<T>,
+}
+
+/// The lock is held by a hart that panicked, so it will never be released.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PoisonError;
+
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+
+pub struct MutexGuard<'a, T: ?Sized>
+{
+    lock: &'a Mutex<T>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for MutexGuard<'_, T> {}
+
+impl<T> Mutex<T>
+{
+    #[inline]
+    pub const fn new(value: T) -> Self
+    {
+        Self {
+            locked: AtomicBool::new(false),
+            #[cfg(feature = "lock-poisoning")]
+            owner: AtomicUsize::new(0),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> T
+    {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T>
+{
+    /// Acquire the lock, spinning with exponential backoff while contended.
+    pub fn lock(&self) -> MutexGuard<'_, T>
+    {
+        let mut backoff = Backoff::new();
+
+        loop
+        {
+            if let Some(guard) = self.try_lock()
+            {
+                return guard;
+            }
+
+            // Spin on a plain load so waiting harts share the cache line
+            // instead of bouncing it with failed read-modify-writes.
+            while self.is_locked()
+            {
+                backoff.spin();
+            }
+        }
+    }
+
+    /// Like [`Mutex::lock`], but gives up with `PoisonError` instead of
+    /// spinning forever once the holder has panicked.
+    ///
+    /// Holders are only tracked with the `lock-poisoning` feature; without it
+    /// this never fails.
+    pub fn lock_checked(&self) -> Result<MutexGuard<'_, T>, PoisonError>
+    {
+        let mut backoff = Backoff::new();
+
+        loop
+        {
+            if let Some(guard) = self.try_lock()
+            {
+                return Ok(guard);
+            }
+
+            while self.is_locked()
+            {
+                if self.is_poisoned()
+                {
+                    return Err(PoisonError);
+                }
+                backoff.spin();
+            }
+        }
+    }
+
+    #[inline]
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>>
+    {
+        let acquired = self
+            .locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok();
+
+        acquired.then(|| {
+            note_locked();
+            #[cfg(feature = "lock-poisoning")]
+            self.owner.store(arch::thread_pointer(), Ordering::Relaxed);
+            MutexGuard { lock: self }
+        })
+    }
+
+    /// Whether the lock is held by a hart that has since panicked.
+    ///
+    /// There is no unwinding, so such a lock is never released.
+    #[cfg(feature = "lock-poisoning")]
+    #[inline]
+    pub fn is_poisoned(&self) -> bool
+    {
+        let poisoner = POISONER.load(Ordering::Acquire);
+        poisoner != 0 && self.is_locked() && self.owner.load(Ordering::Relaxed) == poisoner
+    }
+
+    /// Always `false`: holders aren't tracked without `lock-poisoning`.
+    #[cfg(not(feature = "lock-poisoning"))]
+    #[inline]
+    pub fn is_poisoned(&self) -> bool
+    {
+        false
+    }
+
+    #[inline]
+    pub fn is_locked(&self) -> bool
+    {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut T
+    {
+        self.data.get_mut()
+    }
+}
+
+impl<T: Default> Default for Mutex<T>
+{
+    #[inline]
+    fn default() -> Self
+    {
+        Self::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Mutex<T>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        match self.try_lock()
+        {
+            Some(guard) => f.debug_struct("Mutex").field("data", &&*guard).finish(),
+            None => f.write_str("Mutex { <locked> }"),
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T>
+{
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T
+    {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T>
+{
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T
+    {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T>
+{
+    #[inline]
+    fn drop(&mut self)
+    {
+        // Clear first, so the next holder is never mistaken for this one.
+        #[cfg(feature = "lock-poisoning")]
+        self.lock.owner.store(0, Ordering::Relaxed);
+        self.lock.locked.store(false, Ordering::Release);
+        note_unlocked();
+    }
+}
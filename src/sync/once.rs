@@ -0,0 +1,122 @@
+//! Write-once cell for values initialized at boot and read-only afterwards.
+
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    hint::spin_loop,
+    mem::MaybeUninit,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+const EMPTY: u8 = 0;
+const WRITING: u8 = 1;
+const READY: u8 = 2;
+
+pub struct OnceLock<T>
+{
+    state: AtomicU8,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+unsafe impl<T: Send + Sync> Sync for OnceLock<T> {}
+unsafe impl<T: Send> Send for OnceLock<T> {}
+
+impl<T> OnceLock<T>
+{
+    #[inline]
+    pub const fn new() -> Self
+    {
+        Self {
+            state: AtomicU8::new(EMPTY),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// The value, if it has been set.
+    #[inline]
+    pub fn get(&self) -> Option<&T>
+    {
+        (self.state.load(Ordering::Acquire) == READY)
+            .then(|| unsafe { (*self.value.get()).assume_init_ref() })
+    }
+
+    /// Store `value`, handing it back if the cell was already set (or is
+    /// being set by another hart).
+    pub fn set(&self, value: T) -> Result<(), T>
+    {
+        if self
+            .state
+            .compare_exchange(EMPTY, WRITING, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            return Err(value);
+        }
+
+        unsafe { (*self.value.get()).write(value) };
+        self.state.store(READY, Ordering::Release);
+        Ok(())
+    }
+
+    /// Initialize the value with `f` unless it already is, then return it.
+    ///
+    /// Exactly one caller runs `f`; concurrent callers spin until it is done.
+    /// If `f` panics the cell stays uninitialized forever.
+    pub fn call_once(&self, f: impl FnOnce() -> T) -> &T
+    {
+        if self
+            .state
+            .compare_exchange(EMPTY, WRITING, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            unsafe { (*self.value.get()).write(f()) };
+            self.state.store(READY, Ordering::Release);
+        }
+
+        self.wait()
+    }
+
+    /// Spin until another hart sets the value, then return it.
+    pub fn wait(&self) -> &T
+    {
+        loop
+        {
+            if let Some(value) = self.get()
+            {
+                return value;
+            }
+            spin_loop();
+        }
+    }
+}
+
+impl<T> Default for OnceLock<T>
+{
+    #[inline]
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+impl<T> Drop for OnceLock<T>
+{
+    fn drop(&mut self)
+    {
+        if *self.state.get_mut() == READY
+        {
+            unsafe { self.value.get_mut().assume_init_drop() }
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for OnceLock<T>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        match self.get()
+        {
+            Some(value) => f.debug_struct("OnceLock").field("data", value).finish(),
+            None => f.write_str("OnceLock { <uninit> }"),
+        }
+    }
+}
@@ -0,0 +1,81 @@
+//! Counting semaphore that grants permits in FIFO order.
+//!
+//! Each acquirer draws a ticket and waits until that ticket is granted, so
+//! permits go out strictly in the order `acquire` was called: a newcomer can't
+//! take a released permit ahead of a task that is already waiting, however
+//! the wakeups land across harts.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::{futex_wait, futex_wake};
+
+pub struct Semaphore
+{
+    /// Next ticket to hand out.
+    next: AtomicUsize,
+    /// Tickets below this hold (or held) a permit.
+    granted: AtomicUsize,
+}
+
+impl Semaphore
+{
+    #[inline]
+    pub const fn new(permits: usize) -> Self
+    {
+        Self {
+            next: AtomicUsize::new(0),
+            granted: AtomicUsize::new(permits),
+        }
+    }
+
+    /// Whether `ticket` is below `granted`, allowing for wraparound.
+    #[inline]
+    fn is_granted(ticket: usize, granted: usize) -> bool
+    {
+        granted.wrapping_sub(ticket).wrapping_sub(1) < usize::MAX / 2
+    }
+
+    /// Take a permit, blocking until one is granted to this task.
+    ///
+    /// Must be called from a task with interrupts enabled.
+    pub fn acquire(&self)
+    {
+        let ticket = self.next.fetch_add(1, Ordering::Relaxed);
+        loop
+        {
+            let granted = self.granted.load(Ordering::Acquire);
+            if Self::is_granted(ticket, granted)
+            {
+                return;
+            }
+            futex_wait(&self.granted, granted);
+        }
+    }
+
+    /// Take a permit if one is free and nobody is waiting for it.
+    pub fn try_acquire(&self) -> bool
+    {
+        let ticket = self.next.load(Ordering::Relaxed);
+        Self::is_granted(ticket, self.granted.load(Ordering::Acquire))
+            && self
+                .next
+                .compare_exchange(
+                    ticket,
+                    ticket.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+    }
+
+    /// Return a permit, granting it to the oldest waiter if there is one.
+    ///
+    /// Every waiter is woken to check its ticket, since the futex can't tell
+    /// which of them holds the next one; only that one proceeds. May
+    /// allocate, so not for trap handlers.
+    pub fn release(&self)
+    {
+        self.granted.fetch_add(1, Ordering::Release);
+        futex_wake(&self.granted, usize::MAX);
+    }
+}
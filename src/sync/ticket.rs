@@ -0,0 +1,176 @@
+//! FIFO ticket lock.
+//!
+//! Harts are served strictly in the order they called `lock`, so no hart can
+//! be starved by a faster one repeatedly winning the race.
+
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    hint::spin_loop,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use super::{note_locked, note_unlocked};
+
+pub struct TicketMutex<T: ?Sized>
+{
+    next_ticket: AtomicUsize,
+    now_serving: AtomicUsize,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Sync for TicketMutex<T> {}
+unsafe impl<T: ?Sized + Send> Send for TicketMutex<T> {}
+
+pub struct TicketMutexGuard<'a, T: ?Sized>
+{
+    lock: &'a TicketMutex<T>,
+    ticket: usize,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for TicketMutexGuard<'_, T> {}
+
+impl<T> TicketMutex<T>
+{
+    #[inline]
+    pub const fn new(value: T) -> Self
+    {
+        Self {
+            next_ticket: AtomicUsize::new(0),
+            now_serving: AtomicUsize::new(0),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> T
+    {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> TicketMutex<T>
+{
+    /// Take a ticket and wait until it is served.
+    pub fn lock(&self) -> TicketMutexGuard<'_, T>
+    {
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+
+        loop
+        {
+            let serving = self.now_serving.load(Ordering::Acquire);
+            if serving == ticket
+            {
+                note_locked();
+                return TicketMutexGuard { lock: self, ticket };
+            }
+
+            // Back off proportionally to our distance from the head of the
+            // queue, so far-away waiters poll the shared line less often.
+            for _ in 0..ticket.wrapping_sub(serving)
+            {
+                spin_loop();
+            }
+        }
+    }
+
+    /// Acquire the lock only if nobody holds it or is queued for it.
+    #[inline]
+    pub fn try_lock(&self) -> Option<TicketMutexGuard<'_, T>>
+    {
+        let ticket = self.now_serving.load(Ordering::Acquire);
+
+        self.next_ticket
+            .compare_exchange(
+                ticket,
+                ticket.wrapping_add(1),
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .is_ok()
+            .then(|| {
+                note_locked();
+                TicketMutexGuard { lock: self, ticket }
+            })
+    }
+
+    /// Number of `lock` calls queued behind the holder.
+    #[inline]
+    pub fn waiters(&self) -> usize
+    {
+        let queued = self
+            .next_ticket
+            .load(Ordering::Relaxed)
+            .wrapping_sub(self.now_serving.load(Ordering::Relaxed));
+        queued.saturating_sub(1)
+    }
+
+    #[inline]
+    pub fn is_locked(&self) -> bool
+    {
+        self.next_ticket.load(Ordering::Relaxed) != self.now_serving.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut T
+    {
+        self.data.get_mut()
+    }
+}
+
+impl<T: Default> Default for TicketMutex<T>
+{
+    #[inline]
+    fn default() -> Self
+    {
+        Self::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for TicketMutex<T>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        match self.try_lock()
+        {
+            Some(guard) => f
+                .debug_struct("TicketMutex")
+                .field("data", &&*guard)
+                .finish(),
+            None => f.write_str("TicketMutex { <locked> }"),
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for TicketMutexGuard<'_, T>
+{
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T
+    {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for TicketMutexGuard<'_, T>
+{
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T
+    {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for TicketMutexGuard<'_, T>
+{
+    #[inline]
+    fn drop(&mut self)
+    {
+        self.lock
+            .now_serving
+            .store(self.ticket.wrapping_add(1), Ordering::Release);
+        note_unlocked();
+    }
+}
@@ -2,42 +2,395 @@
 //!
 //! This module defines task types and task lifecycle operations.
 
+pub mod balance;
 mod context;
 mod scheduler;
+pub mod worker;
 
-use alloc::boxed::Box;
+use alloc::{boxed::Box, sync::Arc, vec, vec::Vec};
 use core::{
     arch::{asm, naked_asm},
-    sync::atomic::{AtomicUsize, Ordering},
+    fmt,
+    panic::PanicInfo,
+    sync::atomic::{AtomicI32, AtomicU8, AtomicUsize, Ordering},
 };
 
 pub use context::TrapContext;
 pub use scheduler::*;
 
 use crate::{
-    arch::{CPU_VEC, Cpu},
-    interrupt,
+    arch::{self, CPU_VEC, Cpu, PAGE_SIZE},
+    crash, interrupt,
+    memory::map,
     platform::timer,
+    sync,
 };
 
+/// Exit code of a task ended by a contained panic.
+pub const PANIC_EXIT_CODE: i32 = -1;
+/// Exit code of a task killed for using up its [`Budget`].
+pub const BUDGET_EXIT_CODE: i32 = -2;
+
 const STACK_SIZE: usize = 1024 * 16; // 16KB
+const MIN_STACK_SIZE: usize = 1024 * 4; // 4KB
+
+/// New tasks start in S-mode; the trampoline enables interrupts itself.
+const INITIAL_SSTATUS: usize = interrupt::SPP_FLAG;
 
 static SPAWN_TICKET: AtomicUsize = AtomicUsize::new(0);
+static NEXT_TASK_ID: AtomicUsize = AtomicUsize::new(0);
+static PLACEMENT: AtomicU8 = AtomicU8::new(Placement::RoundRobin as _);
+
+/// Local queues up to this long keep new tasks on the spawning hart.
+const LOCAL_QUEUE_LIMIT: usize = 2;
+
+/// How `Task::spawn` picks a hart for new tasks.
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Placement
+{
+    /// Cycle through all harts regardless of load.
+    RoundRobin = 0,
+    /// Stay on the spawning hart while its queue is short, otherwise use the
+    /// least-loaded hart.
+    Local = 1,
+}
+
+/// Select the placement policy for subsequently spawned tasks.
+#[inline]
+pub fn set_placement(policy: Placement)
+{
+    PLACEMENT.store(policy as _, Ordering::Relaxed);
+}
+
+#[inline]
+pub fn placement() -> Placement
+{
+    match PLACEMENT.load(Ordering::Relaxed)
+    {
+        0 => Placement::RoundRobin,
+        _ => Placement::Local,
+    }
+}
+
+/// Pick the logical hart a new task should run on.
+fn target_hart() -> usize
+{
+    let hart = match placement()
+    {
+        Placement::RoundRobin =>
+        {
+            // Harts only ever go offline during boot, before anything spawns,
+            // so the count can't change between here and `nth`.
+            let online = arch::online_cpus().count();
+            let ticket = SPAWN_TICKET.fetch_add(1, Ordering::Relaxed) % online;
+            arch::online_cpus()
+                .nth(ticket)
+                .map_or(0, |cpu| cpu.logical_id)
+        }
+        Placement::Local =>
+        {
+            let local = Cpu::get();
+            if local.load() < LOCAL_QUEUE_LIMIT
+            {
+                local.logical_id
+            }
+            else
+            {
+                // Lengths can change under us; this is only a hint.
+                arch::online_cpus()
+                    .min_by_key(|cpu| cpu.load())
+                    .map_or(local.logical_id, |cpu| cpu.logical_id)
+            }
+        }
+    };
+
+    debug_assert!(
+        Cpu::nth(hart).is_ready(),
+        "Task placed on hart {hart} before its setup"
+    );
+    hart
+}
+
+/// Whether the caller may block or yield: false inside trap handlers, which
+/// run on the trap stack and can't be switched away from.
+#[inline]
+pub fn may_block() -> bool
+{
+    !arch::in_interrupt()
+}
+
+/// Make a panic in the current task stop the whole system, as a panic outside
+/// any task does, instead of only ending the task.
+#[inline]
+pub fn set_panic_fatal(fatal: bool)
+{
+    interrupt::with_disabled(|| Cpu::get().scheduler.lock().task_mut().panic_fatal = fatal);
+}
+
+/// Called first thing by the panic handler: if the panic can be confined to
+/// the current task, log it and end that task instead. Returns when the whole
+/// system has to stop.
+///
+/// Only a `User` task qualifies, and only when it panicked with interrupts
+/// enabled and preemption allowed, outside any trap handler, holding no
+/// `Mutex` or `TicketMutex`: nothing would ever release such a lock, and every
+/// other task that wants it would spin forever. Those panics stop the system
+/// instead, which poisons the locks. Nothing unwinds: whatever else the task
+/// owned leaks, semaphore permits included, and its stack is freed with it.
+pub fn contain_panic(info: &PanicInfo)
+{
+    if crash::is_stopping() || !interrupt::is_enabled() || !may_block() || sync::held_locks() != 0
+    {
+        return;
+    }
+
+    let cpu = Cpu::get();
+    let contained = cpu.preemptible()
+        && interrupt::with_disabled(|| {
+            cpu.scheduler.try_lock().is_some_and(|scheduler| {
+                let task = scheduler.task();
+                matches!(task.kind, TaskKind::User { .. }) && !task.panic_fatal
+            })
+        });
+    if !contained
+    {
+        return;
+    }
+
+    log::error!("Task {} panicked: {}", arch::current_task_id(), info);
+    Task::exit_with(PANIC_EXIT_CODE)
+}
+
+/// Give up the rest of this time slice to the tasks waiting on this hart in
+/// the same [`Priority`] band or a higher one.
+///
+/// The current task goes to the back of its band, so tasks that yield to each
+/// other strictly alternate. With only lower bands waiting it resumes
+/// immediately. Called with interrupts disabled, the switch happens as soon as
+/// they are re-enabled.
+#[inline]
+pub fn yield_now()
+{
+    debug_assert!(may_block(), "yield_now called from a trap handler");
+    Cpu::get().sched_counters.record_yield();
+    timer::ipi::raise_local();
+}
 
-#[derive(PartialEq, Default)]
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
 pub enum TaskState
 {
     #[default]
     Ready, // Waiting to be picked
-    Running, // Currently on a CPU core
-    Dead,    // Finished, waiting to be "reaped" (deleted)
+    Running,   // Currently on a CPU core
+    Blocked,   // Parked until explicitly woken
+    Suspended, // Created by `spawn_suspended`, waiting for `TaskHandle::resume`
+    Dead,      // Finished, waiting to be "reaped" (deleted)
+}
+
+/// Scheduling band of a task.
+///
+/// A hart always runs a task from the highest band with one ready, taking
+/// turns within that band; lower bands wait until it empties, or until they
+/// have waited long enough to be raised into it (see [`set_aging_rate`]).
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Debug)]
+pub enum Priority
+{
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+/// What happens to a task once it uses up its [`Budget`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BudgetAction
+{
+    /// Log a warning, once, and let it run on.
+    Warn,
+    /// Log a warning and end the task with [`BUDGET_EXIT_CODE`].
+    Kill,
+}
+
+/// CPU time a task may use before its [`BudgetAction`] is taken.
+///
+/// Usage is sampled by the timer: every tick that finds the task running
+/// charges it one whole tick.
+#[derive(Clone, Copy, Debug)]
+pub struct Budget
+{
+    ticks: usize,
+    action: BudgetAction,
+    used: usize,
+    exceeded: bool,
+}
+
+impl Budget
+{
+    /// Allow `us` microseconds of CPU time, rounded up to whole ticks.
+    pub fn new(us: u64, action: BudgetAction) -> Self
+    {
+        let ticks = (us * timer::TICK_HZ).div_ceil(1_000_000) as usize;
+        Self {
+            ticks: ticks.max(1),
+            action,
+            used: 0,
+            exceeded: false,
+        }
+    }
+
+    /// Ticks charged so far.
+    #[inline]
+    pub fn used(&self) -> usize
+    {
+        self.used
+    }
+}
+
+/// Identifies a task for as long as the kernel runs; IDs are never reused.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TaskId(usize);
+
+impl TaskId
+{
+    #[inline]
+    fn next() -> Self
+    {
+        Self(NEXT_TASK_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    #[inline]
+    pub const fn from_raw(raw: usize) -> Self
+    {
+        Self(raw)
+    }
+
+    #[inline]
+    pub const fn get(self) -> usize
+    {
+        self.0
+    }
+}
+
+impl fmt::Display for TaskId
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        write!(f, "#{}", self.0)
+    }
+}
+
+/// Refers to a task made by [`Task::spawn_suspended`].
+#[derive(Clone, Copy, Debug)]
+pub struct TaskHandle
+{
+    id: TaskId,
+    hart: usize,
+}
+
+impl TaskHandle
+{
+    #[inline]
+    pub fn id(&self) -> TaskId
+    {
+        self.id
+    }
+
+    /// Logical ID of the hart the task was placed on.
+    #[inline]
+    pub fn hart(&self) -> usize
+    {
+        self.hart
+    }
+
+    /// Let the task run, queueing it on its hart. Does nothing if it already
+    /// has been resumed.
+    pub fn resume(&self)
+    {
+        let cpu = Cpu::nth(self.hart);
+        let resumed = interrupt::with_disabled(|| cpu.scheduler.lock().resume(self.id));
+
+        if resumed && self.hart != arch::logical_hart_id()
+        {
+            timer::ipi::send(cpu.physical_id);
+        }
+    }
+}
+
+/// Outlives the task it belongs to, so its exit code can still be read once
+/// the task has been reaped.
+#[derive(Default)]
+struct JoinSlot
+{
+    /// Nonzero once `code` is final; waited on with a futex.
+    done: AtomicUsize,
+    code: AtomicI32,
+}
+
+/// Waits for a task made by [`Task::spawn_joinable`] and collects its exit
+/// code.
+pub struct JoinHandle
+{
+    id: TaskId,
+    slot: Arc<JoinSlot>,
+}
+
+impl JoinHandle
+{
+    #[inline]
+    pub fn id(&self) -> TaskId
+    {
+        self.id
+    }
+
+    /// The task's exit code, if it has exited and been reaped.
+    #[inline]
+    pub fn try_join(&self) -> Option<i32>
+    {
+        (self.slot.done.load(Ordering::Acquire) != 0)
+            .then(|| self.slot.code.load(Ordering::Relaxed))
+    }
+
+    /// Block until the task has exited and been reaped, returning its exit
+    /// code: 0 for a normal return, else whatever ended it.
+    pub fn join(self) -> i32
+    {
+        loop
+        {
+            if let Some(code) = self.try_join()
+            {
+                return code;
+            }
+            sync::futex_wait(&self.slot.done, 0);
+        }
+    }
 }
 
 pub struct Task
 {
     pub context: Box<TrapContext>,
     pub kind: TaskKind,
+    pub id: TaskId,
     pub state: TaskState,
+    /// Set by [`Task::exit_with`]; meaningful only once `state` is `Dead`.
+    pub exit_code: i32,
+    /// Whether a panic here stops the system rather than just this task; see
+    /// [`contain_panic`].
+    pub panic_fatal: bool,
+    /// CPU time limit, for tasks from [`Task::spawn_with_budget`].
+    pub budget: Option<Budget>,
+    pub priority: Priority,
+    /// Where `exit_code` goes on reaping, for tasks from
+    /// [`Task::spawn_joinable`].
+    join: Option<Arc<JoinSlot>>,
+    /// The hart's `preempt_count` while this task is switched out, so one
+    /// that blocks inside [`without_preemption`] resumes inside it.
+    preempt_count: usize,
+    /// Likewise the hart's `held_locks`; see [`sync::held_locks`].
+    held_locks: usize,
+    /// Ticks spent in the run queue since it last ran, for aging; see
+    /// [`set_aging_rate`].
+    ticks_waited: usize,
 }
 
 #[derive(PartialEq)]
@@ -45,60 +398,323 @@ pub enum TaskKind
 {
     User
     {
-        stack: Box<[u8; STACK_SIZE]>,
+        stack: Box<[u8]>
+    },
+    /// Per-hart boot flow, then fallback that only runs when nothing else is
+    /// runnable; see [`Task::idle`].
+    Idle,
+    /// Per-hart background kernel work; see [`worker`].
+    Worker
+    {
+        stack: Box<[u8]>
     },
-    Main,
+}
+
+impl TaskKind
+{
+    #[inline]
+    pub fn name(&self) -> &'static str
+    {
+        match self
+        {
+            Self::User { .. } => "user",
+            Self::Idle => "idle",
+            Self::Worker { .. } => "worker",
+        }
+    }
 }
 
 impl Task
 {
+    /// Adopt the flow the hart boots on as its idle task.
+    ///
+    /// It runs on the boot stack with the context it is first switched out
+    /// with, and ends in `boot::idle` once setup is done.
+    #[inline]
+    pub fn idle() -> Self
+    {
+        Self::new(TaskKind::Idle, Box::default(), TaskState::default())
+    }
+
+    /// Build this hart's kernel worker, which runs `worker::run`.
     #[inline]
-    pub fn main() -> Self
+    pub fn worker() -> Self
+    {
+        let (context, stack) = Self::prepare(Box::new(|| worker::run()), STACK_SIZE);
+        let mut task = Self::new(TaskKind::Worker { stack }, context, TaskState::Blocked);
+        // Dead tasks hold their stacks until it runs, so busy high-priority
+        // tasks must not keep it waiting.
+        task.priority = Priority::High;
+        task
+    }
+
+    /// A task with a fresh ID and every other field at its default.
+    fn new(kind: TaskKind, context: Box<TrapContext>, state: TaskState) -> Self
     {
         Self {
-            context: Box::new(TrapContext::default()),
-            kind: TaskKind::Main,
-            state: TaskState::default(),
+            context,
+            kind,
+            id: TaskId::next(),
+            state,
+            exit_code: 0,
+            panic_fatal: false,
+            budget: None,
+            priority: Priority::Normal,
+            join: None,
+            preempt_count: 0,
+            held_locks: 0,
+            ticks_waited: 0,
         }
     }
 
-    /// Spawn a task and distribute it across harts in round-robin order.
-    pub fn spawn(entry: impl FnOnce() + 'static)
+    /// A ready user task that enters `entry` on a new stack of `stack_size`
+    /// bytes.
+    fn user(entry: Box<dyn FnOnce()>, stack_size: usize) -> Self
     {
-        let n_harts = CPU_VEC.wait().len();
-        let ticket = SPAWN_TICKET.fetch_add(1, Ordering::Relaxed);
-        let target_hart = ticket % n_harts;
-        let target_cpu = Cpu::nth(target_hart);
+        let (context, stack) = Self::prepare(entry, stack_size);
+        Self::new(TaskKind::User { stack }, context, TaskState::default())
+    }
+
+    /// Spawn a task on a hart chosen by the current [`Placement`] policy.
+    ///
+    /// Returns the new task's ID, fixed before it is queued, so it names the
+    /// task even if it has already run to completion; see [`state_of`].
+    #[inline]
+    pub fn spawn(entry: impl FnOnce() + 'static) -> TaskId
+    {
+        Self::spawn_with_stack(STACK_SIZE, entry)
+    }
+
+    /// Like [`Task::spawn`], with a stack of at least `stack_size` bytes,
+    /// rounded up to whole pages.
+    pub fn spawn_with_stack(stack_size: usize, entry: impl FnOnce() + 'static) -> TaskId
+    {
+        assert!(
+            stack_size >= MIN_STACK_SIZE,
+            "Task stack must be at least {MIN_STACK_SIZE} bytes"
+        );
+
+        Self::submit(Self::user(
+            Box::new(entry),
+            stack_size.next_multiple_of(PAGE_SIZE),
+        ))
+    }
+
+    /// Like [`Task::spawn`], returning a handle that can wait for the task
+    /// and read its exit code.
+    pub fn spawn_joinable(entry: impl FnOnce() + 'static) -> JoinHandle
+    {
+        let slot = Arc::new(JoinSlot::default());
+        let mut task = Self::user(Box::new(entry), STACK_SIZE);
+        task.join = Some(slot.clone());
+
+        JoinHandle {
+            id: Self::submit(task),
+            slot,
+        }
+    }
+
+    /// Hand `exit_code` to the task's [`JoinHandle`], if it has one.
+    ///
+    /// Called by the worker as it reaps the task, since waking the joiner
+    /// may allocate.
+    fn release_joiner(&mut self)
+    {
+        if let Some(slot) = self.join.take()
+        {
+            slot.code.store(self.exit_code, Ordering::Relaxed);
+            slot.done.store(1, Ordering::Release);
+            sync::futex_wake(&slot.done, usize::MAX);
+        }
+    }
+
+    /// Like [`Task::spawn`], for a task in band `priority`.
+    pub fn spawn_with_priority(priority: Priority, entry: impl FnOnce() + 'static) -> TaskId
+    {
+        let mut task = Self::user(Box::new(entry), STACK_SIZE);
+        task.priority = priority;
+        Self::submit(task)
+    }
+
+    /// Like [`Task::spawn`], for a task limited to `budget` of CPU time.
+    pub fn spawn_with_budget(budget: Budget, entry: impl FnOnce() + 'static) -> TaskId
+    {
+        let mut task = Self::user(Box::new(entry), STACK_SIZE);
+        task.budget = Some(budget);
+        Self::submit(task)
+    }
+
+    /// Charge this running task one tick against its budget, returning the
+    /// action taken if that used it up. Called from the timer interrupt.
+    ///
+    /// A kill waits for a tick that finds the task preemptible and holding
+    /// no lock, as [`contain_panic`] does for a panic: nothing would release
+    /// a lock it died with. `may_kill` says whether this tick qualifies.
+    pub fn charge_tick(&mut self, may_kill: bool) -> Option<BudgetAction>
+    {
+        let budget = self.budget.as_mut()?;
+        budget.used += 1;
+
+        if budget.used <= budget.ticks
+            || budget.exceeded
+            || (budget.action == BudgetAction::Kill && !may_kill)
+        {
+            return None;
+        }
+        budget.exceeded = true;
+
+        log::warn!(
+            "Task {} used up its CPU budget of {} ticks",
+            self.id,
+            budget.ticks
+        );
+        if budget.action == BudgetAction::Kill
+        {
+            self.exit_code = BUDGET_EXIT_CODE;
+            self.state = TaskState::Dead;
+        }
+        Some(budget.action)
+    }
 
-        let task = Task::from(Box::new(entry) as Box<dyn FnOnce()>);
+    /// Create a task that doesn't run until [`TaskHandle::resume`].
+    ///
+    /// Its hart is chosen now, by the current [`Placement`] policy, and it
+    /// waits there outside the run queue.
+    pub fn spawn_suspended(entry: impl FnOnce() + 'static) -> TaskHandle
+    {
+        let mut task = Self::user(Box::new(entry), STACK_SIZE);
+        task.state = TaskState::Suspended;
+
+        let handle = TaskHandle {
+            id: task.id,
+            hart: target_hart(),
+        };
+        interrupt::with_disabled(|| Cpu::nth(handle.hart).scheduler.lock().add_suspended(task));
+        handle
+    }
+
+    /// Spawn a task running the plain function `entry`.
+    ///
+    /// Unlike [`Task::spawn`] there is no closure to box: the function pointer
+    /// goes straight into the initial context, and `entry` is entered without
+    /// the shim. Only the stack is allocated.
+    pub fn spawn_fn(entry: extern "C" fn()) -> TaskId
+    {
+        let (stack, sp) = Self::alloc_stack(STACK_SIZE);
+
+        let ctx = TrapContext {
+            pc: Task::fn_trampoline as *const () as usize,
+            sp,
+            s1: entry as usize,
+            sstatus: INITIAL_SSTATUS,
+            ..Default::default()
+        };
+
+        Self::submit(Self::new(
+            TaskKind::User { stack },
+            Box::new(ctx),
+            TaskState::default(),
+        ))
+    }
+
+    /// Spawn a task for each of `entries`, placed as [`Task::spawn`] would.
+    ///
+    /// Every stack is allocated up front; then each target hart's scheduler
+    /// is locked once for all of its tasks and sent at most one IPI. Returns
+    /// how many harts received tasks.
+    ///
+    /// Loads are sampled before anything is queued, so under
+    /// [`Placement::Local`] the whole batch tends to stay on this hart.
+    pub fn spawn_batch<F>(entries: impl IntoIterator<Item = F>) -> usize
+    where
+        F: FnOnce() + 'static,
+    {
+        let mut batches = (0..CPU_VEC.wait().len())
+            .map(|_| Vec::new())
+            .collect::<Vec<Vec<Task>>>();
+
+        for entry in entries
+        {
+            batches[target_hart()].push(Self::user(Box::new(entry), STACK_SIZE));
+        }
+
+        let mut harts = 0;
+        for (hart, batch) in batches.into_iter().enumerate()
+        {
+            if batch.is_empty()
+            {
+                continue;
+            }
+
+            let target_cpu = Cpu::nth(hart);
+            interrupt::with_disabled(|| {
+                let mut scheduler = target_cpu.scheduler.lock();
+                batch.into_iter().for_each(|task| scheduler.add_task(task));
+            });
+
+            if hart != arch::logical_hart_id()
+            {
+                timer::ipi::send(target_cpu.physical_id);
+            }
+            harts += 1;
+        }
+        harts
+    }
+
+    /// Queue a new task on a hart chosen by the current [`Placement`] policy,
+    /// returning its ID.
+    fn submit(task: Task) -> TaskId
+    {
+        let id = task.id;
+        let target_hart = target_hart();
+        let target_cpu = Cpu::nth(target_hart);
 
         interrupt::with_disabled(|| {
             let mut scheduler = target_cpu.scheduler.lock();
             scheduler.add_task(task);
         });
 
-        let local_cpu = Cpu::get();
-        if target_hart != local_cpu.logical_id
+        if target_hart != arch::logical_hart_id()
         {
             timer::ipi::send(target_cpu.physical_id);
         }
+        id
     }
 
+    /// First instruction stream of every spawned task.
+    ///
+    /// A new task's context carries `SPIE` clear, so `sret` enters here with
+    /// interrupts masked; the trampoline is the one place that enables them,
+    /// once the task is actually running.
     #[unsafe(naked)]
     #[unsafe(no_mangle)]
     pub extern "C" fn trampoline()
     {
         naked_asm!(
-            "csrsi sstatus, 2",     // Enable interrupts
+            "csrsi sstatus, {sie}", // Enable interrupts
             "mv a0, s1",            // `data` argument
             "mv a1, s2",            // `vtable` argument
             "la ra, {exit}",        // Set return address to Task::exit
             "tail {shim}",          // Jump to the shim
+            sie = const interrupt::SIE_FLAG,
             exit = sym Self::exit,
             shim = sym Self::task_entry_shim,
         )
     }
 
+    /// Like [`Task::trampoline`], for tasks from [`Task::spawn_fn`].
+    #[unsafe(naked)]
+    pub extern "C" fn fn_trampoline()
+    {
+        naked_asm!(
+            "csrsi sstatus, {sie}", // Enable interrupts
+            "la ra, {exit}",        // Set return address to Task::exit
+            "jr s1",                // Jump straight to the entry function
+            sie = const interrupt::SIE_FLAG,
+            exit = sym Self::exit,
+        )
+    }
+
     extern "C" fn task_entry_shim(data: usize, vtable: usize)
     {
         let closure =
@@ -107,36 +723,90 @@ impl Task
         closure();
     }
 
+    /// Where a task's entry closure returns to: a normal return exits with 0.
     fn exit() -> !
     {
+        Self::exit_with(0)
+    }
+
+    /// Terminate the current task with `code`, which stays on the task until
+    /// it is reaped and then passes to its [`JoinHandle`], if any.
+    pub fn exit_with(code: i32) -> !
+    {
+        debug_assert!(may_block(), "Task exit from a trap handler");
+
         interrupt::with_disabled(|| {
             let mut scheduler = Cpu::get().scheduler.lock();
 
             let task = scheduler.task_mut();
+            task.exit_code = code;
             task.state = TaskState::Dead;
         });
-        log::info!("Task exited");
+
+        if code == 0
+        {
+            log::info!("Task exited");
+        }
+        else
+        {
+            log::warn!("Task exited with code {code}");
+        }
 
         // Trigger a trap to refresh the state immediately
-        unsafe { csr_set_i!("sip", 2) } // Raise a supervisor software interrupt
+        timer::ipi::raise_local();
 
         loop
         {
             unsafe { asm!("wfi") }
         }
     }
-}
 
-impl From<Box<dyn FnOnce()>> for Task
-{
-    fn from(entry_point: Box<dyn FnOnce()>) -> Self
+    /// The stack this task runs on, if the task owns one.
+    #[inline]
+    fn stack(&self) -> Option<&[u8]>
     {
-        let mut stack = Box::new([0; _]);
+        match &self.kind
+        {
+            TaskKind::User { stack } | TaskKind::Worker { stack } => Some(stack),
+            TaskKind::Idle => None,
+        }
+    }
 
-        // Calculate aligned stack top
-        let stack_bottom = stack.as_mut_ptr() as usize;
-        let stack_top_unaligned = stack_bottom + STACK_SIZE;
-        let sp = stack_top_unaligned & !0xF; // Align down to 16 bytes
+    /// Panic if the saved context can't be this task's: a misaligned `pc` or
+    /// one outside kernel text and loaded programs, or an `sp` outside the
+    /// task's own stack.
+    ///
+    /// Resuming such a context would crash somewhere unrelated, so the
+    /// scheduler checks it (in debug builds) before switching in.
+    pub fn check_context(&self)
+    {
+        let ctx = &self.context;
+        assert!(
+            ctx.pc.is_multiple_of(2) && map::is_code(ctx.pc),
+            "Corrupt context for {} task: pc {:#x} not in code",
+            self.kind.name(),
+            ctx.pc
+        );
+
+        if let Some(stack) = self.stack()
+        {
+            let bottom = stack.as_ptr() as usize;
+            assert!(
+                (bottom..=bottom + stack.len()).contains(&ctx.sp),
+                "Corrupt context for {} task: sp {:#x} outside its stack {:#x}..{:#x}",
+                self.kind.name(),
+                ctx.sp,
+                bottom,
+                bottom + stack.len()
+            );
+        }
+    }
+
+    /// Allocate a stack and an initial context that enters `entry_point`
+    /// through the trampoline.
+    fn prepare(entry_point: Box<dyn FnOnce()>, stack_size: usize) -> (Box<TrapContext>, Box<[u8]>)
+    {
+        let (stack, sp) = Self::alloc_stack(stack_size);
 
         // Deconstruct `entry_point` so that we can pass it to `ctx` as two flat
         // pointers
@@ -144,19 +814,40 @@ impl From<Box<dyn FnOnce()>> for Task
         let (data_ptr, vtable_ptr) =
             unsafe { core::mem::transmute::<_, (usize, usize)>(entry_ptr) };
 
+        // `_trap` loads `pc` into `sepc`, so the first `sret` into this task
+        // lands on the trampoline exactly once. `ra` is left zeroed on purpose:
+        // the trampoline installs `Task::exit` itself, and a stray `ret` before
+        // that faults loudly instead of silently re-entering the trampoline.
         let ctx = TrapContext {
-            ra: Task::trampoline as *const () as usize,
             pc: Task::trampoline as *const () as usize,
             sp,
             s1: data_ptr,
             s2: vtable_ptr,
+            sstatus: INITIAL_SSTATUS,
             ..Default::default()
         };
 
-        Self {
-            context: Box::new(ctx),
-            kind: TaskKind::User { stack },
-            state: Default::default(),
-        }
+        (Box::new(ctx), stack)
+    }
+
+    /// Allocate a zeroed stack, returning it with its 16-byte aligned top.
+    fn alloc_stack(stack_size: usize) -> (Box<[u8]>, usize)
+    {
+        let mut stack = vec![0; stack_size].into_boxed_slice();
+
+        // Calculate aligned stack top
+        let stack_bottom = stack.as_mut_ptr() as usize;
+        let stack_top_unaligned = stack_bottom + stack.len();
+        let sp = stack_top_unaligned & !0xF; // Align down to 16 bytes
+
+        (stack, sp)
+    }
+}
+
+impl From<Box<dyn FnOnce()>> for Task
+{
+    fn from(entry_point: Box<dyn FnOnce()>) -> Self
+    {
+        Self::user(entry_point, STACK_SIZE)
     }
 }
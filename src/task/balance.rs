@@ -0,0 +1,98 @@
+//! Periodic load balancing between harts' run queues.
+//!
+//! Spawn-time placement only decides where a task starts; as tasks exit at
+//! different rates the queues drift apart. Every [`INTERVAL_TICKS`] of hart 0's
+//! timer, a rebalance pass is deferred to its worker, which moves waiting
+//! tasks from the longest queue to the shortest. Only user tasks move: the
+//! idle task and the worker belong to their hart.
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use super::{MigrateError, migrate_task};
+use crate::{
+    arch::{self, Cpu},
+    interrupt::{self, deferred},
+};
+
+/// Hart 0 ticks between rebalance passes.
+pub const INTERVAL_TICKS: usize = 50;
+/// Most tasks one pass moves, so a burst of spawns can't keep it busy.
+pub const MAX_MOVES: usize = 4;
+
+static ENABLED: AtomicBool = AtomicBool::new(true);
+static TICKS: AtomicUsize = AtomicUsize::new(0);
+
+#[inline]
+pub fn set_enabled(enabled: bool)
+{
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+#[inline]
+pub fn is_enabled() -> bool
+{
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Called from the timer interrupt; queues a pass on hart 0 when one is due.
+#[inline]
+pub fn on_tick(cpu: &Cpu)
+{
+    if cpu.logical_id == 0
+        && is_enabled()
+        && TICKS
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(INTERVAL_TICKS)
+    {
+        // A full queue just means this pass is skipped.
+        let _ = deferred::defer(run_pass, 0);
+    }
+}
+
+fn run_pass(_: usize)
+{
+    rebalance();
+}
+
+/// Run one rebalance pass now, returning how many tasks it moved.
+///
+/// Stops once no two queues differ by more than one task, so a balanced
+/// system is left alone, or after [`MAX_MOVES`] attempts.
+pub fn rebalance() -> usize
+{
+    let mut moved = 0;
+
+    for _ in 0..MAX_MOVES
+    {
+        let loads = || arch::online_cpus().map(|cpu| (cpu.load(), cpu));
+        let (Some((high, busiest)), Some((low, idlest))) = (
+            loads().max_by_key(|&(load, _)| load),
+            loads().min_by_key(|&(load, _)| load),
+        )
+        else
+        {
+            break;
+        };
+        if high <= low + 1
+        {
+            break;
+        }
+
+        let Some(id) = interrupt::with_disabled(|| busiest.scheduler.lock().newest_movable())
+        else
+        {
+            break;
+        };
+
+        match migrate_task(id, idlest.logical_id)
+        {
+            Ok(()) => moved += 1,
+            // It started running or exited since we looked; sample again.
+            Err(MigrateError::Running | MigrateError::NotWaiting) =>
+            {}
+            Err(MigrateError::NoSuchHart) => break,
+        }
+    }
+
+    moved
+}
@@ -35,4 +35,7 @@ pub struct TrapContext
     pub s10: usize,
     pub s11: usize,
     pub pc: usize,
+    /// `sstatus` at trap time. `_trap` restores only its `SPP` and `SPIE`
+    /// bits, so each task resumes in its own mode and interrupt state.
+    pub sstatus: usize,
 }
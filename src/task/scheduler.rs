@@ -1,74 +1,700 @@
-//! Per-hart round-robin scheduler and context-switch policy.
+//! Per-hart priority scheduler and context-switch policy.
+//!
+//! Each hart runs the first waiting task of the highest
+//! [`Priority`](super::Priority) band, and the tasks of one band take turns
+//! round-robin. A task left waiting is raised one band per
+//! [`aging_rate`] ticks, so a steady stream of higher bands can't starve it.
 //!
 //! This module manages runnable tasks and scheduling decisions per hart.
 
 use alloc::collections::VecDeque;
-use core::mem;
+use core::{
+    iter, mem,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
-use super::{Task, TaskKind, TaskState, TrapContext};
+use super::{Priority, Task, TaskId, TaskKind, TaskState, TrapContext};
+use crate::{
+    arch::{self, CPU_VEC, Cpu},
+    interrupt,
+    platform::timer,
+    trace,
+};
 
 pub struct Scheduler
 {
     current_task: Task,
     waiting_tasks: VecDeque<Task>,
+    // Parked here rather than in `waiting_tasks`, so it never competes with
+    // (or is counted as) runnable work. `None` only while it is running, as it
+    // is from boot.
+    idle_task: Option<Task>,
+    idle_hook: Option<fn()>,
+    // Same idea for the kernel worker, but `None` whenever it is runnable.
+    worker_task: Option<Task>,
+    worker_signaled: bool,
+    // Dead tasks waiting for the worker to free their stacks. Always has room
+    // for every live task on this hart, see `add_task`.
+    dead_tasks: VecDeque<Task>,
+    // Tasks blocked until `unblock`, or suspended until `resume`, with the
+    // same room guarantee.
+    blocked_tasks: VecDeque<Task>,
+}
+
+/// Ticks a waiting task needs to rise one band, or 0 for no aging.
+static AGING_TICKS: AtomicUsize = AtomicUsize::new(DEFAULT_AGING_TICKS);
+
+/// Default for [`set_aging_rate`]: a task rises one band per 200ms waited.
+pub const DEFAULT_AGING_TICKS: usize = 20;
+
+/// Raise waiting tasks one band for every `ticks` timer ticks they wait,
+/// until they next run; 0 turns aging off and keeps bands strict.
+#[inline]
+pub fn set_aging_rate(ticks: usize)
+{
+    AGING_TICKS.store(ticks, Ordering::Relaxed);
+}
+
+#[inline]
+pub fn aging_rate() -> usize
+{
+    AGING_TICKS.load(Ordering::Relaxed)
+}
+
+/// The band `task` competes in: its own, raised by the time it has waited.
+fn effective_priority(task: &Task, rate: usize) -> Priority
+{
+    let raised = match rate
+    {
+        0 => 0,
+        rate => task.ticks_waited / rate,
+    };
+
+    match task.priority as usize + raised
+    {
+        0 => Priority::Low,
+        1 => Priority::Normal,
+        _ => Priority::High,
+    }
+}
+
+/// Install `hook` to run on this hart every time it is about to sleep for
+/// lack of work, replacing any previous hook.
+///
+/// The hook runs with interrupts disabled and without the scheduler lock, so
+/// it must be short and must not block or wait on other harts.
+pub fn set_idle_hook(hook: fn()) -> Option<fn()>
+{
+    interrupt::with_disabled(|| Cpu::get().scheduler.lock().idle_hook.replace(hook))
+}
+
+/// Keep the current task on this hart's CPU until the matching
+/// [`preempt_enable`], without masking interrupts.
+///
+/// Ticks and IPIs are still taken, but any switch they would cause is held
+/// back. Calls nest. Blocking or exiting still switches; a task that blocked
+/// is back inside its section when it resumes.
+#[inline]
+pub fn preempt_disable()
+{
+    Cpu::get().preempt_count.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Undo one [`preempt_disable`]; leaving the outermost section runs any
+/// switch that was held back.
+#[inline]
+pub fn preempt_enable()
+{
+    let cpu = Cpu::get();
+    let previous = cpu.preempt_count.fetch_sub(1, Ordering::Relaxed);
+    debug_assert!(previous != 0, "Unbalanced preempt_enable");
+
+    if previous == 1 && cpu.resched_pending.swap(false, Ordering::Relaxed)
+    {
+        // The switch a tick owed, not a yield of the task's own.
+        timer::ipi::raise_local();
+    }
+}
+
+/// Run `f` with preemption disabled.
+#[inline]
+pub fn without_preemption<T>(f: impl FnOnce() -> T) -> T
+{
+    preempt_disable();
+    let result = f();
+    preempt_enable();
+    result
+}
+
+/// Scheduler counters owned by a single hart.
+///
+/// Only that hart increments them, so `Relaxed` is enough; readers on other
+/// harts get a best-effort snapshot, as with `interrupt::stats`.
+pub struct SchedCounters
+{
+    switches: AtomicUsize,
+    preemptions: AtomicUsize,
+    yields: AtomicUsize,
+    idle_ticks: AtomicUsize,
+    busy_ticks: AtomicUsize,
+    budget_overruns: AtomicUsize,
+}
+
+impl SchedCounters
+{
+    #[inline]
+    pub const fn new() -> Self
+    {
+        Self {
+            switches: AtomicUsize::new(0),
+            preemptions: AtomicUsize::new(0),
+            yields: AtomicUsize::new(0),
+            idle_ticks: AtomicUsize::new(0),
+            busy_ticks: AtomicUsize::new(0),
+            budget_overruns: AtomicUsize::new(0),
+        }
+    }
+
+    /// Account one timer tick to whatever was running when it fired.
+    #[inline]
+    pub fn record_tick(&self, idle: bool)
+    {
+        let counter = if idle
+        {
+            &self.idle_ticks
+        }
+        else
+        {
+            &self.busy_ticks
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A tick switched the running task out.
+    #[inline]
+    pub fn record_preemption(&self)
+    {
+        self.preemptions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A task used up its CPU budget.
+    #[inline]
+    pub fn record_budget_overrun(&self)
+    {
+        self.budget_overruns.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub(super) fn record_yield(&self)
+    {
+        self.yields.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    fn record_switch(&self)
+    {
+        self.switches.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Snapshot of this hart's counters.
+    pub fn snapshot(&self) -> SchedStats
+    {
+        let load = |counter: &AtomicUsize| counter.load(Ordering::Relaxed);
+
+        SchedStats {
+            switches: load(&self.switches),
+            preemptions: load(&self.preemptions),
+            yields: load(&self.yields),
+            idle_ticks: load(&self.idle_ticks),
+            busy_ticks: load(&self.busy_ticks),
+            budget_overruns: load(&self.budget_overruns),
+        }
+    }
+}
+
+impl Default for SchedCounters
+{
+    #[inline]
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+/// Scheduler activity on one hart since boot.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct SchedStats
+{
+    /// Times a different task was put on the hart, for any reason.
+    pub switches: usize,
+    /// Switches forced by a timer tick.
+    pub preemptions: usize,
+    /// Calls to [`yield_now`](super::yield_now).
+    pub yields: usize,
+    /// Ticks that fired while the idle task ran.
+    pub idle_ticks: usize,
+    /// Ticks that fired while any other task ran.
+    pub busy_ticks: usize,
+    /// Tasks that used up their [`Budget`](super::Budget).
+    pub budget_overruns: usize,
+}
+
+/// Scheduler counters of the hart with logical ID `hart`, if it exists.
+pub fn stats(hart: usize) -> Option<SchedStats>
+{
+    CPU_VEC
+        .get()?
+        .get(hart)
+        .map(|cpu| cpu.sched_counters.snapshot())
+}
+
+/// Why [`migrate_task`] left a task where it was.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MigrateError
+{
+    /// The target hart doesn't exist or never came up.
+    NoSuchHart,
+    /// The task is running right now; try again once it has been switched
+    /// out.
+    Running,
+    /// No hart has the task waiting in its run queue.
+    NotWaiting,
+}
+
+/// Move the waiting task `id` to the back of hart `target`'s run queue.
+///
+/// Only tasks queued to run can move: a running task has its state on its
+/// hart's stack and trap frame, and a blocked one is known by hart to whoever
+/// will wake it. Both schedulers are locked at once, lower logical ID first,
+/// which nothing else ever does, so this can't deadlock against another
+/// migration.
+pub fn migrate_task(id: TaskId, target: usize) -> Result<(), MigrateError>
+{
+    let cpus = CPU_VEC.wait();
+    let target_cpu = cpus
+        .get(target)
+        .filter(|cpu| cpu.is_online())
+        .ok_or(MigrateError::NoSuchHart)?;
+
+    for source_cpu in cpus.iter().filter(|cpu| cpu.is_online())
+    {
+        let outcome = interrupt::with_disabled(|| {
+            if source_cpu.logical_id == target
+            {
+                let scheduler = source_cpu.scheduler.lock();
+                return (scheduler.task().id == id || scheduler.is_waiting(id)).then_some(Ok(()));
+            }
+
+            let (mut source, mut target) = if source_cpu.logical_id < target
+            {
+                let source = source_cpu.scheduler.lock();
+                (source, target_cpu.scheduler.lock())
+            }
+            else
+            {
+                let target = target_cpu.scheduler.lock();
+                (source_cpu.scheduler.lock(), target)
+            };
+
+            if source.task().id == id
+            {
+                return Some(Err(MigrateError::Running));
+            }
+
+            let task = source.take_waiting(id)?;
+            target.add_task(task);
+            Some(Ok(()))
+        });
+
+        if let Some(result) = outcome
+        {
+            if result.is_ok() && target != arch::logical_hart_id()
+            {
+                timer::ipi::send(target_cpu.physical_id);
+            }
+            return result;
+        }
+    }
+
+    Err(MigrateError::NotWaiting)
+}
+
+/// State of task `id` on whichever hart holds it, or `None` once it has
+/// been freed (or never existed).
+///
+/// Each scheduler is locked on its own, so a task moving between harts
+/// mid-scan can be missed; the result is only a snapshot either way.
+pub fn state_of(id: TaskId) -> Option<TaskState>
+{
+    arch::online_cpus()
+        .find_map(|cpu| interrupt::with_disabled(|| cpu.scheduler.lock().state_of(id)))
+}
+
+/// Remove this hart's idle hook, returning it.
+pub fn clear_idle_hook() -> Option<fn()>
+{
+    interrupt::with_disabled(|| Cpu::get().scheduler.lock().idle_hook.take())
 }
 
 impl Scheduler
 {
+    /// A scheduler running `idle`, the hart's boot flow; see [`Task::idle`].
     #[inline]
-    pub fn with_task(task: Task) -> Self
+    pub fn with_task(idle: Task) -> Self
     {
         Self {
-            current_task: task,
-            waiting_tasks: VecDeque::new(),
+            current_task: idle,
+            // Room for the worker, see `add_task`.
+            waiting_tasks: VecDeque::with_capacity(1),
+            idle_task: None,
+            idle_hook: None,
+            worker_task: Some(Task::worker()),
+            worker_signaled: false,
+            dead_tasks: VecDeque::new(),
+            blocked_tasks: VecDeque::new(),
         }
     }
 
+    #[inline]
+    pub fn task(&self) -> &Task
+    {
+        &self.current_task
+    }
+
     #[inline]
     pub fn task_mut(&mut self) -> &mut Task
     {
         &mut self.current_task
     }
 
+    #[inline]
+    pub fn idle_hook(&self) -> Option<fn()>
+    {
+        self.idle_hook
+    }
+
+    /// Wake the kernel worker, ahead of every other waiting task.
+    pub fn signal_worker(&mut self)
+    {
+        if let Some(mut worker) = self.worker_task.take()
+        {
+            worker.state = TaskState::Ready;
+            self.waiting_tasks.push_front(worker);
+        }
+        else if matches!(self.current_task.kind, TaskKind::Worker { .. })
+            && self.current_task.state == TaskState::Blocked
+        {
+            // It is parking but hasn't been switched out yet; cancel that.
+            self.current_task.state = TaskState::Running;
+        }
+        else
+        {
+            // Queued or running: make its next `park` return immediately.
+            self.worker_signaled = true;
+        }
+    }
+
+    /// Consume a pending worker signal.
+    #[inline]
+    pub(super) fn take_worker_signal(&mut self) -> bool
+    {
+        mem::take(&mut self.worker_signaled)
+    }
+
+    /// Take one dead task for the worker to free.
+    #[inline]
+    pub(super) fn pop_dead(&mut self) -> Option<Task>
+    {
+        self.dead_tasks.pop_front()
+    }
+
+    /// Number of tasks waiting for this hart.
+    #[inline]
+    pub fn queue_len(&self) -> usize
+    {
+        self.waiting_tasks.len()
+    }
+
+    /// Whether `id` is queued to run here.
+    #[inline]
+    pub fn is_waiting(&self, id: TaskId) -> bool
+    {
+        self.waiting_tasks.iter().any(|task| task.id == id)
+    }
+
+    /// State of task `id`, if this hart holds it in any queue.
+    pub fn state_of(&self, id: TaskId) -> Option<TaskState>
+    {
+        iter::once(&self.current_task)
+            .chain(&self.waiting_tasks)
+            .chain(&self.blocked_tasks)
+            .chain(&self.dead_tasks)
+            .chain(&self.worker_task)
+            .chain(&self.idle_task)
+            .find(|task| task.id == id)
+            .map(|task| task.state)
+    }
+
+    /// The user task queued here most recently, which has the least time
+    /// invested in waiting, as the one to move elsewhere.
+    #[inline]
+    pub(super) fn newest_movable(&self) -> Option<TaskId>
+    {
+        self.waiting_tasks
+            .iter()
+            .rev()
+            .find(|task| matches!(task.kind, TaskKind::User { .. }))
+            .map(|task| task.id)
+    }
+
+    /// Take the user task `id` out of the run queue, for another hart.
+    ///
+    /// The worker stays put: it only ever runs on its own hart.
+    fn take_waiting(&mut self, id: TaskId) -> Option<Task>
+    {
+        let index = self
+            .waiting_tasks
+            .iter()
+            .position(|task| task.id == id && matches!(task.kind, TaskKind::User { .. }))?;
+        self.waiting_tasks.remove(index)
+    }
+
+    /// Whether any task other than the current one is ready to run.
+    #[inline]
+    pub fn has_waiting(&self) -> bool
+    {
+        !self.waiting_tasks.is_empty()
+    }
+
+    /// Queue `task` behind every waiting task.
+    ///
+    /// Always leaves room for one more entry, so that waking the worker from
+    /// an interrupt handler never has to allocate, and room in `dead_tasks`
+    /// for every task that could die here, so retiring one never does either.
     #[inline]
     pub fn add_task(&mut self, task: Task)
     {
+        self.waiting_tasks.reserve(2);
         self.waiting_tasks.push_back(task);
+        // Everything waiting, plus the current task.
+        self.dead_tasks.reserve(self.waiting_tasks.len() + 1);
+        self.blocked_tasks.reserve(self.waiting_tasks.len() + 1);
     }
 
-    pub fn schedule(&mut self, frame: &mut TrapContext)
+    /// Park a task created suspended until [`Scheduler::resume`].
+    pub fn add_suspended(&mut self, task: Task)
+    {
+        debug_assert!(task.state == TaskState::Suspended);
+        self.blocked_tasks.push_back(task);
+        // Keep `add_task`'s guarantee with this one counted too.
+        self.blocked_tasks.reserve(self.waiting_tasks.len() + 1);
+    }
+
+    /// Queue the suspended task `id`, returning `false` if there is none here.
+    /// May allocate, so not for trap handlers.
+    pub fn resume(&mut self, id: TaskId) -> bool
+    {
+        let Some(index) = self
+            .blocked_tasks
+            .iter()
+            .position(|t| t.id == id && t.state == TaskState::Suspended)
+        else
+        {
+            return false;
+        };
+
+        let mut task = self.blocked_tasks.remove(index).unwrap();
+        task.state = TaskState::Ready;
+        self.add_task(task);
+        true
+    }
+
+    /// Make the blocked task `id` runnable again, behind every task already
+    /// waiting, so a hart runs the tasks woken onto it in wake order.
+    ///
+    /// If it has marked itself blocked but not been switched out yet, this
+    /// cancels the block instead. Returns `false` if no such task is blocked
+    /// here. May allocate, so not for trap handlers.
+    pub fn unblock(&mut self, id: TaskId) -> bool
+    {
+        if self.current_task.id == id
+        {
+            let blocked = self.current_task.state == TaskState::Blocked;
+            if blocked
+            {
+                self.current_task.state = TaskState::Running;
+            }
+            return blocked;
+        }
+
+        let Some(index) = self
+            .blocked_tasks
+            .iter()
+            .position(|t| t.id == id && t.state == TaskState::Blocked)
+        else
+        {
+            return false;
+        };
+
+        let mut task = self.blocked_tasks.remove(index).unwrap();
+        task.state = TaskState::Ready;
+        self.add_task(task);
+        true
+    }
+
+    /// Age every waiting task by one tick; see [`set_aging_rate`].
+    #[inline]
+    pub fn age_waiting(&mut self)
+    {
+        for task in &mut self.waiting_tasks
+        {
+            task.ticks_waited = task.ticks_waited.saturating_add(1);
+        }
+    }
+
+    /// Index of the task to run next: the one queued longest in the highest
+    /// band after aging, unless the current task may keep running because it
+    /// is in a higher band still.
+    fn next_waiting(&self) -> Option<usize>
+    {
+        let rate = aging_rate();
+        let band = self
+            .waiting_tasks
+            .iter()
+            .map(|task| effective_priority(task, rate))
+            .max()?;
+
+        // Idle sits below every band.
+        let keeps_running = self.current_task.state == TaskState::Ready
+            && self.current_task.kind != TaskKind::Idle
+            && self.current_task.priority > band;
+        if keeps_running
+        {
+            return None;
+        }
+
+        self.waiting_tasks
+            .iter()
+            .position(|task| effective_priority(task, rate) == band)
+    }
+
+    /// Put a switched-out task back in line, behind its band. The slot it was
+    /// taken from is still free, so this never allocates.
+    #[inline]
+    fn requeue(&mut self, task: Task)
+    {
+        self.waiting_tasks.push_back(task);
+    }
+
+    /// Save the interrupted task into its context and load the next one into
+    /// `frame`.
+    ///
+    /// `frame` is the trap frame `_trap` restores on the way out: its `pc`
+    /// becomes `sepc` and its `sp` the resumed stack, so whatever is written
+    /// here is exactly where the hart continues after `sret`. The selection is
+    /// made and copied while `self` is still locked by the caller, so the
+    /// resumed `pc` can never belong to a task picked by a later `schedule`.
+    ///
+    /// A running task gives way to waiting tasks of its own band or a higher
+    /// one. A dead or blocked task is never resumed: with nothing waiting, the
+    /// hart falls back to its idle task.
+    ///
+    /// Returns whether a different task now runs.
+    pub fn schedule(&mut self, frame: &mut TrapContext) -> bool
     {
         // Persist interrupted task state unless it has already terminated.
         if self.current_task.state != TaskState::Dead
         {
-            self.current_task.state = TaskState::Ready;
+            if self.current_task.state != TaskState::Blocked
+            {
+                self.current_task.state = TaskState::Ready;
+            }
             *self.current_task.context = *frame;
         }
 
-        // We should always have at least the main task as a runnable fallback.
-        let next_task = match self.waiting_tasks.pop_front()
+        let runnable = matches!(self.current_task.state, TaskState::Ready);
+        let mut next_task = match self
+            .next_waiting()
+            .and_then(|index| self.waiting_tasks.remove(index))
         {
             Some(task) => task,
-            // No other tasks are ready, keep running the current one.
-            None =>
+            // Nothing else in its band or above, keep running the current one.
+            None if runnable =>
             {
-                if self.current_task.state == TaskState::Dead
-                {
-                    panic!("No runnable tasks available");
-                }
                 self.current_task.state = TaskState::Running;
-                return;
+                return false;
             }
+            // The current task is gone or parked and nothing is waiting: fall
+            // back to idle.
+            None => self.idle_task.take().expect("Idle task cannot be dead"),
         };
+        debug_assert!(
+            next_task.state != TaskState::Dead,
+            "Dead task {} queued to run",
+            next_task.id
+        );
+
+        // Its wait is over, and with it any boost.
+        next_task.ticks_waited = 0;
 
-        let old_task = mem::replace(&mut self.current_task, next_task);
+        trace::record(trace::EventKind::Switch, [frame.pc, next_task.context.pc]);
+        let cpu = Cpu::get();
+        cpu.sched_counters.record_switch();
+        let mut old_task = mem::replace(&mut self.current_task, next_task);
+        // Still under the lock with interrupts off, so nothing on this hart can
+        // see the new task running with the old ID cached.
+        cpu.current_task
+            .store(self.current_task.id.get(), Ordering::Relaxed);
+        // The preemption section and held locks, if any, go with their task; a
+        // switch the section held back is moot now.
+        old_task.preempt_count = cpu
+            .preempt_count
+            .swap(self.current_task.preempt_count, Ordering::Relaxed);
+        old_task.held_locks = cpu
+            .held_locks
+            .swap(self.current_task.held_locks, Ordering::Relaxed);
+        cpu.resched_pending.store(false, Ordering::Relaxed);
+
+        match old_task.kind
+        {
+            // The boot stack it runs on is never freed.
+            TaskKind::Idle =>
+            {
+                // Parked here, it would come back only once nothing else can
+                // run, woken or not.
+                debug_assert!(
+                    old_task.state == TaskState::Ready,
+                    "Idle task blocked or exited"
+                );
+                self.idle_task = Some(old_task)
+            }
+            TaskKind::Worker { .. } if old_task.state == TaskState::Blocked =>
+            {
+                self.worker_task = Some(old_task)
+            }
+            TaskKind::Worker { .. } => self.requeue(old_task),
+            // Room was reserved in `add_task`.
+            TaskKind::User { .. } if old_task.state == TaskState::Blocked =>
+            {
+                self.blocked_tasks.push_back(old_task)
+            }
+            TaskKind::User { .. } if old_task.state != TaskState::Dead => self.requeue(old_task),
+            // Freeing a stack here would run the allocator in trap context, so
+            // leave it to the worker; `add_task` reserved the slot already.
+            TaskKind::User { .. } =>
+            {
+                self.dead_tasks.push_back(old_task);
+                self.signal_worker();
+            }
+        }
 
-        if old_task.state != TaskState::Dead || old_task.kind == TaskKind::Main
+        if cfg!(debug_assertions)
         {
-            self.add_task(old_task);
+            self.current_task.check_context();
         }
 
         self.current_task.state = TaskState::Running;
         *frame = *self.current_task.context;
+        true
     }
 }
@@ -0,0 +1,98 @@
+//! Per-hart kernel worker task.
+//!
+//! Background kernel work runs here, as a schedulable task with interrupts
+//! enabled, instead of inline in trap handlers: deferred work queued by
+//! interrupt handlers, freeing dead tasks, and registered maintenance
+//! callbacks. The worker parks whenever it runs out of work and is put at the
+//! front of its hart's run queue when signaled.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::TaskState;
+use crate::{arch::Cpu, interrupt, platform::timer};
+
+/// Maximum number of maintenance callbacks.
+pub const MAX_CALLBACKS: usize = 8;
+
+// Raw function pointers, zero meaning "empty", so registration needs no lock.
+static CALLBACKS: [AtomicUsize; MAX_CALLBACKS] = [const { AtomicUsize::new(0) }; _];
+
+/// Run `callback` on every hart each time its worker wakes up.
+///
+/// Returns `false` if every slot is taken.
+pub fn register(callback: fn()) -> bool
+{
+    CALLBACKS.iter().any(|slot| {
+        slot.compare_exchange(0, callback as usize, Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok()
+    })
+}
+
+/// Remove a previously registered callback.
+pub fn unregister(callback: fn()) -> bool
+{
+    CALLBACKS.iter().any(|slot| {
+        slot.compare_exchange(callback as usize, 0, Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok()
+    })
+}
+
+/// Wake the current hart's worker.
+#[inline]
+pub fn wake()
+{
+    interrupt::with_disabled(|| Cpu::get().scheduler.lock().signal_worker());
+}
+
+pub(super) fn run() -> !
+{
+    loop
+    {
+        interrupt::deferred::run_pending();
+        reap();
+
+        for slot in &CALLBACKS
+        {
+            let raw = slot.load(Ordering::Acquire);
+            if raw != 0
+            {
+                let callback = unsafe { core::mem::transmute::<usize, fn()>(raw) };
+                callback();
+            }
+        }
+
+        park();
+    }
+}
+
+/// Free the tasks the scheduler retired since the last wakeup.
+fn reap()
+{
+    // Take one at a time so the drop itself runs unlocked and preemptible.
+    while let Some(mut task) = interrupt::with_disabled(|| Cpu::get().scheduler.lock().pop_dead())
+    {
+        task.release_joiner();
+        drop(task);
+    }
+}
+
+/// Block until signaled, unless a signal arrived while we were working.
+fn park()
+{
+    let parked = interrupt::with_disabled(|| {
+        let mut scheduler = Cpu::get().scheduler.lock();
+        if scheduler.take_worker_signal()
+        {
+            return false;
+        }
+
+        scheduler.task_mut().state = TaskState::Blocked;
+        true
+    });
+
+    if parked
+    {
+        // Switch away now; we resume right here once signaled.
+        timer::ipi::raise_local();
+    }
+}
@@ -0,0 +1,127 @@
+//! Low-overhead binary event tracing.
+//!
+//! Each hart records compact fixed-size events into its own ring, overwriting
+//! the oldest once full; nothing is formatted until the ring is dumped. This
+//! keeps recording cheap enough for trap entry and context switches, where
+//! `println!` would distort the timing being observed.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{
+    arch::{Cpu, cause},
+    collections::RingBuffer,
+    interrupt,
+    platform::timer,
+    sync::Mutex,
+};
+
+/// Events kept per hart.
+pub const CAPACITY: usize = 64;
+
+pub type TraceRing = Mutex<RingBuffer<Event, CAPACITY>>;
+
+// On by default only in debug builds.
+static ENABLED: AtomicBool = AtomicBool::new(cfg!(debug_assertions));
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EventKind
+{
+    /// `args`: raw `scause`, `sepc`.
+    Trap,
+    /// `args`: outgoing and incoming `pc`.
+    Switch,
+    /// Free for ad-hoc instrumentation; `args` are caller-defined.
+    Mark(u16),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Event
+{
+    /// Raw `time` counter value.
+    pub timestamp: u64,
+    pub kind: EventKind,
+    pub args: [usize; 2],
+}
+
+impl fmt::Display for Event
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match timer::ticks_to_us(self.timestamp)
+        {
+            Some(us) => write!(f, "{us:>12} us  ")?,
+            None => write!(f, "{:>12} tk  ", self.timestamp)?,
+        }
+
+        let [a, b] = self.args;
+        match self.kind
+        {
+            EventKind::Trap => write!(f, "trap    {} at {b:#x}", cause::describe(a)),
+            EventKind::Switch => write!(f, "switch  {a:#x} -> {b:#x}"),
+            EventKind::Mark(id) => write!(f, "mark {id:<3} {a:#x} {b:#x}"),
+        }
+    }
+}
+
+#[inline]
+pub fn set_enabled(enabled: bool)
+{
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+#[inline]
+pub fn is_enabled() -> bool
+{
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Record an event on the current hart.
+#[inline]
+pub fn record(kind: EventKind, args: [usize; 2])
+{
+    if !is_enabled()
+    {
+        return;
+    }
+
+    let event = Event {
+        timestamp: timer::now(),
+        kind,
+        args,
+    };
+    interrupt::with_disabled(|| Cpu::get().trace.lock().force_push(event));
+}
+
+/// Print and remove every event recorded on `cpu`, oldest first.
+///
+/// The panic handler calls this, possibly having panicked inside `record`, so
+/// a ring that is locked is reported busy and skipped rather than waited for.
+pub fn dump(cpu: &Cpu)
+{
+    let drained = interrupt::with_disabled(|| {
+        cpu.trace.try_lock().map(|mut ring| {
+            let lost = ring.overruns();
+            let mut events = [None; CAPACITY];
+            events.iter_mut().for_each(|slot| *slot = ring.pop());
+            (events, lost)
+        })
+    });
+    let Some((events, lost)) = drained
+    else
+    {
+        println!("Trace of hart {}: ring busy, skipped", cpu.logical_id);
+        return;
+    };
+
+    println!(
+        "Trace of hart {} ({lost} events overwritten):",
+        cpu.logical_id
+    );
+    for event in events.iter().flatten()
+    {
+        println!("  {event}");
+    }
+}
@@ -0,0 +1,150 @@
+//! Per-hart watchdog that notices harts which stopped taking timer ticks.
+//!
+//! Every tick stamps a heartbeat for the hart that took it, along with the
+//! interrupted `pc`. Harts idle in `wfi` still take ticks, since `boot::idle`
+//! re-arms the timer before each sleep, so only a hart stuck with interrupts
+//! masked (a deadlock, a loop in a trap handler) goes quiet. Hart 0 checks
+//! the others every few ticks and reports each stall once.
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::{
+    arch::{self, Cpu},
+    platform::timer,
+};
+
+/// A hart silent for longer than this is reported as stalled.
+pub const TIMEOUT_US: u64 = 1_000_000;
+/// Hart 0 checks on the others once every this many of its ticks.
+const CHECK_EVERY: usize = 10;
+
+static ENABLED: AtomicBool = AtomicBool::new(true);
+static CHECK_TICKS: AtomicUsize = AtomicUsize::new(0);
+
+/// Last sign of life from one hart.
+pub struct Heartbeat
+{
+    // Low bits of the `time` counter; zero until the first tick.
+    last: AtomicUsize,
+    pc: AtomicUsize,
+    reported: AtomicBool,
+}
+
+impl Heartbeat
+{
+    #[inline]
+    pub const fn new() -> Self
+    {
+        Self {
+            last: AtomicUsize::new(0),
+            pc: AtomicUsize::new(0),
+            reported: AtomicBool::new(false),
+        }
+    }
+
+    /// Record a tick that interrupted `pc`.
+    #[inline]
+    pub fn beat(&self, pc: usize)
+    {
+        self.pc.store(pc, Ordering::Relaxed);
+        // `max(1)`: zero means "never ticked".
+        self.last
+            .store((timer::now() as usize).max(1), Ordering::Release);
+    }
+
+    /// Pretend the last tick was at `time`, for testing the checks.
+    #[inline]
+    pub fn set_last(&self, time: usize)
+    {
+        self.last.store(time, Ordering::Release);
+    }
+
+    #[inline]
+    pub fn last(&self) -> usize
+    {
+        self.last.load(Ordering::Acquire)
+    }
+}
+
+impl Default for Heartbeat
+{
+    #[inline]
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+#[inline]
+pub fn set_enabled(enabled: bool)
+{
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+#[inline]
+pub fn is_enabled() -> bool
+{
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Whether `cpu` has ticked before but not within [`TIMEOUT_US`].
+///
+/// A hart that never ticked counts as still booting, not stalled.
+pub fn is_stalled(cpu: &Cpu) -> bool
+{
+    let last = cpu.heartbeat.last();
+    let Some(hz) = timer::timebase_hz()
+    else
+    {
+        return false;
+    };
+    let timeout = (hz * TIMEOUT_US / 1_000_000) as usize;
+
+    last != 0 && (timer::now() as usize).wrapping_sub(last) > timeout
+}
+
+/// Report every other hart that newly stalled, returning how many are
+/// stalled now.
+pub fn check() -> usize
+{
+    let me = arch::logical_hart_id();
+    let mut stalled = 0;
+
+    for cpu in arch::online_cpus().filter(|cpu| cpu.logical_id != me)
+    {
+        if !is_stalled(cpu)
+        {
+            cpu.heartbeat.reported.store(false, Ordering::Relaxed);
+            continue;
+        }
+
+        stalled += 1;
+        if !cpu.heartbeat.reported.swap(true, Ordering::Relaxed)
+        {
+            log::error!(
+                "Hart {} stalled: no timer tick for over {} ms, last pc {:#x}",
+                cpu.logical_id,
+                TIMEOUT_US / 1000,
+                cpu.heartbeat.pc.load(Ordering::Relaxed)
+            );
+        }
+    }
+
+    stalled
+}
+
+/// Called from the timer interrupt with the interrupted `pc`.
+#[inline]
+pub fn on_tick(cpu: &Cpu, pc: usize)
+{
+    cpu.heartbeat.beat(pc);
+
+    if cpu.logical_id == 0
+        && is_enabled()
+        && CHECK_TICKS
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(CHECK_EVERY)
+    {
+        check();
+    }
+}